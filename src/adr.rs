@@ -0,0 +1,694 @@
+/*
+    Copyright (C) 2019  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+*/
+
+//! Adaptive data rate and transmit power control: the two ends of a link
+//! tell each other how well they hear one another, agree to move to
+//! faster or more robust settings, and each turn its power down as far
+//! as the other end can spare.
+//!
+//! The messages ride in the control block of a frame's header (see
+//! [`frame`](crate::frame)).  An end that wants to change the
+//! modulation sends CHANGE; the other end answers with ACK and switches
+//! as soon as the ACK is sent, and the first end switches as soon as it
+//! is received.  Power needs no agreement, since each end only changes
+//! its own; every frame says what power it was sent at, and every REPORT
+//! says what power the frames it measured were sent at.  If nothing is
+//! heard for the timeout, each end goes back on its own to the settings
+//! it started with.
+
+use crate::airtime;
+use crate::radioconfig::{Module, RadioSettings};
+use log::*;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// The most a control block can take up in a frame: its length byte, a
+/// REPORT, a CHANGE or ACK, and a POWER.
+pub const CONTROLRESERVE: usize = 1 + 3 + 4 + 2;
+
+const REPORT: u8 = 1;
+const CHANGE: u8 = 2;
+const ACK: u8 = 3;
+const POWER: u8 = 4;
+
+/// In a REPORT, the power the measured frames were sent at isn't known.
+const UNKNOWNPOWER: i8 = i8::MIN;
+
+/// How far above the demodulation floor the SNR must be before speeding
+/// up, and how close to it before slowing down, in dB.
+const UPMARGIN: f32 = 10.0;
+const DOWNMARGIN: f32 = 3.0;
+
+/// How far above the target margin the SNR must be before turning the
+/// power down, in dB.
+const POWERSLACK: f32 = 3.0;
+
+/// SNR readings needed at the current settings before deciding anything.
+const MINSAMPLES: u32 = 3;
+
+/// The settings adaptive data rate moves between, slowest first.  The
+/// settings the radio was initialized with are added if they aren't here.
+const LADDER: [(u8, u16, u8); 9] = [(12, 125, 8), (12, 125, 5), (11, 125, 5), (10, 125, 5), (9, 125, 5),
+                                    (8, 125, 5), (7, 125, 5), (7, 250, 5), (7, 500, 5)];
+
+/// Settings for adaptive data rate.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AdrConfig {
+    /// How long to go without hearing the other end before going back to
+    /// the initial settings.  Reports are sent a third as often.
+    pub timeout: Duration,
+}
+
+impl Default for AdrConfig {
+    fn default() -> AdrConfig {
+        AdrConfig { timeout: Duration::from_secs(60) }
+    }
+}
+
+/// Settings for transmit power control.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TpcConfig {
+    /// The lowest power to use, in dBm.  None for the lowest the radio
+    /// allows.
+    pub min: Option<i8>,
+
+    /// The highest power to use, in dBm.  None for the power the radio was
+    /// initialized with.
+    pub max: Option<i8>,
+
+    /// How far above the lowest SNR that can be received to keep the
+    /// other end's reports, in dB.
+    pub margin: f32,
+}
+
+impl Default for TpcConfig {
+    fn default() -> TpcConfig {
+        TpcConfig { min: None, max: None, margin: 10.0 }
+    }
+}
+
+/// A spreading factor, bandwidth in kHz, and coding rate (5 for 4/5
+/// through 8 for 4/8).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Modulation {
+    pub sf: u8,
+    pub bw: u16,
+    pub cr: u8,
+}
+
+impl Modulation {
+    /// The modulation of `settings`.
+    pub fn of(settings: &RadioSettings) -> Modulation {
+        let cr = settings.cr.strip_prefix("4/").and_then(|d| d.parse().ok()).unwrap_or(5);
+        Modulation { sf: settings.sf, bw: settings.bw, cr }
+    }
+
+    /// The commands that put this modulation into effect.
+    pub fn commands(&self) -> Vec<String> {
+        vec![format!("radio set sf sf{}", self.sf),
+             format!("radio set bw {}", self.bw),
+             format!("radio set cr 4/{}", self.cr)]
+    }
+
+    /// The lowest SNR, in dB, at which this can be received.  Spreading
+    /// factors below 7, which the radio may be configured with, are taken
+    /// as 7.
+    pub fn floor(&self) -> f32 {
+        -7.5 - 2.5 * f32::from(self.sf.saturating_sub(7))
+    }
+
+    fn airtime(&self) -> Duration {
+        airtime::frameairtime(self.sf, self.bw, &format!("4/{}", self.cr), 8, true, 100)
+    }
+
+    fn encode(&self, kind: u8) -> [u8; 4] {
+        let bw = match self.bw { 125 => 0, 250 => 1, _ => 2 };
+        [kind, self.sf, bw, self.cr]
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Modulation> {
+        match bytes {
+            [sf, bw, cr] if (7..=12).contains(sf) && (5..=8).contains(cr) => {
+                let bw = match bw { 0 => 125, 1 => 250, 2 => 500, _ => return None };
+                Some(Modulation { sf: *sf, bw, cr: *cr })
+            },
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Modulation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SF{} BW {} kHz CR 4/{}", self.sf, self.bw, self.cr)
+    }
+}
+
+/// One message from a control block.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Message {
+    /// The SNR the sender hears us at, and the power we sent at, if known.
+    Report { snr: i8, power: Option<i8> },
+    Change(Modulation),
+    Ack(Modulation),
+    /// The power this frame was sent at, in dBm.
+    Power(i8),
+}
+
+/// Pick apart a control block.  Anything that can't be understood ends
+/// it.
+pub(crate) fn parse(ctl: &[u8]) -> Vec<Message> {
+    let mut msgs = vec![];
+    let mut rest = ctl;
+    while let Some((kind, args)) = rest.split_first() {
+        let (msg, len) = match (*kind, args) {
+            (REPORT, [snr, power, ..]) => {
+                let power = *power as i8;
+                (Some(Message::Report { snr: *snr as i8,
+                                        power: if power == UNKNOWNPOWER { None } else { Some(power) } }), 2)
+            },
+            (CHANGE, [a, b, c, ..]) => (Modulation::decode(&[*a, *b, *c]).map(Message::Change), 3),
+            (ACK, [a, b, c, ..]) => (Modulation::decode(&[*a, *b, *c]).map(Message::Ack), 3),
+            (POWER, [power, ..]) => (Some(Message::Power(*power as i8)), 1),
+            _ => (None, 0),
+        };
+        match msg {
+            Some(msg) => msgs.push(msg),
+            None => {
+                warn!("Ignoring malformed control block {:?}", ctl);
+                break;
+            },
+        }
+        rest = &args[len..];
+    }
+    msgs
+}
+
+/// Transmit power control state.
+#[derive(Clone)]
+struct Power {
+    min: i8,
+    max: i8,
+    margin: f32,
+    // What the radio is initialized to, and that brought within bounds.
+    configured: i8,
+    initial: i8,
+    current: i8,
+}
+
+/// The state of adaptive data rate and transmit power control on one end
+/// of a link.  Like [`Turns`](crate::turns::Turns), this does no I/O.
+#[derive(Clone)]
+pub(crate) struct Adr {
+    timeout: Duration,
+    ladder: Vec<Modulation>,
+    safe: Modulation,
+    current: Modulation,
+    power: Option<Power>,
+
+    // How well we hear the other end at the current settings, smoothed,
+    // and the power it says it is sending at.
+    snr: Option<f32>,
+    samples: u32,
+    peerpower: Option<i8>,
+
+    // How well the other end hears us, as it last reported.
+    peersnr: Option<f32>,
+
+    // When a report was last sent, and whether one should be.
+    lastreport: Option<Instant>,
+    reportdue: bool,
+
+    // A change we asked for, when, and whether the request is yet to go out.
+    proposed: Option<(Modulation, Instant)>,
+    changequeued: bool,
+
+    // A change to agree to in the next frame.
+    ack: Option<Modulation>,
+
+    // Changes to make before the radio is next used.
+    switchto: Option<Modulation>,
+    powerto: Option<i8>,
+
+    lastheard: Instant,
+    lastsent: Instant,
+    lastswitch: Instant,
+}
+
+impl Adr {
+    /// `adr` turns on changing the modulation, and `tpc` the power; with
+    /// neither, this only reports.
+    pub fn new(adr: Option<&AdrConfig>, tpc: Option<&TpcConfig>, settings: &RadioSettings) -> Adr {
+        let safe = Modulation::of(settings);
+        let mut ladder: Vec<Modulation> = match adr {
+            Some(_) => LADDER.iter()
+                .map(|(sf, bw, cr)| Modulation { sf: *sf, bw: *bw, cr: *cr })
+                // 500 kHz isn't allowed in the European bands.
+                .filter(|m| settings.version.module != Module::Rn2483 || m.bw != 500)
+                .collect(),
+            None => vec![],
+        };
+        if !ladder.contains(&safe) {
+            ladder.push(safe.clone());
+        }
+        ladder.sort_by_key(|m| std::cmp::Reverse(m.airtime()));
+
+        let power = tpc.map(|tpc| {
+            let (lo, hi) = settings.version.module.pwrrange();
+            let max = tpc.max.unwrap_or(settings.pwr).clamp(lo, hi);
+            let min = tpc.min.unwrap_or(lo).clamp(lo, max);
+            Power { min, max, margin: tpc.margin, configured: settings.pwr,
+                    initial: settings.pwr.clamp(min, max), current: settings.pwr }
+        });
+        let now = Instant::now();
+        let mut adr = Adr { timeout: adr.cloned().unwrap_or_default().timeout, ladder, current: safe.clone(), safe, power,
+                            snr: None, samples: 0, peerpower: None, peersnr: None,
+                            lastreport: None, reportdue: false,
+                            proposed: None, changequeued: false, ack: None, switchto: None, powerto: None,
+                            lastheard: now, lastsent: now, lastswitch: now };
+        adr.reset();
+        adr
+    }
+
+    fn interval(&self) -> Duration {
+        self.timeout / 3
+    }
+
+    // Whether anything has been changed from how the radio was initialized.
+    fn changed(&self) -> bool {
+        self.current != self.safe || self.power.as_ref().map(|p| p.current != p.initial).unwrap_or(false)
+    }
+
+    // Away from the initial modulation, a frame must go out now and then
+    // so the other end doesn't fall back.
+    fn keepalivedue(&self) -> bool {
+        self.current != self.safe && self.lastsent.elapsed() >= self.interval()
+    }
+
+    /// Whether there are messages waiting to go out.
+    pub fn hascontrol(&self) -> bool {
+        self.ack.is_some() || self.changequeued || (self.reportdue && self.snr.is_some())
+            || self.keepalivedue()
+    }
+
+    /// The messages for the next frame, which is about to be sent.  An
+    /// ACK takes effect once the frame has gone.
+    pub fn control(&mut self) -> Vec<u8> {
+        self.lastsent = Instant::now();
+        let mut ctl = vec![];
+        if let Some(m) = self.ack.take() {
+            ctl.extend_from_slice(&m.encode(ACK));
+            self.switchto = Some(m);
+        } else if self.changequeued {
+            if let Some((m, _)) = &self.proposed {
+                ctl.extend_from_slice(&m.encode(CHANGE));
+            }
+            self.changequeued = false;
+        }
+        if let (true, Some(snr)) = (self.reportdue, self.snr) {
+            ctl.extend_from_slice(&[REPORT, snr.round() as i8 as u8, self.peerpower.unwrap_or(UNKNOWNPOWER) as u8]);
+            self.reportdue = false;
+            self.lastreport = Some(Instant::now());
+        }
+        if let Some(power) = &self.power {
+            ctl.extend_from_slice(&[POWER, power.current as u8]);
+        }
+        ctl
+    }
+
+    /// Note a frame from the other end, with the messages in its control
+    /// block and the SNR it was received at.
+    pub fn received(&mut self, msgs: &[Message], snr: Option<f32>) {
+        self.lastheard = Instant::now();
+        for msg in msgs {
+            match msg {
+                Message::Power(power) if self.peerpower != Some(*power) => {
+                    // Earlier readings no longer say how well we hear it.
+                    debug!("Other end now sending at {} dBm", power);
+                    self.peerpower = Some(*power);
+                    self.snr = None;
+                    self.samples = 0;
+                },
+                Message::Report { snr, power } => self.reported(f32::from(*snr), *power),
+                Message::Change(m) => self.changerequested(m.clone()),
+                Message::Ack(m) => self.acked(m.clone()),
+                _ => (),
+            }
+        }
+        if let Some(snr) = snr {
+            self.snr = Some(match self.snr {
+                Some(old) => (old + snr) / 2.0,
+                None => snr,
+            });
+            self.samples += 1;
+            if self.lastreport.map(|t| t.elapsed() >= self.interval()).unwrap_or(true) {
+                self.reportdue = true;
+            }
+        }
+        self.decide();
+    }
+
+    fn reported(&mut self, snr: f32, power: Option<i8>) {
+        let current = match &self.power {
+            Some(p) => p.current,
+            None => {
+                self.peersnr = Some(snr);
+                return;
+            },
+        };
+        if power != Some(current) {
+            debug!("Ignoring report of frames sent at {:?} dBm; now at {} dBm", power, current);
+            return;
+        }
+        self.peersnr = Some(snr);
+        self.adjustpower(snr);
+    }
+
+    // Bring the SNR the other end hears us at to the target margin.  The
+    // power is only turned down once the modulation is as fast as it will
+    // go, or adaptive data rate would never speed up.
+    fn adjustpower(&mut self, peersnr: f32) {
+        let attop = self.ladder.last() == Some(&self.current);
+        let power = match &mut self.power {
+            Some(power) => power,
+            None => return,
+        };
+        let excess = peersnr - self.current.floor() - power.margin;
+        let lower = excess >= POWERSLACK && attop;
+        if !lower && excess >= 0.0 {
+            return;
+        }
+        let target = (f32::from(power.current) - excess.round()).clamp(f32::from(power.min), f32::from(power.max)) as i8;
+        if target != power.current {
+            info!("TPC: other end hears us at {:.0} dB SNR; power {} -> {} dBm", peersnr, power.current, target);
+            power.current = target;
+            self.powerto = Some(target);
+            // Its reports must now be of frames at the new power.
+            self.peersnr = None;
+        }
+    }
+
+    fn changerequested(&mut self, m: Modulation) {
+        if !self.ladder.contains(&m) {
+            warn!("ADR: other end asked for {}, which isn't allowed here", m);
+            return;
+        }
+        info!("ADR: other end asked for {}; agreeing", m);
+        // Theirs wins over any request of ours.
+        self.proposed = None;
+        self.changequeued = false;
+        self.ack = Some(m);
+    }
+
+    fn acked(&mut self, m: Modulation) {
+        match &self.proposed {
+            Some((p, _)) if *p == m => {
+                info!("ADR: other end agreed to {}", m);
+                self.proposed = None;
+                self.switchto = Some(m);
+            },
+            _ => debug!("ADR: ignoring agreement to {}, which we didn't ask for", m),
+        }
+    }
+
+    /// Ask for a change of modulation, if the SNR calls for one.
+    fn decide(&mut self) {
+        if let Some((_, when)) = &self.proposed {
+            if when.elapsed() < self.timeout {
+                return;
+            }
+            debug!("ADR: no answer to our request; giving up on it");
+            self.proposed = None;
+            self.changequeued = false;
+        }
+        if self.ack.is_some() || self.switchto.is_some() || self.samples < MINSAMPLES
+            || self.lastswitch.elapsed() < self.interval() {
+            return;
+        }
+        let snr = match self.snr {
+            Some(snr) => snr,
+            None => return,
+        };
+        let link = self.peersnr.map(|p| p.min(snr)).unwrap_or(snr);
+        let idx = match self.ladder.iter().position(|m| *m == self.current) {
+            Some(idx) => idx,
+            None => return,
+        };
+        // While there is power to spare, turn it up rather than slow down.
+        let powerleft = self.power.as_ref().map(|p| p.current < p.max).unwrap_or(false);
+
+        let target = if link < self.current.floor() + DOWNMARGIN && idx > 0 && !powerleft {
+            Some(self.ladder[idx - 1].clone())
+        } else if self.peersnr.is_some() && idx + 1 < self.ladder.len() {
+            // Wider bandwidth lets in more noise.
+            let next = &self.ladder[idx + 1];
+            let predicted = link - 10.0 * (f32::from(next.bw) / f32::from(self.current.bw)).log10();
+            if predicted >= next.floor() + UPMARGIN { Some(next.clone()) } else { None }
+        } else {
+            None
+        };
+        if let Some(m) = target {
+            info!("ADR: SNR {:.1} dB at {}; asking for {}", link, self.current, m);
+            self.proposed = Some((m, Instant::now()));
+            self.changequeued = true;
+        }
+    }
+
+    /// A change of modulation to make to the radio now, if one is due: one
+    /// agreed with the other end, or a fall back to the initial settings if
+    /// it hasn't been heard from.
+    pub fn switch(&mut self) -> Option<Modulation> {
+        let lost = self.changed() && self.lastheard.max(self.lastswitch).elapsed() >= self.timeout;
+        if lost {
+            if let Some(power) = &mut self.power {
+                if power.current != power.initial {
+                    warn!("TPC: nothing heard for {:?}; power back to {} dBm", self.timeout, power.initial);
+                    power.current = power.initial;
+                    self.powerto = Some(power.initial);
+                    self.lastswitch = Instant::now();
+                }
+            }
+        }
+        let target = match self.switchto.take() {
+            Some(m) => m,
+            None if lost && self.current != self.safe => {
+                warn!("ADR: nothing heard for {:?} at {}; falling back to {}", self.timeout, self.current, self.safe);
+                self.safe.clone()
+            },
+            None => return None,
+        };
+        if target == self.current {
+            return None;
+        }
+        info!("ADR: switching from {} to {}", self.current, target);
+        self.current = target.clone();
+        self.snr = None;
+        self.samples = 0;
+        self.peersnr = None;
+        self.proposed = None;
+        self.changequeued = false;
+        self.lastswitch = Instant::now();
+        Some(target)
+    }
+
+    /// A change of power to make to the radio now, if one is due.  Call
+    /// after [`switch`](Adr::switch).
+    pub fn powerchange(&mut self) -> Option<i8> {
+        self.powerto.take()
+    }
+
+    /// How long until a keepalive or a fall back may be due, if the
+    /// settings have been changed.
+    pub fn deadline(&self) -> Option<Duration> {
+        if !self.changed() {
+            return None;
+        }
+        let fallback = self.timeout.saturating_sub(self.lastheard.max(self.lastswitch).elapsed());
+        if self.current == self.safe {
+            Some(fallback)
+        } else {
+            let keepalive = self.interval().saturating_sub(self.lastsent.elapsed());
+            Some(fallback.min(keepalive))
+        }
+    }
+
+    /// The settings the radio was initialized with.
+    pub fn safe(&self) -> &Modulation {
+        &self.safe
+    }
+
+    /// The radio has been (re)initialized.
+    pub fn reset(&mut self) {
+        self.current = self.safe.clone();
+        self.powerto = None;
+        if let Some(power) = &mut self.power {
+            if power.configured != power.initial {
+                info!("TPC: power {} -> {} dBm to be within bounds", power.configured, power.initial);
+                self.powerto = Some(power.initial);
+            }
+            power.current = power.initial;
+        }
+        self.snr = None;
+        self.samples = 0;
+        self.peerpower = None;
+        self.peersnr = None;
+        self.proposed = None;
+        self.changequeued = false;
+        self.ack = None;
+        self.switchto = None;
+        self.lastheard = Instant::now();
+        self.lastsent = Instant::now();
+        self.lastswitch = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::radioconfig::Version;
+
+    fn settings(module: Module, sf: u8, pwr: i8) -> RadioSettings {
+        RadioSettings { version: Version { module, firmware: (1, 0, 5), raw: String::new() },
+                        freq: 915_000_000, pwr, sf, bw: 125, cr: String::from("4/5"), wdt: 15000,
+                        sync: String::from("12"), prlen: 8, crc: true, iqi: false }
+    }
+
+    fn sf(sf: u8) -> Modulation {
+        Modulation { sf, bw: 125, cr: 5 }
+    }
+
+    // Let enough time pass since the last switch for another.
+    fn settled(adr: &mut Adr) {
+        adr.lastswitch -= adr.interval();
+    }
+
+    // Hear `count` frames at `snr`, each reporting the other end hears us
+    // at `peersnr`.
+    fn hear(adr: &mut Adr, count: u32, snr: f32, peersnr: i8) {
+        let power = adr.power.as_ref().map(|p| p.current);
+        for _ in 0..count {
+            adr.received(&[Message::Report { snr: peersnr, power }], Some(snr));
+        }
+    }
+
+    #[test]
+    fn stepup() {
+        let mut adr = Adr::new(Some(&AdrConfig::default()), None, &settings(Module::Rn2903, 12, 20));
+        settled(&mut adr);
+        hear(&mut adr, MINSAMPLES - 1, 5.0, 5);
+        assert!(adr.proposed.is_none());
+        hear(&mut adr, 1, 5.0, 5);
+        assert_eq!(adr.proposed.as_ref().map(|p| &p.0), Some(&sf(11)));
+        assert!(parse(&adr.control()).contains(&Message::Change(sf(11))));
+        // It switches once the other end agrees.
+        assert_eq!(adr.switch(), None);
+        adr.received(&[Message::Ack(sf(11))], None);
+        assert_eq!(adr.switch(), Some(sf(11)));
+        assert_eq!(adr.switch(), None);
+    }
+
+    #[test]
+    fn stepdown() {
+        let mut adr = Adr::new(Some(&AdrConfig::default()), None, &settings(Module::Rn2903, 9, 20));
+        settled(&mut adr);
+        // Down needs only how well we hear the other end.
+        for _ in 0..MINSAMPLES {
+            adr.received(&[], Some(-11.0));
+        }
+        assert_eq!(adr.proposed.as_ref().map(|p| &p.0), Some(&sf(10)));
+    }
+
+    #[test]
+    fn agree() {
+        let mut adr = Adr::new(Some(&AdrConfig::default()), None, &settings(Module::Rn2483, 12, 14));
+        // Not on the ladder in the European bands.
+        adr.received(&[Message::Change(Modulation { sf: 7, bw: 500, cr: 5 })], None);
+        assert!(!adr.hascontrol());
+        // Switched once the answer has gone.
+        adr.received(&[Message::Change(sf(10))], None);
+        assert_eq!(adr.switch(), None);
+        assert_eq!(parse(&adr.control()), vec![Message::Ack(sf(10))]);
+        assert_eq!(adr.switch(), Some(sf(10)));
+    }
+
+    #[test]
+    fn fallback() {
+        let mut adr = Adr::new(Some(&AdrConfig::default()), None, &settings(Module::Rn2903, 12, 20));
+        adr.received(&[Message::Change(sf(9))], None);
+        adr.control();
+        assert_eq!(adr.switch(), Some(sf(9)));
+        // Frames go out every so often, so the other end doesn't fall back.
+        assert!(adr.deadline().unwrap() <= adr.interval());
+        adr.lastsent -= adr.interval();
+        assert!(adr.hascontrol());
+        // With nothing heard for the timeout, back to where it started.
+        adr.lastheard -= adr.timeout;
+        adr.lastswitch -= adr.timeout;
+        assert_eq!(adr.switch(), Some(sf(12)));
+        assert_eq!(adr.deadline(), None);
+    }
+
+    #[test]
+    fn powerclamp() {
+        // Bounds beyond what the radio allows are brought within them.
+        let tpc = TpcConfig { min: Some(-10), max: Some(30), margin: 10.0 };
+        let mut adr = Adr::new(None, Some(&tpc), &settings(Module::Rn2903, 7, 20));
+        assert_eq!(adr.powerchange(), None);
+        // The other end hears us far above the margin; down, to the least.
+        hear(&mut adr, 1, 10.0, 40);
+        assert_eq!(adr.powerchange(), Some(2));
+        // Far below; up, to the most.
+        hear(&mut adr, 1, 10.0, -20);
+        assert_eq!(adr.powerchange(), Some(20));
+
+        // An initial power outside the bounds is brought within them.
+        let tpc = TpcConfig { min: None, max: Some(10), margin: 10.0 };
+        let mut adr = Adr::new(None, Some(&tpc), &settings(Module::Rn2483, 7, 14));
+        assert_eq!(adr.powerchange(), Some(10));
+        assert_eq!(parse(&adr.control()), vec![Message::Power(10)]);
+    }
+
+    #[test]
+    fn stalereports() {
+        let mut adr = Adr::new(None, Some(&TpcConfig::default()), &settings(Module::Rn2903, 7, 20));
+        hear(&mut adr, 1, 10.0, 10);
+        assert_eq!(adr.powerchange(), Some(12));
+        // Reports of frames sent at the old power, or at an unknown one,
+        // don't count.
+        adr.received(&[Message::Report { snr: 10, power: Some(20) }], None);
+        adr.received(&[Message::Report { snr: 10, power: None }], None);
+        assert_eq!(adr.powerchange(), None);
+        hear(&mut adr, 1, 10.0, 10);
+        assert_eq!(adr.powerchange(), Some(4));
+
+        // Readings from before a change of modulation don't count either,
+        // nor does agreement to a change that wasn't asked for.
+        let mut adr = Adr::new(Some(&AdrConfig::default()), None, &settings(Module::Rn2903, 12, 20));
+        settled(&mut adr);
+        hear(&mut adr, MINSAMPLES - 1, 5.0, 5);
+        adr.received(&[Message::Ack(sf(7))], None);
+        assert_eq!(adr.switch(), None);
+        adr.received(&[Message::Change(sf(11))], None);
+        adr.control();
+        assert_eq!(adr.switch(), Some(sf(11)));
+        settled(&mut adr);
+        hear(&mut adr, MINSAMPLES - 1, 5.0, 5);
+        assert!(adr.proposed.is_none());
+        hear(&mut adr, 1, 5.0, 5);
+        assert_eq!(adr.proposed.as_ref().map(|p| &p.0), Some(&sf(10)));
+    }
+}
@@ -0,0 +1,101 @@
+/*
+    Copyright (C) 2019  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+*/
+
+//! How long frames take on the air, after Semtech's LoRa modem designer's
+//! guide (AN1200.13).
+
+use crate::radioconfig::RadioSettings;
+use std::time::Duration;
+
+/// Allowance for the radios and the serial links on top of the airtime:
+/// handing the received frame over, and getting back into receive mode.
+pub const TURNAROUND: Duration = Duration::from_millis(500);
+
+/// The duration of one symbol.
+pub fn symboltime(sf: u8, bw: u16) -> Duration {
+    Duration::from_secs_f64(f64::from(1u32 << sf) / (f64::from(bw) * 1000.0))
+}
+
+/// The time on the air of a frame of `len` bytes, sent with an explicit
+/// header.  `cr` is the coding rate as the radio gives it, such as `4/5`.
+pub fn frameairtime(sf: u8, bw: u16, cr: &str, prlen: u16, crc: bool, len: usize) -> Duration {
+    let tsym = symboltime(sf, bw);
+    // The radio turns on low data rate optimization for symbols over 16 ms.
+    let de = if tsym > Duration::from_millis(16) { 1 } else { 0 };
+    // 1 for 4/5 through 4 for 4/8.
+    let cr = cr.strip_prefix("4/")
+        .and_then(|d| d.parse::<i64>().ok())
+        .map(|d| d - 4)
+        .unwrap_or(1);
+    let sf = i64::from(sf);
+
+    let bits = 8 * len as i64 - 4 * sf + 28 + if crc { 16 } else { 0 };
+    let per = 4 * (sf - 2 * de);
+    let payloadsyms = 8 + (((bits + per - 1) / per) * (cr + 4)).max(0);
+    tsym.mul_f64(f64::from(prlen) + 4.25) + tsym * payloadsyms as u32
+}
+
+/// The time on the air of a frame of `len` bytes with `settings`.
+pub fn airtime(settings: &RadioSettings, len: usize) -> Duration {
+    frameairtime(settings.sf, settings.bw, &settings.cr, settings.prlen, settings.crc, len)
+}
+
+/// How long to wait for the other end's next frame before deciding it
+/// won't come: the pause before it is sent, enough time on the air for
+/// two of the largest frames, and the [`TURNAROUND`].
+pub fn autoeotwait(settings: &RadioSettings, txwait: Duration, framelen: usize) -> Duration {
+    txwait + airtime(settings, framelen) * 2 + TURNAROUND
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Airtime in ms, to the hundredth, as Semtech's calculator gives it.
+    fn ms(sf: u8, bw: u16, cr: &str, len: usize) -> f64 {
+        (frameairtime(sf, bw, cr, 8, true, len).as_secs_f64() * 100_000.0).round() / 100.0
+    }
+
+    #[test]
+    fn semtech() {
+        assert_eq!(ms(7, 125, "4/5", 10), 41.22);
+        assert_eq!(ms(7, 500, "4/8", 50), 35.9);
+        assert_eq!(ms(9, 125, "4/5", 100), 553.98);
+        assert_eq!(ms(12, 125, "4/5", 100), 3940.35);
+        assert_eq!(ms(12, 125, "4/8", 250), 13770.75);
+        // A frame with no payload is just the header and CRC.
+        assert_eq!(ms(7, 125, "4/5", 0), 25.86);
+    }
+
+    #[test]
+    fn lowdatarate() {
+        // Low data rate optimization is on for symbols over 16 ms: from
+        // SF11 at 125 kHz, and SF12 at 250 kHz.
+        assert_eq!(ms(10, 125, "4/5", 100), 1026.05);
+        assert_eq!(ms(11, 125, "4/5", 100), 2215.94);
+        assert_eq!(ms(11, 250, "4/5", 100), 944.13);
+        assert_eq!(ms(12, 250, "4/5", 100), 1970.18);
+    }
+
+    #[test]
+    fn symbols() {
+        assert_eq!(symboltime(7, 125), Duration::from_micros(1024));
+        assert_eq!(symboltime(12, 125), Duration::from_micros(32768));
+        assert_eq!(symboltime(12, 500), Duration::from_micros(8192));
+    }
+}
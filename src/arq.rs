@@ -0,0 +1,418 @@
+/*
+    Copyright (C) 2019  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+*/
+
+//! Reliable delivery: frames carrying data are numbered, the other end
+//! acknowledges them in the headers of the frames it sends on its turn,
+//! and those not acknowledged are sent again.
+//!
+//! Every frame sent reliably carries a [`Sequence`]; every frame sent
+//! once something has been received reliably carries an [`Ack`], with
+//! the next frame expected and which of the 32 frames after that have
+//! come in out of order.  A frame the other end has skipped over is sent
+//! again as soon as that is known; frames sent last are sent again if
+//! nothing acknowledges them within the retransmit timeout after this
+//! end stops transmitting.  Frames received out of order are held until
+//! the ones before them come in, so the payloads are handed on in the
+//! order they were sent.
+
+use crate::frame::{Ack, Sequence};
+use log::*;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The room reliable delivery takes up in a frame header.
+pub const ARQRESERVE: usize = 3 + 6;
+
+/// The most frames that may be sent without being acknowledged.  The
+/// bitmap in an [`Ack`] covers this many.
+pub const WINDOW: usize = 32;
+
+/// The retransmit timeout is doubled for each time a frame has been sent
+/// again, up to this many times.
+const MAXBACKOFF: u32 = 2;
+
+/// How far `a` is after `b`, allowing for wraparound.
+fn after(a: u16, b: u16) -> i32 {
+    i32::from(a.wrapping_sub(b) as i16)
+}
+
+// A frame sent but not yet acknowledged.
+#[derive(Clone)]
+struct Outstanding {
+    seq: u16,
+    payload: Vec<u8>,
+    tries: u32,
+    // Acknowledged out of order.
+    acked: bool,
+    // Known to be lost, to be sent again straight away.
+    lost: bool,
+}
+
+/// The state of reliable delivery in both directions.
+#[derive(Clone)]
+pub(crate) struct Arq {
+    // Sending: the sequence number for the next new frame, and the frames
+    // not yet acknowledged, oldest first.
+    nextseq: u16,
+    outstanding: VecDeque<Outstanding>,
+
+    // The retransmit timeout, and when this end last transmitted.
+    rto: Duration,
+    lasttx: Instant,
+
+    // Receiving: the next frame to hand on, if any has been received,
+    // frames received ahead of it, and whether to acknowledge.
+    expected: Option<u16>,
+    held: BTreeMap<u16, Vec<u8>>,
+    ackdue: bool,
+}
+
+impl Arq {
+    /// Start sending at a random sequence number, so the other end can
+    /// tell a restart from old frames.
+    pub fn new(rto: Duration) -> Arq {
+        let nextseq = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos() as u16).unwrap_or(0);
+        Arq { nextseq, outstanding: VecDeque::new(), rto, lasttx: Instant::now(),
+              expected: None, held: BTreeMap::new(), ackdue: false }
+    }
+
+    /// Set the retransmit timeout, as after a change of radio settings.
+    pub fn setrto(&mut self, rto: Duration) {
+        self.rto = rto;
+    }
+
+    /// Whether a new frame may be sent without overrunning the window.
+    pub fn cansend(&self) -> bool {
+        self.room() > 0
+    }
+
+    /// How many more new frames may be sent without overrunning the
+    /// window.
+    pub fn room(&self) -> usize {
+        WINDOW.saturating_sub(self.outstanding.len())
+    }
+
+    /// Whether a frame should be sent to acknowledge what was received.
+    pub fn ackdue(&self) -> bool {
+        self.ackdue
+    }
+
+    // How long after this end stops transmitting the oldest frame not
+    // acknowledged is sent again.
+    fn timeout(&self) -> Option<Duration> {
+        let oldest = self.outstanding.iter().find(|o| !o.acked)?;
+        Some(self.rto * 2u32.pow(oldest.tries.saturating_sub(1).min(MAXBACKOFF)))
+    }
+
+    /// Whether a frame is waiting to be sent again.
+    pub fn retransmitdue(&self) -> bool {
+        self.outstanding.iter().any(|o| o.lost)
+            || self.timeout().map(|t| self.lasttx.elapsed() >= t).unwrap_or(false)
+    }
+
+    /// How long until a frame is to be sent again, if any is waiting on
+    /// an acknowledgment.
+    pub fn deadline(&self) -> Option<Duration> {
+        if self.outstanding.iter().any(|o| o.lost) {
+            return Some(Duration::ZERO);
+        }
+        self.timeout().map(|t| t.saturating_sub(self.lasttx.elapsed()))
+    }
+
+    /// The next frame to send again, if one is due: its sequence number
+    /// and payload.  It counts as sent.
+    pub fn retransmission(&mut self) -> Option<(Sequence, Vec<u8>)> {
+        if let Some(timeout) = self.timeout() {
+            if self.lasttx.elapsed() >= timeout {
+                // Nothing was heard; everything still out may be lost.
+                for o in self.outstanding.iter_mut().filter(|o| !o.acked) {
+                    o.lost = true;
+                }
+            }
+        }
+        let base = self.outstanding.front()?.seq;
+        let o = self.outstanding.iter_mut().find(|o| o.lost)?;
+        o.lost = false;
+        o.tries += 1;
+        debug!("Sending frame {} again (try {})", o.seq, o.tries);
+        let seq = Sequence { num: o.seq, unacked: o.seq.wrapping_sub(base) as u8 };
+        Some((seq, o.payload.clone()))
+    }
+
+    /// Whether frames known to be lost are still waiting to go again.
+    pub fn resendwaiting(&self) -> bool {
+        self.outstanding.iter().any(|o| o.lost)
+    }
+
+    /// A frame from [`retransmission`](Arq::retransmission) could not be
+    /// sent after all.
+    pub fn notsent(&mut self, seq: Sequence) {
+        if let Some(o) = self.outstanding.iter_mut().find(|o| o.seq == seq.num) {
+            o.lost = true;
+            o.tries -= 1;
+        }
+    }
+
+    /// Number a new frame carrying `payload` and keep it until it is
+    /// acknowledged.
+    pub fn newframe(&mut self, payload: &[u8]) -> Sequence {
+        let num = self.nextseq;
+        self.nextseq = self.nextseq.wrapping_add(1);
+        let base = self.outstanding.front().map(|o| o.seq).unwrap_or(num);
+        self.outstanding.push_back(Outstanding { seq: num, payload: payload.to_vec(), tries: 1,
+                                                 acked: false, lost: false });
+        Sequence { num, unacked: num.wrapping_sub(base) as u8 }
+    }
+
+    /// A frame is being transmitted.
+    pub fn transmitted(&mut self) {
+        self.lasttx = Instant::now();
+    }
+
+    /// The acknowledgment to put in a frame being sent, if anything has
+    /// been received.
+    pub fn ack(&mut self) -> Option<Ack> {
+        let next = self.expected?;
+        self.ackdue = false;
+        let mut sack = 0u32;
+        for seq in self.held.keys() {
+            let n = after(*seq, next) - 1;
+            if (0..32).contains(&n) {
+                sack |= 1 << n;
+            }
+        }
+        Some(Ack { next, sack })
+    }
+
+    /// Take in an acknowledgment from the other end.
+    fn acked(&mut self, ack: Ack) {
+        if after(ack.next, self.nextseq) > 0 {
+            debug!("Ignoring acknowledgment of frame {} not yet sent", ack.next);
+            return;
+        }
+        while self.outstanding.front().map(|o| after(ack.next, o.seq) > 0).unwrap_or(false) {
+            self.outstanding.pop_front();
+        }
+        let highest = (0..32).rev().find(|n| ack.sack & (1 << n) != 0);
+        for o in self.outstanding.iter_mut() {
+            let n = after(o.seq, ack.next) - 1;
+            if (0..32).contains(&n) && ack.sack & (1 << n) != 0 {
+                o.acked = true;
+            } else if !o.acked && !o.lost && highest.map(|h| n < h).unwrap_or(false) {
+                // The other end has frames after it, so it was lost.
+                debug!("Frame {} was lost", o.seq);
+                o.lost = true;
+            }
+        }
+    }
+
+    /// Take in a received frame's sequence number and acknowledgment, if
+    /// it has them.  Returns the payloads now ready to be handed on, in
+    /// order: none if the frame is early, a duplicate, or only an
+    /// acknowledgment, or it and any held frames that follow it.
+    pub fn received(&mut self, seq: Option<Sequence>, ack: Option<Ack>, payload: Vec<u8>) -> Vec<Vec<u8>> {
+        if let Some(ack) = ack {
+            self.acked(ack);
+        }
+        let seq = match seq {
+            Some(seq) => seq,
+            None if payload.is_empty() => return vec![],
+            None => return vec![payload],
+        };
+        self.ackdue = true;
+        let base = seq.num.wrapping_sub(u16::from(seq.unacked));
+        let window = WINDOW as i32;
+        let expected = match self.expected {
+            // The other end has moved on past frames never received, or
+            // restarted; pick up from where it is.
+            Some(expected) if after(base, expected) > 0 || after(base, expected) < -window
+                || after(seq.num, expected) >= window => {
+                warn!("Lost track of the other end's frames (expected {}, got {}); data may be missing",
+                      expected, seq.num);
+                self.held.clear();
+                base
+            },
+            Some(expected) => expected,
+            None => base,
+        };
+        self.expected = Some(expected);
+
+        match after(seq.num, expected) {
+            n if n < 0 => {
+                debug!("Frame {} received again", seq.num);
+                vec![]
+            },
+            0 => {
+                let mut ready = vec![payload];
+                let mut next = expected.wrapping_add(1);
+                while let Some(payload) = self.held.remove(&next) {
+                    ready.push(payload);
+                    next = next.wrapping_add(1);
+                }
+                self.expected = Some(next);
+                ready
+            },
+            _ => {
+                debug!("Frame {} received ahead of {}; holding it", seq.num, expected);
+                self.held.insert(seq.num, payload);
+                vec![]
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    fn pair(start: u16, rto: Duration) -> (Arq, Arq) {
+        let mut a = Arq::new(rto);
+        a.nextseq = start;
+        (a, Arq::new(rto))
+    }
+
+    fn send(a: &mut Arq, payload: u8) -> (Sequence, Vec<u8>) {
+        (a.newframe(&[payload]), vec![payload])
+    }
+
+    #[test]
+    fn inorder() {
+        let (mut a, mut b) = pair(100, Duration::from_secs(10));
+        for i in 0..3 {
+            let (seq, payload) = send(&mut a, i);
+            assert_eq!(b.received(Some(seq), None, payload), vec![vec![i]]);
+        }
+        assert_eq!(a.room(), WINDOW - 3);
+        assert!(b.ackdue());
+        let ack = b.ack().unwrap();
+        assert_eq!(ack, Ack { next: 103, sack: 0 });
+        assert!(!b.ackdue());
+        assert!(a.received(None, Some(ack), vec![]).is_empty());
+        assert_eq!(a.room(), WINDOW);
+        assert!(a.deadline().is_none());
+    }
+
+    #[test]
+    fn droppedandreordered() {
+        let (mut a, mut b) = pair(0, Duration::from_secs(10));
+        let frames: Vec<_> = (0..5).map(|i| send(&mut a, i)).collect();
+        assert_eq!(b.received(Some(frames[0].0), None, frames[0].1.clone()), vec![vec![0]]);
+        // 1 is lost, 3 comes before 2.
+        assert!(b.received(Some(frames[3].0), None, frames[3].1.clone()).is_empty());
+        assert!(b.received(Some(frames[2].0), None, frames[2].1.clone()).is_empty());
+        let ack = b.ack().unwrap();
+        assert_eq!(ack, Ack { next: 1, sack: 0b11 });
+
+        a.received(None, Some(ack), vec![]);
+        assert!(a.retransmitdue());
+        assert!(a.resendwaiting());
+        let (seq, payload) = a.retransmission().unwrap();
+        assert_eq!((seq.num, payload.clone()), (1, vec![1]));
+        // 4 may still be on its way, so isn't sent again yet.
+        assert!(a.retransmission().is_none());
+
+        assert_eq!(b.received(Some(seq), None, payload), vec![vec![1], vec![2], vec![3]]);
+        assert_eq!(b.received(Some(frames[4].0), None, frames[4].1.clone()), vec![vec![4]]);
+        // A duplicate is dropped.
+        assert!(b.received(Some(frames[2].0), None, frames[2].1.clone()).is_empty());
+        a.received(None, b.ack(), vec![]);
+        assert_eq!(a.room(), WINDOW);
+    }
+
+    #[test]
+    fn timeout() {
+        let (mut a, mut b) = pair(0, Duration::from_millis(20));
+        let frames: Vec<_> = (0..2).map(|i| send(&mut a, i)).collect();
+        a.transmitted();
+        assert!(!a.retransmitdue());
+        assert!(a.retransmission().is_none());
+        sleep(Duration::from_millis(30));
+        assert!(a.retransmitdue());
+        let first = a.retransmission().unwrap();
+        let second = a.retransmission().unwrap();
+        assert_eq!((first.0.num, second.0.num), (0, 1));
+        assert!(a.retransmission().is_none());
+
+        // Sent again, the timeout backs off.
+        a.transmitted();
+        sleep(Duration::from_millis(30));
+        assert!(!a.retransmitdue());
+
+        // The first try got through after all, so the retry is a duplicate.
+        assert_eq!(b.received(Some(frames[0].0), None, frames[0].1.clone()), vec![vec![0]]);
+        assert!(b.received(Some(first.0), None, first.1).is_empty());
+        assert_eq!(b.received(Some(second.0), None, second.1), vec![vec![1]]);
+    }
+
+    #[test]
+    fn notsent() {
+        let (mut a, _) = pair(0, Duration::ZERO);
+        send(&mut a, 0);
+        let (seq, _) = a.retransmission().unwrap();
+        a.notsent(seq);
+        assert!(a.resendwaiting());
+        assert_eq!(a.retransmission().unwrap().0.num, 0);
+    }
+
+    #[test]
+    fn wraparound() {
+        let (mut a, mut b) = pair(0xfffe, Duration::from_secs(10));
+        let frames: Vec<_> = (0..4).map(|i| send(&mut a, i)).collect();
+        assert_eq!(frames[3].0.num, 1);
+        assert_eq!(frames[3].0.unacked, 3);
+        assert_eq!(b.received(Some(frames[0].0), None, frames[0].1.clone()), vec![vec![0]]);
+        assert!(b.received(Some(frames[2].0), None, frames[2].1.clone()).is_empty());
+        assert!(b.received(Some(frames[3].0), None, frames[3].1.clone()).is_empty());
+        assert_eq!(b.ack().unwrap(), Ack { next: 0xffff, sack: 0b11 });
+        assert_eq!(b.received(Some(frames[1].0), None, frames[1].1.clone()),
+                   vec![vec![1], vec![2], vec![3]]);
+        let ack = b.ack().unwrap();
+        assert_eq!(ack, Ack { next: 2, sack: 0 });
+        a.received(None, Some(ack), vec![]);
+        assert_eq!(a.room(), WINDOW);
+    }
+
+    #[test]
+    fn losttrack() {
+        let (mut a, mut b) = pair(500, Duration::from_secs(10));
+        let (seq, payload) = send(&mut a, 0);
+        assert_eq!(b.received(Some(seq), None, payload), vec![vec![0]]);
+
+        // The other end gave up on 501..=539 and the first it still has
+        // out is 540; 541 arrives first.
+        let early = Sequence { num: 541, unacked: 1 };
+        assert!(b.received(Some(early), None, vec![41]).is_empty());
+        assert_eq!(b.ack().unwrap(), Ack { next: 540, sack: 0b1 });
+        let late = Sequence { num: 540, unacked: 0 };
+        assert_eq!(b.received(Some(late), None, vec![40]), vec![vec![40], vec![41]]);
+
+        // A restart far from where it was.
+        let restart = Sequence { num: 9000, unacked: 0 };
+        assert_eq!(b.received(Some(restart), None, vec![9]), vec![vec![9]]);
+        assert_eq!(b.ack().unwrap().next, 9001);
+    }
+
+    #[test]
+    fn unsequenced() {
+        let (_, mut b) = pair(0, Duration::from_secs(10));
+        assert!(b.received(None, None, vec![]).is_empty());
+        assert_eq!(b.received(None, None, vec![1]), vec![vec![1]]);
+        assert!(!b.ackdue());
+    }
+}
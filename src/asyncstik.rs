@@ -0,0 +1,547 @@
+/*
+    Copyright (C) 2019  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+*/
+
+//! An async version of [`LoraStik`](crate::LoraStik), for use under tokio.
+//!
+//! The whole link runs as one task, so one process can serve many radios
+//! without a thread for each.  Turn-taking, `txslot` and `eotwait` work
+//! just as they do for the blocking version.  The radio must be an
+//! RN2903 or RN2483, on a serial port or a tcp:// port.
+
+use crate::adr::Modulation;
+use crate::cmd::{Reply, REPLYTIMEOUT};
+use crate::error::{Error, Result};
+use crate::lorastik::{LinkConfig, LinkStats, ReceivedFrames, RECOVERWAIT};
+use crate::radioconfig::{RadioInit, RadioSettings};
+use crate::rn2903::{checkinit, checktx, inittimeout, rxstopped, txcommand, Configure, Step, QUIESCE, QUIESCEWAIT,
+                    TXTIMEOUT};
+use crate::ser::BAUD;
+use crate::turns::{Received, Turns};
+use format_escape_default::format_escape_default;
+use futures_core::Stream;
+use log::*;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, Lines, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::{sleep, timeout};
+use tokio_serial::SerialPortBuilderExt;
+
+trait Port: AsyncRead + AsyncWrite + Send + Unpin {}
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Port for T {}
+
+async fn openport(portname: &Path) -> Result<Box<dyn Port>> {
+    match portname.to_str() {
+        Some(s) if s.starts_with("tcp://") => {
+            let stream = TcpStream::connect(&s[6..]).await?;
+            stream.set_nodelay(true)?;
+            Ok(Box::new(stream))
+        },
+        Some(s) if s.starts_with("rfc2217://") =>
+            Err(Error::Config(String::from("rfc2217:// ports are not supported by the async link"))),
+        _ => {
+            let port = tokio_serial::new(portname.to_string_lossy(), BAUD)
+                .open_native_async()
+                .map_err(io::Error::from)?;
+            Ok(Box::new(port))
+        }
+    }
+}
+
+/// An RN2903 on an async port.  This is the async counterpart of
+/// [`Rn2903`](crate::Rn2903) and its [`CmdEngine`](crate::cmd::CmdEngine);
+/// since only the link's task uses it, no reader thread or channel is
+/// needed.
+struct AsyncRadio {
+    portname: PathBuf,
+    lines: Lines<BufReader<ReadHalf<Box<dyn Port>>>>,
+    writer: WriteHalf<Box<dyn Port>>,
+    // Packets received while waiting for the answer to a command.
+    deferred: Vec<Vec<u8>>,
+}
+
+impl AsyncRadio {
+    async fn open(portname: &Path) -> Result<AsyncRadio> {
+        let (reader, writer) = tokio::io::split(openport(portname).await?);
+        Ok(AsyncRadio { portname: portname.to_path_buf(),
+                        lines: BufReader::new(reader).lines(),
+                        writer,
+                        deferred: vec![] })
+    }
+
+    async fn writeln(&mut self, line: &str) -> Result<()> {
+        trace!("{:?} SEROUT: {}", self.portname, line);
+        let data = format!("{}\r\n", line);
+        let res = match self.writer.write_all(data.as_bytes()).await {
+            Ok(()) => self.writer.flush().await,
+            Err(e) => Err(e),
+        };
+        res.map_err(|e| Error::Disconnected(e.to_string()))
+    }
+
+    /// Wait for the next line from the radio.  This is cancel safe, so it
+    /// can be used in `select!`.
+    async fn readreply(&mut self) -> Result<Reply> {
+        match self.lines.next_line().await {
+            Ok(Some(line)) => {
+                let line = line.trim();
+                trace!("{:?} SERIN: {}", self.portname, line);
+                Ok(Reply::parse(line))
+            },
+            Ok(None) => Err(Error::Disconnected(String::from("EOF"))),
+            Err(e) => Err(Error::Disconnected(e.to_string())),
+        }
+    }
+
+    /// Throw away whatever the radio has already said, setting aside
+    /// packets.
+    async fn flush(&mut self) -> Result<()> {
+        while let Ok(stale) = timeout(Duration::from_secs(0), self.readreply()).await {
+            match stale? {
+                Reply::RadioRx(frame) => self.deferred.push(frame),
+                r => debug!("Discarding stale reply {:?}", r),
+            }
+        }
+        Ok(())
+    }
+
+    async fn waitreply(&mut self, cmd: &str, limit: Duration) -> Result<Reply> {
+        let deadline = Instant::now() + limit;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match timeout(remaining, self.readreply()).await {
+                Ok(reply) => match reply? {
+                    Reply::RadioRx(frame) => {
+                        debug!("Setting aside packet received while waiting for reply to {}", cmd);
+                        self.deferred.push(frame);
+                    },
+                    r => return Ok(r),
+                },
+                Err(_) => return Err(Error::Timeout { command: String::from(cmd), timeout: limit }),
+            }
+        }
+    }
+
+    /// Send a command and wait for its reply, as
+    /// [`CmdEngine::command`](crate::cmd::CmdEngine::command) does.
+    async fn command(&mut self, cmd: &str, limit: Duration) -> Result<Reply> {
+        self.flush().await?;
+        self.writeln(cmd).await?;
+
+        let mut sawerr = false;
+        loop {
+            match self.waitreply(cmd, limit).await {
+                Ok(Reply::RadioErr) => {
+                    debug!("Ignoring lingering radio_err before reply to {}", cmd);
+                    sawerr = true;
+                },
+                Err(Error::Timeout { .. }) if sawerr => return Ok(Reply::RadioErr),
+                res => return res,
+            }
+        }
+    }
+
+    async fn commandok(&mut self, cmd: &str, limit: Duration) -> Result<()> {
+        self.command(cmd, limit).await?.ok(cmd)
+    }
+
+    async fn commandvalue(&mut self, cmd: &str, limit: Duration) -> Result<String> {
+        self.command(cmd, limit).await?.value(cmd)
+    }
+
+    /// Send one line of an init script and check the response.
+    async fn initcmd(&mut self, line: &str) -> Result<()> {
+        let reply = self.command(line, inittimeout(line)).await?;
+        checkinit(line, reply)
+    }
+
+    async fn configure(&mut self, init: &RadioInit) -> Result<RadioSettings> {
+        for line in QUIESCE.iter() {
+            self.writeln(line).await?;
+        }
+        sleep(QUIESCEWAIT).await;
+        self.flush().await?;
+        self.deferred.clear();
+
+        let mut configure = Configure::new(init);
+        let mut reply = None;
+        loop {
+            match configure.step(reply)? {
+                Step::Command(cmd, limit) => reply = Some(self.command(&cmd, limit).await?),
+                Step::Done(settings) => return Ok(settings),
+            }
+        }
+    }
+
+    async fn txframe(&mut self, frame: &[u8]) -> Result<()> {
+        let txstr = txcommand(frame);
+
+        self.commandok(&txstr, REPLYTIMEOUT).await?;
+        checktx(self.waitreply(&txstr, TXTIMEOUT).await?);
+        Ok(())
+    }
+
+    async fn enterrx(&mut self) -> Result<()> {
+        self.commandok("radio rx 0", REPLYTIMEOUT).await
+    }
+
+    /// Take the radio out of receive mode, returning a packet that raced
+    /// in.
+    async fn rxstop(&mut self) -> Result<Option<Vec<u8>>> {
+        let reply = self.command("radio rxstop", REPLYTIMEOUT).await?;
+        rxstopped(reply, std::mem::take(&mut self.deferred))
+    }
+
+    async fn readqual(&mut self) -> Result<(String, String)> {
+        let snr = self.commandvalue("radio get snr", REPLYTIMEOUT).await?;
+        let rssi = self.commandvalue("radio get rssi", REPLYTIMEOUT).await?;
+        Ok((snr, rssi))
+    }
+
+    async fn rssi(&mut self) -> Result<i32> {
+        let rssi = self.commandvalue("radio get rssi", REPLYTIMEOUT).await?;
+        rssi.parse().map_err(|_| Error::UnexpectedReply { command: String::from("radio get rssi"), reply: rssi })
+    }
+
+    async fn setmodulation(&mut self, modulation: &Modulation) -> Result<()> {
+        for line in modulation.commands() {
+            self.initcmd(&line).await?;
+        }
+        Ok(())
+    }
+
+    async fn setpower(&mut self, power: i8) -> Result<()> {
+        self.initcmd(&format!("radio set pwr {}", power)).await
+    }
+}
+
+/// A handle for sending over an async link.  Clones send over the same
+/// link.
+#[derive(Clone)]
+pub struct AsyncLoraStik {
+    txblockstx: mpsc::Sender<Vec<u8>>,
+    settings: RadioSettings,
+    stats: Arc<Mutex<LinkStats>>,
+}
+
+impl AsyncLoraStik {
+    /// Open and initialize the radio, and start the task that runs the
+    /// link.  Must be called from within a tokio runtime.  Returns a handle
+    /// for sending, and the stream of received frames.
+    ///
+    /// If the connection to the radio is lost, or the radio stops
+    /// answering, the task reopens and reinitializes it.  The task ends if
+    /// anything else goes wrong, or once all the handles and the stream
+    /// have been dropped.
+    pub async fn open(portname: PathBuf, config: LinkConfig, init: RadioInit) -> Result<(AsyncLoraStik, ReceivedStream)> {
+        let mut radio = AsyncRadio::open(&portname).await?;
+        let settings = radio.configure(&init).await?;
+        let mut turns = Turns::new(&config);
+        turns.tune(&settings);
+        let stats = turns.sharedstats();
+
+        let (txblockstx, txblocksrx) = mpsc::channel(2);
+        let (readeroutput, rx) = mpsc::unbounded_channel();
+        let link = AsyncLink { radio, readeroutput, txblocksrx,
+                               nextblock: None,
+                               txclosed: false,
+                               heard: None,
+                               readqual: config.readqual,
+                               turns,
+                               init };
+        tokio::spawn(async move {
+            if let Err(e) = link.mainloop().await {
+                error!("Radio link stopped: {}", e);
+            }
+        });
+        Ok((AsyncLoraStik { txblockstx, settings, stats }, ReceivedStream { rx }))
+    }
+
+    /// The settings in effect on the radio when it was opened.
+    pub fn settings(&self) -> &RadioSettings {
+        &self.settings
+    }
+
+    /// The counters kept by the link.
+    pub fn stats(&self) -> LinkStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Queue data for transmission.  Waits if the queue is full.
+    pub async fn send(&self, data: &[u8]) -> Result<()> {
+        self.txblockstx.send(data.to_vec()).await
+            .map_err(|_| Error::Disconnected(String::from("the link has stopped")))
+    }
+}
+
+/// The frames received over an async link.
+pub struct ReceivedStream {
+    rx: mpsc::UnboundedReceiver<ReceivedFrames>,
+}
+
+impl Stream for ReceivedStream {
+    type Item = ReceivedFrames;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<ReceivedFrames>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+/// The task behind an [`AsyncLoraStik`].
+struct AsyncLink {
+    radio: AsyncRadio,
+
+    // Frames going to the app
+    readeroutput: mpsc::UnboundedSender<ReceivedFrames>,
+
+    // Blocks to transmit
+    txblocksrx: mpsc::Receiver<Vec<u8>>,
+
+    // A block taken from txblocksrx while waiting in receive mode, to be
+    // sent next.
+    nextblock: Option<Vec<u8>>,
+
+    // Whether all the senders are gone.
+    txclosed: bool,
+
+    // A packet heard while listening before talking, to be handled once
+    // the send it interrupted is over.
+    heard: Option<Vec<u8>>,
+
+    readqual: bool,
+    turns: Turns,
+    init: RadioInit,
+}
+
+/// Take the next block to send, if there is one.
+fn trynext(nextblock: &mut Option<Vec<u8>>, txblocksrx: &mut mpsc::Receiver<Vec<u8>>) -> Option<Vec<u8>> {
+    nextblock.take().or_else(|| txblocksrx.try_recv().ok())
+}
+
+impl AsyncLink {
+    async fn mainloop(mut self) -> Result<()> {
+        loop {
+            match self.runradio().await {
+                Err(e @ Error::Disconnected(_)) | Err(e @ Error::Timeout { .. }) => self.recover(e).await,
+                res => return res,
+            }
+        }
+    }
+
+    async fn recover(&mut self, e: Error) {
+        warn!("{}; will reinitialize radio when it returns", e);
+        loop {
+            sleep(RECOVERWAIT).await;
+            let res = match AsyncRadio::open(&self.radio.portname).await {
+                Ok(mut radio) => radio.configure(&self.init).await.map(|_| radio),
+                Err(e) => Err(e),
+            };
+            match res {
+                Ok(radio) => {
+                    self.radio = radio;
+                    self.heard = None;
+                    break;
+                },
+                Err(e) => debug!("Radio reinitialization failed: {}", e),
+            }
+        }
+        warn!("Radio reinitialized; resuming");
+        self.turns.reset();
+    }
+
+    async fn dosend(&mut self, data: Vec<u8>) -> Result<()> {
+        let nextblock = &mut self.nextblock;
+        let txblocksrx = &mut self.txblocksrx;
+        let data = self.turns.fill(data, || trynext(nextblock, txblocksrx));
+
+        // Give receiver a change to process.
+        sleep(self.turns.txwait).await;
+
+        if !self.listen().await? || !self.turns.dutyclear(self.turns.framelen(data.len())) {
+            self.turns.putback(data);
+            return Ok(());
+        }
+
+        let queued = self.nextblock.is_some() || !self.txblocksrx.is_empty();
+        let turn = self.turns.txturn(queued, &data);
+
+        let frame = self.turns.buildframe(turn, &data);
+        if let Err(e) = self.radio.txframe(&frame).await {
+            self.turns.putback(data);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Listen before talking, if enabled, as
+    /// [`LoraStik`](crate::LoraStik) does.  A packet heard is kept for
+    /// `runradio` to handle.
+    async fn listen(&mut self) -> Result<bool> {
+        let window = match self.turns.lbtwindow() {
+            Some(window) => window,
+            None => return Ok(true),
+        };
+        self.radio.enterrx().await?;
+        sleep(window).await;
+        let frame = self.radio.rxstop().await?;
+        let heard = frame.is_some();
+        let rssi = self.radio.rssi().await?;
+        self.heard = frame;
+        Ok(self.turns.channelclear(rssi, heard))
+    }
+
+    async fn handlerx(&mut self, reply: Reply) -> Result<()> {
+        match reply {
+            Reply::RadioRx(frame) => self.handleframe(frame, true).await,
+            // Might get radio_err here.  That's harmless.
+            _ => Ok(()),
+        }
+    }
+
+    async fn handleframe(&mut self, decoded: Vec<u8>, readqual: bool) -> Result<()> {
+        trace!("DECODED: {}", format_escape_default(&decoded));
+        let radioqual = if readqual && (self.readqual || self.turns.wantsqual()) {
+            Some(self.radio.readqual().await?)
+        } else {
+            None
+        };
+
+        let Received { payloads, power, sender, ourturn } = match self.turns.rxframe(decoded, radioqual.as_ref().map(|q| q.0.as_str())) {
+            Some(frame) => frame,
+            None => return Ok(()),
+        };
+
+        // Nobody may be listening any more; that's fine.
+        let radioqual = if self.readqual { radioqual } else { None };
+        for payload in payloads {
+            let _ = self.readeroutput.send(ReceivedFrames { payload, qual: radioqual.clone(), power, sender });
+        }
+
+        if ourturn {
+            self.adapt().await?;
+            self.dosend(vec![]).await?;
+        }
+        Ok(())
+    }
+
+    async fn adapt(&mut self) -> Result<()> {
+        if let Some(modulation) = self.turns.adrswitch() {
+            self.radio.setmodulation(&modulation).await?;
+        }
+        if let Some(power) = self.turns.powerchange() {
+            self.radio.setpower(power).await?;
+        }
+        Ok(())
+    }
+
+    async fn rxstop(&mut self) -> Result<()> {
+        if let Some(frame) = self.radio.rxstop().await? {
+            self.handleframe(frame, false).await?;
+        }
+        Ok(())
+    }
+
+    async fn runradio(&mut self) -> Result<()> {
+        loop {
+            // Packets heard while listening before talking, or set aside
+            // while waiting for the replies to commands.
+            let heard = self.heard.take().into_iter().chain(std::mem::take(&mut self.radio.deferred));
+            for frame in heard.collect::<Vec<_>>() {
+                self.handleframe(frame, false).await?;
+            }
+            self.adapt().await?;
+            for (sender, payload) in self.turns.expired() {
+                let _ = self.readeroutput.send(ReceivedFrames { payload, qual: None, power: None, sender });
+            }
+
+            if self.txclosed && self.nextblock.is_none() && !self.turns.hasextra()
+                && self.readeroutput.is_closed() {
+                debug!("Nothing left to send or receive; stopping");
+                return Ok(());
+            }
+
+            if let Some(delayamt) = self.turns.txdelayrequired() {
+                // We can't transmit yet.  Just read, but with a time box.
+                self.radio.enterrx().await?;
+                match timeout(delayamt, self.radio.readreply()).await {
+                    Ok(reply) => {
+                        self.handlerx(reply?).await?;
+                        continue;
+                    },
+                    Err(_) => {
+                        debug!("readerthread: txdelay timeout expired");
+                        self.turns.cleartxdelay();
+                        if self.turns.pending() || (self.nextblock.is_some() && self.turns.cansend()) {
+                            self.rxstop().await?;
+                            continue;
+                        }
+                        // Now we can fall through to the rest of the logic - already in read mode.
+                    }
+                }
+            } else {
+                if self.turns.pending() {
+                    self.dosend(vec![]).await?;
+                    continue;
+                }
+                // With reliable delivery, new data may have to wait for what
+                // was sent to be acknowledged.
+                if self.turns.cansend() {
+                    if let Some(data) = trynext(&mut self.nextblock, &mut self.txblocksrx) {
+                        self.dosend(data).await?;
+                        continue;
+                    }
+                }
+                self.radio.enterrx().await?;
+            }
+
+            // In rx mode; wait for either a write request or data.
+            let deadline = self.turns.deadline();
+            let cansend = self.turns.cansend();
+            loop {
+                tokio::select! {
+                    reply = self.radio.readreply() => {
+                        self.handlerx(reply?).await?;
+                        break;
+                    },
+                    block = self.txblocksrx.recv(), if !self.txclosed && cansend => match block {
+                        Some(data) => {
+                            self.nextblock = Some(data);
+                            self.rxstop().await?;
+                            break;
+                        },
+                        None => {
+                            debug!("All senders are gone");
+                            self.txclosed = true;
+                        },
+                    },
+                    // Time for a keepalive, to check whether to fall back, to
+                    // send a frame again, or to stop holding frames.
+                    _ = sleep(deadline.unwrap_or_default()), if deadline.is_some() => {
+                        self.rxstop().await?;
+                        break;
+                    },
+                }
+            }
+        }
+    }
+}
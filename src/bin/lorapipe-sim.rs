@@ -0,0 +1,414 @@
+/*
+    Copyright (C) 2019  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+*/
+
+/* An emulator for a set of RN2903 radios sharing a frequency.  Each
+emulated radio is a pseudo-terminal; point lorapipe at the slave side
+and it will believe it is talking to a LoStik. */
+
+use simplelog::*;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use log::*;
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use serialport::posix::TTYPort;
+use serialport::SerialPort;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "lorapipe-sim", about = "Emulate RN2903 radios on pseudo-terminals", author = "John Goerzen <jgoerzen@complete.org>")]
+struct Opt {
+    /// Activate debug mode
+    #[structopt(short, long)]
+    debug: bool,
+
+    /// Number of radios to emulate
+    #[structopt(long, default_value = "2")]
+    radios: usize,
+
+    /// Create symlinks PREFIX0, PREFIX1, ... pointing at the pseudo-terminals
+    #[structopt(long, parse(from_os_str))]
+    link: Option<PathBuf>,
+
+    /// Percentage of frames [0..100] that each receiver silently fails to hear
+    #[structopt(long, default_value = "0")]
+    loss: f64,
+
+    /// Fixed time (ms) that every frame spends on the air
+    #[structopt(long, default_value = "50")]
+    airtime: u64,
+
+    /// Additional time (ms) on the air per byte of frame
+    #[structopt(long, default_value = "1")]
+    bytetime: u64,
+
+    /// Let overlapping transmissions arrive intact instead of corrupting each other
+    #[structopt(long)]
+    nocollisions: bool,
+
+    /// Seed for the loss generator.  Taken from the clock if not given.
+    #[structopt(long)]
+    seed: Option<u64>,
+
+    /// Answer to sys get ver.  If it names an RN2483, that module's bands
+    /// and power range are enforced.
+    #[structopt(long, default_value = "RN2903 1.0.5 Nov 06 2018 10:45:27")]
+    ver: String,
+
+    /// SNR reported for received packets sent at 20 dBm and 125 kHz bandwidth, in dB; less at lower powers and wider bandwidths
+    #[structopt(long, default_value = "10", allow_hyphen_values = true)]
+    snr: i32,
+}
+
+/// RSSI reported by an idle channel and by one carrying a transmission.
+const NOISEFLOOR: &str = "-115";
+const SIGNAL: &str = "-60";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+    Idle,
+    /// In receive mode.  The number distinguishes one `radio rx` from the next.
+    Rx(u64),
+    Tx,
+}
+
+struct Radio {
+    writer: Box<dyn SerialPort>,
+    mode: Mode,
+    rxgen: u64,
+    settings: HashMap<String, String>,
+    snr: i32,
+}
+
+/// A frame on the air.
+struct Transmission {
+    id: u64,
+    from: usize,
+    freq: String,
+    // Spreading factor and bandwidth; a receiver must match both.
+    sf: String,
+    bw: String,
+    pwr: i32,
+    corrupted: bool,
+}
+
+/// Everything shared between the radios: the radios themselves and
+/// the channel they share.
+struct Air {
+    radios: Vec<Radio>,
+    onair: Vec<Transmission>,
+    nexttx: u64,
+    rng: u64,
+    loss: f64,
+    airtime: u64,
+    bytetime: u64,
+    collisions: bool,
+    ver: String,
+    snr: i32,
+}
+
+impl Radio {
+    fn new(writer: Box<dyn SerialPort>, rn2483: bool) -> Radio {
+        let mut settings = HashMap::new();
+        let freq = if rn2483 { "868100000" } else { "923300000" };
+        for (k, v) in &[("mod", "lora"), ("freq", freq), ("pwr", "2"), ("sf", "sf12"),
+                        ("bw", "125"), ("cr", "4/5"), ("wdt", "15000"), ("prlen", "8"),
+                        ("crc", "on"), ("iqi", "off"), ("sync", "34"), ("bt", "0.5"),
+                        ("afcbw", "41.7"), ("rxbw", "25"), ("bitrate", "50000"), ("fdev", "25000")] {
+            settings.insert(String::from(*k), String::from(*v));
+        }
+        Radio { writer, mode: Mode::Idle, rxgen: 0, settings, snr: 0 }
+    }
+
+    fn reply(&mut self, line: &str) {
+        trace!("SIMOUT: {}", line);
+        let data = format!("{}\r\n", line);
+        if let Err(e) = self.writer.write_all(data.as_bytes()) {
+            warn!("Error writing to pty: {}", e);
+        }
+    }
+
+    fn get(&self, param: &str) -> &str {
+        self.settings.get(param).map(|s| s.as_str()).unwrap_or("")
+    }
+}
+
+/// Check that a value is acceptable for a `radio set` parameter.
+fn validparam(rn2483: bool, param: &str, value: &str) -> bool {
+    let num = value.parse::<i64>();
+    match param {
+        "freq" if rn2483 => num.map(|f| (433_050_000..=434_790_000).contains(&f)
+                                   || (863_000_000..=870_000_000).contains(&f)).unwrap_or(false),
+        "freq" => num.map(|f| (902_000_000..=928_000_000).contains(&f)).unwrap_or(false),
+        "pwr" if rn2483 => num.map(|p| (-3..=15).contains(&p)).unwrap_or(false),
+        "pwr" => num.map(|p| (2..=20).contains(&p)).unwrap_or(false),
+        "sf" => ["sf7", "sf8", "sf9", "sf10", "sf11", "sf12"].contains(&value),
+        "bw" => ["125", "250", "500"].contains(&value),
+        "cr" => ["4/5", "4/6", "4/7", "4/8"].contains(&value),
+        "crc" | "iqi" => ["on", "off"].contains(&value),
+        "mod" => ["lora", "fsk"].contains(&value),
+        "wdt" => num.map(|w| (0..=4_294_967_295).contains(&w)).unwrap_or(false),
+        "prlen" => num.map(|p| (0..=65535).contains(&p)).unwrap_or(false),
+        "sync" => !value.is_empty() && value.len() <= 16 && hex::decode(if value.len() % 2 == 1 { format!("0{}", value) } else { String::from(value) }).is_ok(),
+        "bt" | "afcbw" | "rxbw" | "bitrate" | "fdev" => !value.is_empty(),
+        _ => false,
+    }
+}
+
+impl Air {
+    /// xorshift64*; more than good enough for deciding which frames to drop.
+    fn random(&mut self) -> f64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        (self.rng.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn channelbusy(&self, freq: &str) -> bool {
+        self.onair.iter().any(|t| t.freq == freq)
+    }
+
+    fn rn2483(&self) -> bool {
+        self.ver.starts_with("RN2483")
+    }
+}
+
+/// Handle one command from lorapipe.
+fn command(air: &Arc<Mutex<Air>>, idx: usize, line: &str) {
+    let mut a = air.lock().unwrap();
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let resp: String = match words.as_slice() {
+        ["sys", "get", "ver"] | ["sys", "reset"] => a.ver.clone(),
+        ["sys", "get", "hweui"] => format!("0004A30B0000{:04X}", idx),
+        ["sys", "get", "vdd"] => String::from("3300"),
+        // The RN2483 needs to be told the band; the RN2903 has only one.
+        ["mac", "reset"] if a.rn2483() => String::from("invalid_param"),
+        ["mac", "reset", "433"] | ["mac", "reset", "868"] if a.rn2483() => String::from("ok"),
+        ["mac", "reset", _] => String::from("invalid_param"),
+        ["mac", "reset"] | ["mac", "resume"] => String::from("ok"),
+        ["mac", "pause"] => String::from("4294967245"),
+        ["radio", "get", "snr"] => a.radios[idx].snr.to_string(),
+        ["radio", "get", "rssi"] => {
+            let freq = String::from(a.radios[idx].get("freq"));
+            String::from(if a.channelbusy(&freq) { SIGNAL } else { NOISEFLOOR })
+        },
+        ["radio", "get", param] => match a.radios[idx].settings.get(*param) {
+            Some(v) => v.clone(),
+            None => String::from("invalid_param"),
+        },
+        ["radio", "set", param, value] => {
+            if a.radios[idx].mode != Mode::Idle {
+                String::from("busy")
+            } else if validparam(a.rn2483(), param, value) {
+                a.radios[idx].settings.insert(String::from(*param), String::from(*value));
+                String::from("ok")
+            } else {
+                String::from("invalid_param")
+            }
+        },
+        ["radio", "rx", _] => {
+            if a.radios[idx].mode != Mode::Idle {
+                String::from("busy")
+            } else {
+                a.radios[idx].rxgen += 1;
+                let rxgen = a.radios[idx].rxgen;
+                a.radios[idx].mode = Mode::Rx(rxgen);
+                let wdt: u64 = a.radios[idx].get("wdt").parse().unwrap_or(0);
+                if wdt > 0 {
+                    let air2 = air.clone();
+                    thread::spawn(move || rxwatchdog(air2, idx, rxgen, Duration::from_millis(wdt)));
+                }
+                String::from("ok")
+            }
+        },
+        ["radio", "rxstop"] => {
+            if let Mode::Rx(_) = a.radios[idx].mode {
+                a.radios[idx].mode = Mode::Idle;
+            }
+            String::from("ok")
+        },
+        ["radio", "tx", data] => {
+            match hex::decode(data) {
+                Ok(ref frame) if frame.len() <= 255 => {
+                    if a.radios[idx].mode != Mode::Idle {
+                        String::from("busy")
+                    } else {
+                        a.radios[idx].mode = Mode::Tx;
+                        let txid = a.nexttx;
+                        a.nexttx += 1;
+                        let freq = String::from(a.radios[idx].get("freq"));
+                        let collided = a.collisions && a.channelbusy(&freq);
+                        if collided {
+                            debug!("radio {}: collision on {}", idx, freq);
+                            for t in a.onair.iter_mut().filter(|t| t.freq == freq) {
+                                t.corrupted = true;
+                            }
+                        }
+                        let sf = String::from(a.radios[idx].get("sf"));
+                        let bw = String::from(a.radios[idx].get("bw"));
+                        let pwr = a.radios[idx].get("pwr").parse().unwrap_or(20);
+                        a.onair.push(Transmission { id: txid, from: idx, freq, sf, bw, pwr, corrupted: collided });
+
+                        // Only radios already listening when the preamble starts can hear it.
+                        let listeners: Vec<(usize, u64)> = a.radios.iter().enumerate()
+                            .filter_map(|(i, r)| match r.mode {
+                                Mode::Rx(rxgen) if i != idx => Some((i, rxgen)),
+                                _ => None,
+                            }).collect();
+                        let airtime = Duration::from_millis(a.airtime + a.bytetime * frame.len() as u64);
+                        let air2 = air.clone();
+                        let frame = frame.clone();
+                        thread::spawn(move || txcomplete(air2, txid, frame, listeners, airtime));
+                        String::from("ok")
+                    }
+                },
+                _ => String::from("invalid_param"),
+            }
+        },
+        _ => String::from("invalid_param"),
+    };
+    a.radios[idx].reply(&resp);
+}
+
+/// Wait out the airtime of a frame, then deliver it to whoever heard it.
+fn txcomplete(air: Arc<Mutex<Air>>, txid: u64, frame: Vec<u8>, listeners: Vec<(usize, u64)>, airtime: Duration) {
+    let start = Instant::now();
+    thread::sleep(airtime);
+    let mut a = air.lock().unwrap();
+    let pos = a.onair.iter().position(|t| t.id == txid).expect("Transmission vanished");
+    let tx = a.onair.remove(pos);
+    debug!("radio {}: {} bytes on the air for {:?}{}", tx.from, frame.len(), start.elapsed(),
+           if tx.corrupted { " (corrupted)" } else { "" });
+
+    a.radios[tx.from].mode = Mode::Idle;
+    a.radios[tx.from].reply("radio_tx_ok");
+
+    for (i, rxgen) in listeners {
+        if a.radios[i].mode != Mode::Rx(rxgen) || a.radios[i].get("freq") != tx.freq {
+            continue;
+        }
+        if a.radios[i].get("sf") != tx.sf || a.radios[i].get("bw") != tx.bw {
+            debug!("radio {}: can't decode frame from radio {} at {} {}", i, tx.from, tx.sf, tx.bw);
+            continue;
+        }
+        if tx.corrupted {
+            // The radio saw carrier but couldn't decode it.
+            a.radios[i].mode = Mode::Idle;
+            a.radios[i].reply("radio_err");
+        } else if a.random() * 100.0 < a.loss {
+            debug!("radio {}: dropping frame from radio {}", i, tx.from);
+        } else {
+            a.radios[i].mode = Mode::Idle;
+            // A wider bandwidth lets in more noise.
+            let bw: f64 = tx.bw.parse().unwrap_or(125.0);
+            a.radios[i].snr = a.snr - (10.0 * (bw / 125.0).log10()).round() as i32 - (20 - tx.pwr);
+            a.radios[i].reply(&format!("radio_rx  {}", hex::encode_upper(&frame)));
+        }
+    }
+}
+
+/// Emulates the radio's watchdog timer expiring during `radio rx`.
+fn rxwatchdog(air: Arc<Mutex<Air>>, idx: usize, rxgen: u64, wdt: Duration) {
+    thread::sleep(wdt);
+    let mut a = air.lock().unwrap();
+    if a.radios[idx].mode == Mode::Rx(rxgen) {
+        a.radios[idx].mode = Mode::Idle;
+        a.radios[idx].reply("radio_err");
+    }
+}
+
+/// Read commands from one pty and act on them.
+fn radiothread(air: Arc<Mutex<Air>>, idx: usize, reader: Box<dyn SerialPort>) -> io::Result<()> {
+    let mut br = BufReader::new(reader);
+    loop {
+        let mut buf = String::new();
+        match br.read_line(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(_) => {
+                let line = buf.trim();
+                if !line.is_empty() {
+                    trace!("radio {} SIMIN: {}", idx, line);
+                    command(&air, idx, line);
+                }
+            },
+            // No process has the slave open; wait for one to come along.
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => (),
+            Err(e) => {
+                debug!("radio {}: read error {}; waiting", idx, e);
+                thread::sleep(Duration::from_millis(250));
+            }
+        }
+    }
+}
+
+fn main() {
+    let opt = Opt::from_args();
+
+    if opt.debug {
+        WriteLogger::init(LevelFilter::Trace, Config::default(), io::stderr()).expect("Failed to init log");
+    }
+
+    let seed = opt.seed.unwrap_or_else(|| {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64
+    }) | 1;
+
+    let mut radios = Vec::new();
+    let mut readers = Vec::new();
+    // Keep the slave side open ourselves so the masters don't see a hangup
+    // every time lorapipe exits.
+    let mut slaves = Vec::new();
+
+    for i in 0..opt.radios {
+        let (mut master, mut slave) = TTYPort::pair().expect("Failed to create pty");
+        slave.set_exclusive(false).expect("Failed to make pty non-exclusive");
+        master.set_timeout(Duration::from_secs(3600)).expect("Failed to set pty timeout");
+        let name = slave.name().expect("pty has no name");
+        if let Some(ref prefix) = opt.link {
+            let mut linkname = prefix.clone().into_os_string();
+            linkname.push(i.to_string());
+            let _ = fs::remove_file(&linkname);
+            symlink(&name, &linkname).expect("Failed to create symlink");
+            println!("radio {}: {} -> {}", i, linkname.to_string_lossy(), name);
+        } else {
+            println!("radio {}: {}", i, name);
+        }
+        radios.push(Radio::new(master.try_clone().expect("Failed to clone pty"), opt.ver.starts_with("RN2483")));
+        readers.push(master.try_clone().expect("Failed to clone pty"));
+        slaves.push(slave);
+    }
+    io::stdout().flush().unwrap();
+
+    let air = Arc::new(Mutex::new(Air { radios, onair: vec![], nexttx: 0, rng: seed,
+                                        loss: opt.loss, airtime: opt.airtime,
+                                        bytetime: opt.bytetime, collisions: !opt.nocollisions,
+                                        ver: opt.ver.clone(), snr: opt.snr }));
+
+    let threads: Vec<_> = readers.into_iter().enumerate().map(|(i, reader)| {
+        let air2 = air.clone();
+        thread::spawn(move || radiothread(air2, i, reader).expect("Failure in radiothread"))
+    }).collect();
+
+    for t in threads {
+        t.join().unwrap();
+    }
+}
@@ -0,0 +1,243 @@
+/*
+    Copyright (C) 2019  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+*/
+
+use crate::error::{Error, Result};
+use crate::ser::LoraSer;
+use log::*;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long most commands may take to answer.
+pub const REPLYTIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long a reset may take to answer.
+pub const RESETTIMEOUT: Duration = Duration::from_secs(5);
+
+/// A line from the radio, parsed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reply {
+    Ok,
+    InvalidParam,
+    Busy,
+    RadioErr,
+    RadioTxOk,
+    /// A received packet
+    RadioRx(Vec<u8>),
+    /// Anything else, such as the answer to a `get` command
+    Value(String),
+}
+
+impl Reply {
+    pub fn parse(line: &str) -> Reply {
+        match line {
+            "ok" => Reply::Ok,
+            "invalid_param" => Reply::InvalidParam,
+            "busy" => Reply::Busy,
+            "radio_err" => Reply::RadioErr,
+            "radio_tx_ok" => Reply::RadioTxOk,
+            _ if line.starts_with("radio_rx ") => {
+                match hex::decode(line[9..].trim()) {
+                    Ok(frame) => Reply::RadioRx(frame),
+                    Err(_) => {
+                        // As far as we're concerned, that's as good as a CRC error.
+                        warn!("Error with hex decoding of received packet");
+                        Reply::RadioErr
+                    }
+                }
+            },
+            _ => Reply::Value(String::from(line)),
+        }
+    }
+
+    /// Check that this, the reply to `cmd`, is ok.
+    pub fn ok(self, cmd: &str) -> Result<()> {
+        match self {
+            Reply::Ok => Ok(()),
+            r => Err(Error::UnexpectedReply { command: String::from(cmd), reply: format!("{:?}, expected ok", r) }),
+        }
+    }
+
+    /// The value given in this, the reply to `cmd`, such as the answer to
+    /// `radio get`.
+    pub fn value(self, cmd: &str) -> Result<String> {
+        match self {
+            Reply::Value(v) => Ok(v),
+            r => Err(Error::UnexpectedReply { command: String::from(cmd), reply: format!("{:?}", r) }),
+        }
+    }
+}
+
+/// Reads the lines from the radio and sends them down the channel to
+/// the processing bits.  If the port goes away, an error is sent down
+/// the channel and the port is reopened.
+fn readerlinesthread(mut ser: LoraSer, tx: crossbeam_channel::Sender<Result<Reply>>) {
+    loop {
+        let err = match ser.readln() {
+            Ok(Some(l)) => {
+                tx.send(Ok(Reply::parse(&l))).unwrap();
+                continue;
+            },
+            Ok(None) => String::from("EOF"),
+            Err(e) => e.to_string(),
+        };
+        warn!("{:?}: lost connection to radio: {}", ser.portname, err);
+        tx.send(Err(Error::Disconnected(err))).unwrap();
+        ser.reconnect();
+    }
+}
+
+/// Sends commands to the radio and collects the replies.
+///
+/// Everything the radio says arrives on one channel.  Packets received
+/// while we are waiting for the answer to a command are set aside, and put
+/// back on the channel once the command is done, so they reach the receive
+/// path instead of being taken for the answer.
+#[derive(Clone)]
+pub struct CmdEngine {
+    ser: LoraSer,
+    replies: crossbeam_channel::Receiver<Result<Reply>>,
+    // For putting set-aside packets back.
+    requeue: crossbeam_channel::Sender<Result<Reply>>,
+    deferred: Vec<Vec<u8>>,
+}
+
+impl CmdEngine {
+    /// Creates a new engine, starting the thread that reads from the radio.
+    pub fn new(ser: LoraSer) -> CmdEngine {
+        let (tx, replies) = crossbeam_channel::unbounded();
+        let ser2 = ser.clone();
+        let requeue = tx.clone();
+
+        thread::spawn(move || readerlinesthread(ser2, tx));
+
+        CmdEngine { ser, replies, requeue, deferred: vec![] }
+    }
+
+    /// The channel everything from the radio arrives on.  Only read it
+    /// directly while no command is in progress.
+    pub fn events(&self) -> &crossbeam_channel::Receiver<Result<Reply>> {
+        &self.replies
+    }
+
+    /// Send a line to the radio without waiting for any reply.
+    pub fn send(&mut self, line: &str) -> Result<()> {
+        self.ser.writeln(String::from(line))
+    }
+
+    /// Throw away everything the radio has said so far.
+    pub fn flush(&mut self) {
+        while self.replies.try_recv().is_ok() {
+        }
+    }
+
+    /// Send a command and wait up to `timeout` for its reply.
+    ///
+    /// Replies left over from earlier, and a radio_err left over from an
+    /// earlier receive, are discarded: radio_err is never the first reply
+    /// to a command.  If a radio_err turns out to be the only thing the
+    /// radio says, though, it is returned.
+    pub fn command(&mut self, cmd: &str, timeout: Duration) -> Result<Reply> {
+        let res = self.docommand(cmd, timeout);
+        self.requeuedeferred();
+        res
+    }
+
+    /// Like [`command`](CmdEngine::command), but packets set aside while
+    /// waiting are kept for [`takedeferred`](CmdEngine::takedeferred)
+    /// rather than put back on the channel.
+    pub fn commandkeepdeferred(&mut self, cmd: &str, timeout: Duration) -> Result<Reply> {
+        self.docommand(cmd, timeout)
+    }
+
+    fn docommand(&mut self, cmd: &str, timeout: Duration) -> Result<Reply> {
+        while let Ok(stale) = self.replies.try_recv() {
+            match stale? {
+                Reply::RadioRx(frame) => self.deferred.push(frame),
+                r => debug!("Discarding stale reply {:?}", r),
+            }
+        }
+
+        self.send(cmd)?;
+
+        let mut sawerr = false;
+        loop {
+            match self.waitreply(cmd, timeout) {
+                Ok(Reply::RadioErr) => {
+                    debug!("Ignoring lingering radio_err before reply to {}", cmd);
+                    sawerr = true;
+                },
+                Err(Error::Timeout { .. }) if sawerr => return Ok(Reply::RadioErr),
+                res => return res,
+            }
+        }
+    }
+
+    /// Wait for a further reply to a command that gives more than one, such
+    /// as the radio_tx_ok after a `radio tx`.
+    pub fn nextreply(&mut self, cmd: &str, timeout: Duration) -> Result<Reply> {
+        let res = self.waitreply(cmd, timeout);
+        self.requeuedeferred();
+        res
+    }
+
+    /// Send a command that should be answered with ok.
+    pub fn commandok(&mut self, cmd: &str, timeout: Duration) -> Result<()> {
+        self.command(cmd, timeout)?.ok(cmd)
+    }
+
+    /// Send a command that should be answered with a value, such as
+    /// `radio get`, and return it.
+    pub fn commandvalue(&mut self, cmd: &str, timeout: Duration) -> Result<String> {
+        self.command(cmd, timeout)?.value(cmd)
+    }
+
+    /// Packets set aside by
+    /// [`commandkeepdeferred`](CmdEngine::commandkeepdeferred), in the
+    /// order they arrived.  The caller takes responsibility for them; they
+    /// won't be put back on the channel.  Other commands put back what
+    /// they set aside before returning, so this is empty after them.
+    pub fn takedeferred(&mut self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.deferred)
+    }
+
+    fn waitreply(&mut self, cmd: &str, timeout: Duration) -> Result<Reply> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.replies.recv_timeout(remaining) {
+                Ok(reply) => match reply? {
+                    Reply::RadioRx(frame) => {
+                        debug!("Setting aside packet received while waiting for reply to {}", cmd);
+                        self.deferred.push(frame);
+                    },
+                    r => return Ok(r),
+                },
+                Err(e) if e.is_timeout() => {
+                    return Err(Error::Timeout { command: String::from(cmd), timeout });
+                },
+                Err(e) => return Err(Error::Disconnected(e.to_string())),
+            }
+        }
+    }
+
+    fn requeuedeferred(&mut self) {
+        for frame in self.deferred.drain(..) {
+            self.requeue.send(Ok(Reply::RadioRx(frame))).unwrap();
+        }
+    }
+}
@@ -0,0 +1,287 @@
+/*
+    Copyright (C) 2019  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+*/
+
+//! Datagram framing: the payload of a frame is a series of records, each
+//! carrying a whole message or a fragment of one, so messages keep their
+//! boundaries.  Several small messages can share a frame, and one too big
+//! for a frame is split into numbered fragments and put back together at
+//! the other end.
+//!
+//! A record for a whole message is its type and a length byte, then the
+//! message.  A fragment is its type, the message's id, the fragment's
+//! index, and a length byte, then that part of the message; the last
+//! fragment has a type of its own.  Fragments of a message that don't all
+//! arrive are dropped after a timeout.
+
+use log::*;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+
+const WHOLE: u8 = 1;
+const FRAGMENT: u8 = 2;
+const LASTFRAGMENT: u8 = 3;
+
+const WHOLEHEADER: usize = 2;
+const FRAGMENTHEADER: usize = 4;
+
+/// Packs queued messages into the payloads of frames.
+#[derive(Clone, Default)]
+pub(crate) struct Packer {
+    queue: VecDeque<Vec<u8>>,
+    // The id for the next message to be fragmented.
+    nextid: u8,
+    // For the message at the head of the queue, if it is being
+    // fragmented: how much has been sent, its id, and the next index.
+    offset: usize,
+    id: u8,
+    index: u8,
+}
+
+impl Packer {
+    /// Queue a message to send.  Empty messages are ignored.
+    pub fn push(&mut self, message: Vec<u8>) {
+        if !message.is_empty() {
+            self.queue.push_back(message);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    // Done with the message at the head of the queue.
+    fn pop(&mut self) {
+        self.queue.pop_front();
+        self.offset = 0;
+    }
+
+    /// Add records to `payload` for as much of the queue as fits in `max`
+    /// bytes.  Messages that fit in a frame are never split; bigger ones
+    /// are fragmented to fill the room there is.  Returns whether anything
+    /// was added.
+    pub fn pack(&mut self, payload: &mut Vec<u8>, max: usize) -> bool {
+        let mut added = false;
+        while let Some(message) = self.queue.front() {
+            let room = max.saturating_sub(payload.len());
+            if self.offset == 0 && message.len() + WHOLEHEADER <= max {
+                if message.len() + WHOLEHEADER > room {
+                    break;
+                }
+                payload.push(WHOLE);
+                payload.push(message.len() as u8);
+                payload.extend_from_slice(message);
+                self.pop();
+                added = true;
+                continue;
+            }
+
+            if room <= FRAGMENTHEADER {
+                break;
+            }
+            if self.offset == 0 {
+                self.id = self.nextid;
+                self.nextid = self.nextid.wrapping_add(1);
+                self.index = 0;
+            }
+            let take = (room - FRAGMENTHEADER).min(message.len() - self.offset).min(usize::from(u8::MAX));
+            let last = self.offset + take == message.len();
+            if !last && self.index == u8::MAX {
+                warn!("Dropping a {}-byte message, too big to send in 256 fragments", message.len());
+                self.pop();
+                continue;
+            }
+            payload.extend_from_slice(&[if last { LASTFRAGMENT } else { FRAGMENT }, self.id, self.index, take as u8]);
+            payload.extend_from_slice(&message[self.offset..self.offset + take]);
+            trace!("Fragment {} of message {}: {} bytes", self.index, self.id, take);
+            added = true;
+            if last {
+                self.pop();
+            } else {
+                self.offset += take;
+                self.index += 1;
+            }
+        }
+        added
+    }
+}
+
+// The fragments of a message received so far.
+#[derive(Clone)]
+struct Partial {
+    pieces: BTreeMap<u8, Vec<u8>>,
+    last: Option<u8>,
+    started: Instant,
+}
+
+/// Takes apart the payloads of received frames, putting fragmented
+/// messages back together.
+#[derive(Clone, Default)]
+pub(crate) struct Reassembler {
+    partial: BTreeMap<u8, Partial>,
+}
+
+impl Reassembler {
+    /// Take apart the payload of a frame.  Returns the messages now
+    /// complete.
+    pub fn received(&mut self, payload: &[u8]) -> Vec<Vec<u8>> {
+        let mut messages = vec![];
+        let mut rest = payload;
+        while let Some((kind, r)) = rest.split_first() {
+            let header = match *kind {
+                WHOLE => WHOLEHEADER,
+                FRAGMENT | LASTFRAGMENT => FRAGMENTHEADER,
+                _ => {
+                    warn!("Ignoring the rest of a datagram frame with a record of unknown type {}", kind);
+                    break;
+                },
+            };
+            if r.len() < header - 1 || r.len() < header - 1 + usize::from(r[header - 2]) {
+                warn!("Ignoring a datagram record cut short");
+                break;
+            }
+            let len = usize::from(r[header - 2]);
+            let data = &r[header - 1..header - 1 + len];
+            if *kind == WHOLE {
+                messages.push(data.to_vec());
+            } else {
+                messages.extend(self.fragment(r[0], r[1], *kind == LASTFRAGMENT, data));
+            }
+            rest = &r[header - 1 + len..];
+        }
+        messages
+    }
+
+    // Take in a fragment; returns its message if it is now complete.
+    fn fragment(&mut self, id: u8, index: u8, last: bool, data: &[u8]) -> Option<Vec<u8>> {
+        let partial = self.partial.entry(id)
+            .or_insert_with(|| Partial { pieces: BTreeMap::new(), last: None, started: Instant::now() });
+        if index == 0 && partial.pieces.contains_key(&0) {
+            debug!("Starting message {} over", id);
+            *partial = Partial { pieces: BTreeMap::new(), last: None, started: Instant::now() };
+        }
+        partial.pieces.insert(index, data.to_vec());
+        if last {
+            partial.last = Some(index);
+        }
+        match partial.last {
+            Some(last) if partial.pieces.len() == usize::from(last) + 1 => {
+                let partial = self.partial.remove(&id)?;
+                trace!("Message {} complete in {} fragments", id, partial.pieces.len());
+                Some(partial.pieces.into_values().flatten().collect())
+            },
+            _ => None,
+        }
+    }
+
+    /// How long until the oldest incomplete message is given up on, with
+    /// `timeout` allowed for each.
+    pub fn deadline(&self, timeout: Duration) -> Option<Duration> {
+        self.partial.values().map(|p| timeout.saturating_sub(p.started.elapsed())).min()
+    }
+
+    /// Give up on messages not completed within `timeout`.
+    pub fn expire(&mut self, timeout: Duration) {
+        self.partial.retain(|id, p| {
+            let keep = p.started.elapsed() < timeout;
+            if !keep {
+                warn!("Dropping message {}: only {} fragment(s) arrived within {:?}", id, p.pieces.len(), timeout);
+            }
+            keep
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Pack everything queued into frames of up to `max` bytes.
+    fn frames(messages: &[Vec<u8>], max: usize) -> Vec<Vec<u8>> {
+        let mut packer = Packer::default();
+        for m in messages {
+            packer.push(m.clone());
+        }
+        let mut frames = vec![];
+        while !packer.is_empty() {
+            let mut payload = vec![];
+            assert!(packer.pack(&mut payload, max));
+            assert!(payload.len() <= max);
+            frames.push(payload);
+        }
+        frames
+    }
+
+    fn message(id: u8, len: usize) -> Vec<u8> {
+        (0..len).map(|i| id.wrapping_add(i as u8)).collect()
+    }
+
+    #[test]
+    fn boundaries() {
+        let messages = vec![message(1, 3), message(2, 5), message(3, 1), message(4, 40)];
+        let frames = frames(&messages, 50);
+        assert_eq!(frames.len(), 2);
+        let mut reassembler = Reassembler::default();
+        let received: Vec<_> = frames.iter().flat_map(|f| reassembler.received(f)).collect();
+        assert_eq!(received, messages);
+    }
+
+    #[test]
+    fn notsplit() {
+        // Messages that fit in a frame go whole into the next one rather
+        // than being split to fill the room left.
+        let messages = vec![message(1, 30), message(2, 30)];
+        let frames = frames(&messages, 50);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[1][0], WHOLE);
+    }
+
+    #[test]
+    fn fragmented() {
+        let messages = vec![message(1, 10), message(2, 600), message(3, 10)];
+        let mut frames = frames(&messages, 100);
+        assert!(frames.len() >= 7);
+        let mut reassembler = Reassembler::default();
+        // Out of order fragments are put back in place.
+        frames.swap(2, 3);
+        let received: Vec<_> = frames.iter().flat_map(|f| reassembler.received(f)).collect();
+        assert_eq!(received, messages);
+        assert!(reassembler.deadline(Duration::from_secs(60)).is_none());
+    }
+
+    #[test]
+    fn lostfragment() {
+        let messages = vec![message(1, 10), message(2, 600), message(3, 10), message(4, 300)];
+        let frames = frames(&messages, 100);
+        let mut reassembler = Reassembler::default();
+        let received: Vec<_> = frames.iter().enumerate().filter(|(i, _)| *i != 3)
+            .flat_map(|(_, f)| reassembler.received(f)).collect();
+        assert_eq!(received, vec![message(1, 10), message(3, 10), message(4, 300)]);
+        assert!(reassembler.deadline(Duration::from_secs(60)).is_some());
+        reassembler.expire(Duration::ZERO);
+        assert!(reassembler.deadline(Duration::from_secs(60)).is_none());
+    }
+
+    #[test]
+    fn damaged() {
+        let mut reassembler = Reassembler::default();
+        let mut payload = vec![WHOLE, 2, 7, 8];
+        payload.extend_from_slice(&[WHOLE, 5, 1]);
+        assert_eq!(reassembler.received(&payload), vec![vec![7, 8]]);
+        assert_eq!(reassembler.received(&[9, 1, 2]), Vec::<Vec<u8>>::new());
+    }
+}
@@ -0,0 +1,150 @@
+/*
+    Copyright (C) 2019  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+*/
+
+use log::*;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// The window duty cycle limits are measured over.
+pub const DUTYWINDOW: Duration = Duration::from_secs(3600);
+
+/// A ledger of recent transmissions, for staying within a duty cycle
+/// limit over a sliding window.
+#[derive(Clone, Debug)]
+pub struct DutyCycle {
+    /// The fraction of the window that may be spent transmitting.
+    limit: f64,
+    window: Duration,
+    // When each transmission ended, and how long it took.  A transmission
+    // counts in full until its end leaves the window.
+    ledger: VecDeque<(Instant, Duration)>,
+}
+
+impl DutyCycle {
+    pub fn new(limit: f64, window: Duration) -> DutyCycle {
+        DutyCycle { limit, window, ledger: VecDeque::new() }
+    }
+
+    /// How much transmitting the window allows.
+    pub fn budget(&self) -> Duration {
+        self.window.mul_f64(self.limit)
+    }
+
+    /// How much transmitting has been done in the window.
+    pub fn used(&mut self) -> Duration {
+        let now = Instant::now();
+        while let Some((end, _)) = self.ledger.front() {
+            if *end + self.window <= now {
+                self.ledger.pop_front();
+            } else {
+                break;
+            }
+        }
+        self.ledger.iter().map(|(_, airtime)| *airtime).sum()
+    }
+
+    /// How much transmitting is left in the window.
+    pub fn remaining(&mut self) -> Duration {
+        self.budget().saturating_sub(self.used())
+    }
+
+    /// How long to wait before a transmission of `airtime` fits in the
+    /// budget; None if it can go now.  A transmission bigger than the whole
+    /// budget is let through once the window is empty, since waiting
+    /// longer would not help.
+    pub fn waitfor(&mut self, airtime: Duration) -> Option<Duration> {
+        let budget = self.budget();
+        let mut used = self.used();
+        if used + airtime <= budget || self.ledger.is_empty() {
+            return None;
+        }
+        let now = Instant::now();
+        for (end, oldairtime) in self.ledger.iter() {
+            used -= *oldairtime;
+            if used + airtime <= budget || used == Duration::from_secs(0) {
+                return Some((*end + self.window).saturating_duration_since(now));
+            }
+        }
+        None
+    }
+
+    /// Note a transmission of `airtime` that is starting now.
+    pub fn record(&mut self, airtime: Duration) {
+        self.ledger.push_back((Instant::now() + airtime, airtime));
+        let used = self.used();
+        info!("Duty cycle: {:?} of {:?} used in the last {:?}; {:?} left",
+              used, self.budget(), self.window, self.budget().saturating_sub(used));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    // 10 ms of transmitting in a 100 ms window.
+    fn ledger() -> DutyCycle {
+        DutyCycle::new(0.1, MS * 100)
+    }
+
+    #[test]
+    fn budget() {
+        let mut duty = ledger();
+        assert_eq!(duty.budget(), MS * 10);
+        assert_eq!(duty.waitfor(MS * 10), None);
+        duty.record(MS * 6);
+        assert_eq!(duty.used(), MS * 6);
+        assert_eq!(duty.remaining(), MS * 4);
+        assert_eq!(duty.waitfor(MS * 4), None);
+        // Out of budget until the transmission leaves the window.
+        let wait = duty.waitfor(MS * 5).unwrap();
+        assert!(wait > MS * 50 && wait <= MS * 106, "{:?}", wait);
+        thread::sleep(wait);
+        assert_eq!(duty.waitfor(MS * 5), None);
+        assert_eq!(duty.used(), Duration::ZERO);
+    }
+
+    #[test]
+    fn oldest() {
+        let mut duty = ledger();
+        duty.record(MS * 4);
+        thread::sleep(MS * 30);
+        duty.record(MS * 4);
+        // Only the first has to leave the window to make room.
+        let wait = duty.waitfor(MS * 4).unwrap();
+        assert!(wait > MS * 20 && wait <= MS * 74, "{:?}", wait);
+        thread::sleep(wait);
+        assert_eq!(duty.used(), MS * 4);
+        assert_eq!(duty.waitfor(MS * 4), None);
+    }
+
+    #[test]
+    fn oversized() {
+        // A frame longer than the whole budget goes once the window is
+        // empty, rather than never.
+        let mut duty = ledger();
+        assert_eq!(duty.waitfor(MS * 20), None);
+        duty.record(MS * 20);
+        let wait = duty.waitfor(MS * 20).unwrap();
+        assert!(wait <= MS * 120, "{:?}", wait);
+        thread::sleep(wait);
+        assert_eq!(duty.waitfor(MS * 20), None);
+    }
+}
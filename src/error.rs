@@ -0,0 +1,98 @@
+/*
+    Copyright (C) 2019  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+*/
+
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+/// Errors from talking to the radio.
+#[derive(Debug)]
+pub enum Error {
+    /// An I/O error, such as failing to open the port or read an init file.
+    Io(io::Error),
+
+    /// The connection to the radio was lost.  [`crate::LoraStik::mainloop`]
+    /// recovers from this by itself.
+    Disconnected(String),
+
+    /// The radio did not answer a command in time.
+    Timeout { command: String, timeout: Duration },
+
+    /// The radio answered a command with something we didn't expect.
+    UnexpectedReply { command: String, reply: String },
+
+    /// The radio rejected a command while being initialized.
+    InitFailed { command: String, reply: String },
+
+    /// A configuration that can't be used.
+    Config(String),
+
+    /// The radio is not one we can work with, or its firmware is too old.
+    Unsupported(String),
+
+    /// A setting read back from the radio is not what was asked for.
+    SettingMismatch { param: String, requested: String, actual: String },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Disconnected(why) => write!(f, "Lost connection to radio: {}", why),
+            Error::Timeout { command, timeout } =>
+                write!(f, "Radio did not reply to \"{}\" within {:?}", command, timeout),
+            Error::UnexpectedReply { command, reply } =>
+                write!(f, "Unexpected response to \"{}\": {}", command, reply),
+            Error::InitFailed { command, reply } =>
+                write!(f, "Bad response from radio during initialization to \"{}\": {}", command, reply),
+            Error::Config(why) => write!(f, "Invalid configuration: {}", why),
+            Error::Unsupported(why) => write!(f, "Unsupported radio: {}", why),
+            Error::SettingMismatch { param, requested, actual } =>
+                write!(f, "Radio setting {} is {} after initialization, but {} was requested", param, actual, requested),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::Io(e) => e,
+            Error::Disconnected(_) => io::Error::new(io::ErrorKind::NotConnected, e),
+            Error::Timeout { .. } => io::Error::new(io::ErrorKind::TimedOut, e),
+            Error::Config(_) => io::Error::new(io::ErrorKind::InvalidInput, e),
+            _ => io::Error::other(e),
+        }
+    }
+}
@@ -0,0 +1,528 @@
+/*
+    Copyright (C) 2019  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+*/
+
+//! Forward error correction across frames: after every group of data
+//! frames, parity frames are sent from which the receiver can rebuild as
+//! many lost data frames as there are parity frames, without anything
+//! being sent back.
+//!
+//! The code is a systematic Reed-Solomon code over GF(256), with a Cauchy
+//! matrix for the parity, so any `data` of the `data + parity` frames of
+//! a group are enough.  Payloads differ in length, so each is coded as a
+//! length byte, the payload, and zeros out to the longest in the group;
+//! parity frames are that long.  A group is cut short when there is
+//! nothing more to send, so the data already sent isn't left
+//! unprotected; it then gets parity in proportion.
+
+use crate::error::{Error, Result};
+use crate::frame::{Fec, FrameType};
+use log::*;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The room forward error correction takes up in a frame: the header
+/// field, and the length byte in parity frames.
+pub const FECRESERVE: usize = 4 + 1;
+
+/// Settings for forward error correction.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FecConfig {
+    /// Data frames per group.
+    pub data: u8,
+    /// Parity frames sent after each group; up to this many lost frames
+    /// of the group can be rebuilt.
+    pub parity: u8,
+}
+
+impl fmt::Display for FecConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.data, self.parity)
+    }
+}
+
+impl FromStr for FecConfig {
+    type Err = Error;
+
+    /// Parse DATA:PARITY, such as 8:2.
+    fn from_str(s: &str) -> Result<FecConfig> {
+        let bad = || Error::Config(format!("bad FEC setting {}; give data and parity frames per group, such as 8:2", s));
+        let (data, parity) = s.split_once(':').ok_or_else(bad)?;
+        let data: u8 = data.trim().parse().map_err(|_| bad())?;
+        let parity: u8 = parity.trim().parse().map_err(|_| bad())?;
+        if data == 0 || parity == 0 || usize::from(data) + usize::from(parity) > 255 {
+            return Err(Error::Config(format!("FEC groups need at least one data and one parity frame, \
+                                              and no more than 255 in all; got {}", s)));
+        }
+        Ok(FecConfig { data, parity })
+    }
+}
+
+// GF(256) with the polynomial x^8 + x^4 + x^3 + x^2 + 1.  EXP is doubled
+// up so the sum of two logarithms can index it directly.
+const fn gftables() -> ([u8; 512], [u8; 256]) {
+    let mut exp = [0u8; 512];
+    let mut log = [0u8; 256];
+    let mut x: u16 = 1;
+    let mut i = 0;
+    while i < 255 {
+        exp[i] = x as u8;
+        exp[i + 255] = x as u8;
+        log[x as usize] = i as u8;
+        x <<= 1;
+        if x & 0x100 != 0 {
+            x ^= 0x11d;
+        }
+        i += 1;
+    }
+    (exp, log)
+}
+
+const EXP: [u8; 512] = gftables().0;
+const LOG: [u8; 256] = gftables().1;
+
+fn mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        0
+    } else {
+        EXP[usize::from(LOG[usize::from(a)]) + usize::from(LOG[usize::from(b)])]
+    }
+}
+
+fn inv(a: u8) -> u8 {
+    EXP[255 - usize::from(LOG[usize::from(a)])]
+}
+
+/// The coefficient of data frame `i` in parity frame `j` of a group of
+/// `n` data frames.
+fn coefficient(n: usize, j: usize, i: usize) -> u8 {
+    inv((n + j) as u8 ^ i as u8)
+}
+
+/// `payload` coded as a length byte, itself, and zeros out to `len`.
+fn shard(payload: &[u8], len: usize) -> Vec<u8> {
+    let mut shard = Vec::with_capacity(len);
+    shard.push(payload.len() as u8);
+    shard.extend_from_slice(payload);
+    shard.resize(len, 0);
+    shard
+}
+
+/// Invert a square matrix over GF(256); None if it is singular.
+fn invert(mut m: Vec<Vec<u8>>) -> Option<Vec<Vec<u8>>> {
+    let n = m.len();
+    let mut out: Vec<Vec<u8>> = (0..n).map(|i| (0..n).map(|j| u8::from(i == j)).collect()).collect();
+    for col in 0..n {
+        let pivot = (col..n).find(|r| m[*r][col] != 0)?;
+        m.swap(col, pivot);
+        out.swap(col, pivot);
+        let scale = inv(m[col][col]);
+        for j in 0..n {
+            m[col][j] = mul(m[col][j], scale);
+            out[col][j] = mul(out[col][j], scale);
+        }
+        for r in 0..n {
+            let factor = m[r][col];
+            if r != col && factor != 0 {
+                for j in 0..n {
+                    m[r][j] ^= mul(factor, m[col][j]);
+                    out[r][j] ^= mul(factor, out[col][j]);
+                }
+            }
+        }
+    }
+    Some(out)
+}
+
+/// Groups data frames as they are sent and works out the parity frames
+/// to follow them.
+#[derive(Clone)]
+pub(crate) struct FecEncoder {
+    config: FecConfig,
+    group: u8,
+    // Payloads of the group so far.
+    payloads: Vec<Vec<u8>>,
+    // Parity frames waiting to be sent.
+    parity: VecDeque<(Fec, Vec<u8>)>,
+}
+
+impl FecEncoder {
+    pub fn new(config: &FecConfig) -> FecEncoder {
+        let group = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos() as u8).unwrap_or(0);
+        FecEncoder { config: config.clone(), group, payloads: vec![], parity: VecDeque::new() }
+    }
+
+    /// Whether a group has been started and not yet finished.
+    pub fn ingroup(&self) -> bool {
+        !self.payloads.is_empty()
+    }
+
+    /// Whether parity frames are waiting to be sent.
+    pub fn paritywaiting(&self) -> bool {
+        !self.parity.is_empty()
+    }
+
+    /// Add a data frame carrying `payload` to the group, finishing it if
+    /// it is full.
+    pub fn add(&mut self, payload: &[u8]) -> Fec {
+        let fec = Fec { group: self.group, index: self.payloads.len() as u8,
+                        data: self.config.data, parity: self.config.parity };
+        self.payloads.push(payload.to_vec());
+        if self.payloads.len() == usize::from(self.config.data) {
+            self.finish();
+        }
+        fec
+    }
+
+    /// Finish the group, working out its parity frames.
+    pub fn finish(&mut self) {
+        if self.payloads.is_empty() {
+            return;
+        }
+        let n = self.payloads.len();
+        let len = 1 + self.payloads.iter().map(|p| p.len()).max().unwrap_or(0);
+        let shards: Vec<Vec<u8>> = self.payloads.iter().map(|p| shard(p, len)).collect();
+        // A group cut short gets its share of parity.
+        let data = usize::from(self.config.data);
+        let k = (usize::from(self.config.parity) * n).div_ceil(data).max(1);
+        for j in 0..k {
+            let mut parity = vec![0u8; len];
+            for (i, shard) in shards.iter().enumerate() {
+                let c = coefficient(n, j, i);
+                for (p, s) in parity.iter_mut().zip(shard) {
+                    *p ^= mul(c, *s);
+                }
+            }
+            let fec = Fec { group: self.group, index: (n + j) as u8, data: n as u8, parity: k as u8 };
+            self.parity.push_back((fec, parity));
+        }
+        trace!("FEC group {} finished with {} data frames", self.group, n);
+        self.group = self.group.wrapping_add(1);
+        self.payloads.clear();
+    }
+
+    /// The next parity frame to send.
+    pub fn nextparity(&mut self) -> Option<(Fec, Vec<u8>)> {
+        self.parity.pop_front()
+    }
+
+    /// A parity frame from [`nextparity`](FecEncoder::nextparity) could
+    /// not be sent; it goes out next.
+    pub fn putback(&mut self, fec: Fec, payload: Vec<u8>) {
+        self.parity.push_front((fec, payload));
+    }
+}
+
+/// Collects the frames of a group as they are received, handing on data
+/// frames in order and rebuilding lost ones from parity.
+#[derive(Clone, Default)]
+pub(crate) struct FecDecoder {
+    group: Option<u8>,
+    // Whether the group has been handed on as far as it can be, so
+    // anything more from it is ignored.
+    finished: bool,
+    // Data frames received, by index, including those handed on, and
+    // parity frames, by index among the parity frames.
+    data: BTreeMap<u8, Vec<u8>>,
+    parity: BTreeMap<u8, Vec<u8>>,
+    // How many data frames the group has, once a parity frame says.
+    count: Option<u8>,
+    // The index of the next data frame to hand on.
+    next: u8,
+    lastheard: Option<Instant>,
+}
+
+impl FecDecoder {
+    /// Take in a frame of a group.  Returns the payloads now ready to be
+    /// handed on, in order.
+    pub fn received(&mut self, kind: FrameType, fec: Fec, payload: Vec<u8>) -> Vec<Vec<u8>> {
+        let mut ready = vec![];
+        if self.group != Some(fec.group) {
+            ready = self.flush();
+            *self = FecDecoder { group: Some(fec.group), ..FecDecoder::default() };
+        }
+        if self.finished {
+            trace!("Ignoring late frame {} of FEC group {}", fec.index, fec.group);
+            return ready;
+        }
+        self.lastheard = Some(Instant::now());
+        match kind {
+            FrameType::Parity if fec.index >= fec.data => {
+                self.count = Some(fec.data);
+                self.parity.insert(fec.index - fec.data, payload);
+            },
+            FrameType::Data if fec.index >= self.next => {
+                self.data.insert(fec.index, payload);
+            },
+            _ => trace!("Ignoring frame {} of FEC group {}", fec.index, fec.group),
+        }
+        ready.extend(self.advance());
+        if self.count.map(|n| self.next >= n).unwrap_or(false) {
+            self.finished = true;
+        }
+        ready
+    }
+
+    /// Whether data frames are held waiting for one before them.
+    fn holding(&self) -> bool {
+        !self.finished && self.data.range(self.next..).next().is_some()
+    }
+
+    /// How long until frames held waiting for a lost one are handed on
+    /// anyway, if no more of the group arrives within `hold`.
+    pub fn deadline(&self, hold: Duration) -> Option<Duration> {
+        if self.holding() {
+            self.lastheard.map(|t| hold.saturating_sub(t.elapsed()))
+        } else {
+            None
+        }
+    }
+
+    /// Hand on the frames held, if nothing more of the group has arrived
+    /// within `hold`.
+    pub fn expire(&mut self, hold: Duration) -> Vec<Vec<u8>> {
+        match self.deadline(hold) {
+            Some(left) if left == Duration::ZERO => self.flush(),
+            _ => vec![],
+        }
+    }
+
+    // Hand on data frames in order as far as they go, rebuilding any
+    // missing if there is enough parity.
+    fn advance(&mut self) -> Vec<Vec<u8>> {
+        let mut ready = vec![];
+        loop {
+            while let Some(payload) = self.data.get(&self.next) {
+                ready.push(payload.clone());
+                self.next = self.next.wrapping_add(1);
+            }
+            let missing = self.count.map(|n| self.next < n).unwrap_or(false);
+            if !(missing && self.rebuild()) {
+                break;
+            }
+        }
+        ready
+    }
+
+    // Hand on everything received of the group, rebuilding what can be
+    // and skipping the rest.
+    fn flush(&mut self) -> Vec<Vec<u8>> {
+        if self.finished || self.group.is_none() {
+            return vec![];
+        }
+        let mut ready = self.advance();
+        let held: Vec<u8> = self.data.range(self.next..).map(|(i, _)| *i).collect();
+        if let Some(last) = held.last() {
+            let lost = usize::from(*last) + 1 - usize::from(self.next) - held.len();
+            warn!("Lost {} frame(s) of FEC group {} that couldn't be rebuilt", lost, self.group.unwrap_or(0));
+            for i in held {
+                ready.extend(self.data.get(&i).cloned());
+            }
+        } else if let Some(n) = self.count {
+            if self.next < n {
+                warn!("Lost {} frame(s) of FEC group {} that couldn't be rebuilt",
+                      n - self.next, self.group.unwrap_or(0));
+            }
+        }
+        self.finished = true;
+        ready
+    }
+
+    // Rebuild the missing data frames of the group, if enough frames have
+    // been received.
+    fn rebuild(&mut self) -> bool {
+        let n = match self.count {
+            Some(n) => usize::from(n),
+            None => return false,
+        };
+        let data: Vec<u8> = self.data.keys().copied().filter(|i| usize::from(*i) < n).collect();
+        if data.len() + self.parity.len() < n || data.len() == n {
+            return false;
+        }
+        let len = match self.parity.values().next() {
+            Some(p) => p.len(),
+            None => return false,
+        };
+
+        // Each row gives one received frame in terms of the data frames.
+        let mut rows = vec![];
+        let mut shards = vec![];
+        for i in &data {
+            let payload = &self.data[i];
+            if payload.len() >= len {
+                debug!("Frame {} of FEC group is longer than its parity; can't rebuild", i);
+                return false;
+            }
+            rows.push((0..n).map(|c| u8::from(c == usize::from(*i))).collect());
+            shards.push(shard(payload, len));
+        }
+        for (j, parity) in self.parity.iter().take(n - data.len()) {
+            if parity.len() != len {
+                debug!("Parity frames of FEC group differ in length; can't rebuild");
+                return false;
+            }
+            rows.push((0..n).map(|c| coefficient(n, usize::from(*j), c)).collect());
+            shards.push(parity.clone());
+        }
+        let inverse = match invert(rows) {
+            Some(inverse) => inverse,
+            None => return false,
+        };
+
+        let mut rebuilt = 0;
+        for (i, row) in inverse.iter().enumerate() {
+            if self.data.contains_key(&(i as u8)) {
+                continue;
+            }
+            let mut shard = vec![0u8; len];
+            for (c, s) in row.iter().zip(&shards) {
+                for (b, v) in shard.iter_mut().zip(s) {
+                    *b ^= mul(*c, *v);
+                }
+            }
+            let plen = usize::from(shard[0]);
+            if plen >= len {
+                debug!("Rebuilt frame {} of FEC group makes no sense; dropping it", i);
+                return false;
+            }
+            self.data.insert(i as u8, shard[1..=plen].to_vec());
+            rebuilt += 1;
+        }
+        info!("Rebuilt {} lost frame(s) of FEC group {}", rebuilt, self.group.unwrap_or(0));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matmul(a: &[Vec<u8>], b: &[Vec<u8>]) -> Vec<Vec<u8>> {
+        a.iter().map(|row| (0..b[0].len()).map(|j| {
+            row.iter().zip(b).fold(0, |acc, (x, brow)| acc ^ mul(*x, brow[j]))
+        }).collect()).collect()
+    }
+
+    fn identity(n: usize) -> Vec<Vec<u8>> {
+        (0..n).map(|i| (0..n).map(|j| u8::from(i == j)).collect()).collect()
+    }
+
+    // The frames of a group sent with `config`: kind, header field, payload.
+    fn encode(config: &FecConfig, payloads: &[Vec<u8>]) -> Vec<(FrameType, Fec, Vec<u8>)> {
+        let mut encoder = FecEncoder::new(config);
+        let mut frames: Vec<_> = payloads.iter().map(|p| (FrameType::Data, encoder.add(p), p.clone())).collect();
+        encoder.finish();
+        while let Some((fec, parity)) = encoder.nextparity() {
+            frames.push((FrameType::Parity, fec, parity));
+        }
+        frames
+    }
+
+    fn decode(frames: &[(FrameType, Fec, Vec<u8>)], lost: &[usize]) -> Vec<Vec<u8>> {
+        let mut decoder = FecDecoder::default();
+        let mut out = vec![];
+        for (i, (kind, fec, payload)) in frames.iter().enumerate() {
+            if !lost.contains(&i) {
+                out.extend(decoder.received(*kind, *fec, payload.clone()));
+            }
+        }
+        // The start of the next group hands on whatever is held.
+        let next = Fec { group: frames[0].1.group.wrapping_add(1), index: 0, data: 4, parity: 2 };
+        out.extend(decoder.received(FrameType::Data, next, vec![]).into_iter().filter(|p| !p.is_empty()));
+        out
+    }
+
+    fn payloads(n: usize) -> Vec<Vec<u8>> {
+        (0..n).map(|i| (0..=(i * 7 % 20)).map(|b| (b * 31 + i) as u8).collect()).collect()
+    }
+
+    #[test]
+    fn gf() {
+        assert_eq!(mul(2, 0x80), 0x1d);
+        for a in 0..=255u8 {
+            assert_eq!(mul(a, 1), a);
+            assert_eq!(mul(a, 0), 0);
+            if a != 0 {
+                assert_eq!(mul(a, inv(a)), 1);
+            }
+            for b in [3u8, 0x53, 0xca] {
+                assert_eq!(mul(a, b), mul(b, a));
+                assert_eq!(mul(a, b ^ 0x11), mul(a, b) ^ mul(a, 0x11));
+            }
+        }
+    }
+
+    #[test]
+    fn inversion() {
+        let n = 5;
+        let cauchy: Vec<Vec<u8>> = (0..n).map(|j| (0..n).map(|i| coefficient(n, j, i)).collect()).collect();
+        let inverse = invert(cauchy.clone()).unwrap();
+        assert_eq!(matmul(&cauchy, &inverse), identity(n));
+        assert_eq!(invert(identity(3)).unwrap(), identity(3));
+
+        let singular = vec![vec![1, 2, 3], vec![4, 5, 6], vec![1, 2, 3]];
+        assert!(invert(singular).is_none());
+        assert!(invert(vec![vec![0, 0], vec![0, 7]]).is_none());
+    }
+
+    #[test]
+    fn rebuild() {
+        let config = FecConfig { data: 4, parity: 2 };
+        let payloads = payloads(4);
+        let frames = encode(&config, &payloads);
+        assert_eq!(frames.len(), 6);
+        for a in 0..frames.len() {
+            for b in a..frames.len() {
+                let lost = if a == b { vec![a] } else { vec![a, b] };
+                assert_eq!(decode(&frames, &lost), payloads, "lost {:?}", lost);
+            }
+        }
+        assert_eq!(decode(&frames, &[]), payloads);
+    }
+
+    #[test]
+    fn shortgroup() {
+        let config = FecConfig { data: 4, parity: 2 };
+        let payloads = payloads(2);
+        let frames = encode(&config, &payloads);
+        // Half a group gets half the parity.
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[2].1, Fec { group: frames[0].1.group, index: 2, data: 2, parity: 1 });
+        assert_eq!(decode(&frames, &[0]), payloads);
+        assert_eq!(decode(&frames, &[1]), payloads);
+    }
+
+    #[test]
+    fn toomanylost() {
+        let config = FecConfig { data: 4, parity: 2 };
+        let payloads = payloads(4);
+        let frames = encode(&config, &payloads);
+        // Three data frames lost; only the one left is handed on.
+        assert_eq!(decode(&frames, &[0, 1, 3]), vec![payloads[2].clone()]);
+        assert_eq!(decode(&frames, &[1, 2, 4]), vec![payloads[0].clone(), payloads[3].clone()]);
+    }
+
+    #[test]
+    fn config() {
+        assert_eq!("8:2".parse::<FecConfig>().unwrap(), FecConfig { data: 8, parity: 2 });
+        assert!("8".parse::<FecConfig>().is_err());
+        assert!("0:2".parse::<FecConfig>().is_err());
+        assert!("250:10".parse::<FecConfig>().is_err());
+    }
+}
@@ -0,0 +1,385 @@
+/*
+    Copyright (C) 2019  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+*/
+
+//! The link-layer header at the start of every frame.
+//!
+//! Legacy frames, the only kind before version 1, start with one byte:
+//! 0x00 when the sender has nothing more queued, 0x01 when more is coming
+//! right away, and 0x02 when more is coming but the receiver may take a
+//! turn first.  0x80 is added when a control block (a length byte and that
+//! many bytes of adaptive data rate messages) follows.
+//!
+//! Version 1 frames start with two bytes.  The high nibble of the first
+//! is [`VERSION1`] and the low nibble the frame type; the second holds
+//! [`Turn`] in its low two bits, as in a legacy frame, and flags saying
+//! which optional fields follow, in this order: a [`Sequence`] (three
+//! bytes), an [`Ack`] (six bytes), an [`Fec`] (four bytes), source and
+//! destination addresses (a byte each), and a control block.  Numbers
+//! are big-endian.  Then comes the payload.  One more flag says the
+//! payload is datagram records.
+//!
+//! Either kind is understood on receipt, whatever [`Framing`] is sent.
+
+use crate::error::{Error, Result};
+use log::*;
+use std::fmt;
+use std::str::FromStr;
+
+/// The high nibble of the first byte of a version 1 frame.
+pub const VERSION1: u8 = 0x4;
+
+/// The destination address of a frame for every node.
+pub const BROADCAST: u8 = 0xff;
+
+/// The room source and destination addresses take in a frame.
+pub const ADDRRESERVE: usize = 2;
+
+// Flags in the second byte of a version 1 frame.
+const TURNMASK: u8 = 0x03;
+const HASSEQ: u8 = 0x04;
+const HASADDR: u8 = 0x08;
+const HASCONTROL: u8 = 0x10;
+const HASACK: u8 = 0x20;
+const HASFEC: u8 = 0x40;
+const DATAGRAM: u8 = 0x80;
+
+/// Which kind of header to send.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// The one-byte header understood by every version of lorapipe.
+    /// Only the turn can be sent.
+    #[default]
+    Legacy,
+    /// The versioned header.
+    V1,
+}
+
+pub const FRAMINGS: [Framing; 2] = [Framing::Legacy, Framing::V1];
+
+impl Framing {
+    /// The bytes a header takes, without optional fields.
+    pub fn overhead(self) -> usize {
+        match self {
+            Framing::Legacy => 1,
+            Framing::V1 => 2,
+        }
+    }
+}
+
+impl fmt::Display for Framing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Framing::Legacy => "legacy",
+            Framing::V1 => "v1",
+        })
+    }
+}
+
+impl FromStr for Framing {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Framing> {
+        FRAMINGS.iter().find(|f| f.to_string() == s.to_lowercase()).copied()
+            .ok_or_else(|| Error::Config(format!("unknown framing {}; use legacy or v1", s)))
+    }
+}
+
+/// Whose turn it is after a frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Turn {
+    /// The sender has nothing more queued.
+    Done = 0,
+    /// More frames follow right away.
+    More = 1,
+    /// More frames are queued, but the receiver may send first.
+    Yield = 2,
+}
+
+impl Turn {
+    fn from_bits(bits: u8) -> Option<Turn> {
+        match bits {
+            0 => Some(Turn::Done),
+            1 => Some(Turn::More),
+            2 => Some(Turn::Yield),
+            _ => None,
+        }
+    }
+}
+
+/// What a version 1 frame carries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameType {
+    /// Data for the application, if any, and the optional fields.
+    Data = 0,
+    /// Parity for rebuilding lost data frames of an FEC group.
+    Parity = 1,
+    /// A TDMA coordinator's beacon, starting a superframe.
+    Beacon = 2,
+}
+
+impl FrameType {
+    fn from_nibble(nibble: u8) -> Option<FrameType> {
+        match nibble {
+            0 => Some(FrameType::Data),
+            1 => Some(FrameType::Parity),
+            2 => Some(FrameType::Beacon),
+            _ => None,
+        }
+    }
+}
+
+/// The sequence number of a frame sent reliably.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sequence {
+    pub num: u16,
+    /// How many frames before this one the sender has yet to have
+    /// acknowledged, so a receiver that has lost track knows where to
+    /// start.
+    pub unacked: u8,
+}
+
+/// Acknowledgment of frames sent reliably.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ack {
+    /// The sequence number of the next frame expected; all before it have
+    /// been received.
+    pub next: u16,
+    /// Bit n is set if frame `next + 1 + n` has been received.
+    pub sack: u32,
+}
+
+/// Where a frame sent with forward error correction belongs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Fec {
+    pub group: u8,
+    /// Data frames come first in a group, then parity frames.
+    pub index: u8,
+    /// The number of data frames in the group.  Data frames give the
+    /// most there may be; parity frames, how many there were.
+    pub data: u8,
+    /// The number of parity frames in the group.
+    pub parity: u8,
+}
+
+/// The header of a frame.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header {
+    pub kind: FrameType,
+    pub turn: Turn,
+    pub seq: Option<Sequence>,
+    pub ack: Option<Ack>,
+    pub fec: Option<Fec>,
+    /// Source and destination.
+    pub addr: Option<(u8, u8)>,
+    /// Adaptive data rate messages; empty for none.
+    pub control: Vec<u8>,
+    /// Whether the payload is datagram records rather than a piece of a
+    /// stream.
+    pub datagram: bool,
+}
+
+impl Header {
+    /// A data frame header with no optional fields.
+    pub fn new(turn: Turn) -> Header {
+        Header { kind: FrameType::Data, turn, seq: None, ack: None, fec: None, addr: None, control: vec![], datagram: false }
+    }
+
+    /// The frame with this header and `payload`.  Legacy frames can only
+    /// carry data frames and the turn; the rest is left out.
+    pub fn encode(&self, framing: Framing, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![];
+        match framing {
+            Framing::Legacy => {
+                if self.kind != FrameType::Data || self.seq.is_some() || self.ack.is_some()
+                    || self.fec.is_some() || self.addr.is_some() || !self.control.is_empty() || self.datagram {
+                    debug!("Leaving out what legacy framing can't carry: {:?}", self);
+                }
+                frame.push(self.turn as u8);
+            },
+            Framing::V1 => {
+                let mut flags = self.turn as u8;
+                if self.seq.is_some() { flags |= HASSEQ; }
+                if self.ack.is_some() { flags |= HASACK; }
+                if self.fec.is_some() { flags |= HASFEC; }
+                if self.datagram { flags |= DATAGRAM; }
+                if self.addr.is_some() { flags |= HASADDR; }
+                if !self.control.is_empty() { flags |= HASCONTROL; }
+                frame.push(VERSION1 << 4 | self.kind as u8);
+                frame.push(flags);
+                if let Some(seq) = self.seq {
+                    frame.extend_from_slice(&seq.num.to_be_bytes());
+                    frame.push(seq.unacked);
+                }
+                if let Some(ack) = self.ack {
+                    frame.extend_from_slice(&ack.next.to_be_bytes());
+                    frame.extend_from_slice(&ack.sack.to_be_bytes());
+                }
+                if let Some(fec) = self.fec {
+                    frame.extend_from_slice(&[fec.group, fec.index, fec.data, fec.parity]);
+                }
+                if let Some((src, dst)) = self.addr {
+                    frame.extend_from_slice(&[src, dst]);
+                }
+                if !self.control.is_empty() {
+                    frame.push(self.control.len() as u8);
+                    frame.extend_from_slice(&self.control);
+                }
+            },
+        }
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// Split a received frame into its header, the kind of framing it
+    /// used, and its payload.  None if it can't be understood.
+    pub fn decode(frame: &[u8]) -> Option<(Header, Framing, &[u8])> {
+        let (first, rest) = frame.split_first()?;
+        if first >> 4 == VERSION1 {
+            let kind = FrameType::from_nibble(first & 0x0f);
+            let (flags, mut rest) = rest.split_first()?;
+            let (kind, turn) = match (kind, Turn::from_bits(flags & TURNMASK)) {
+                (Some(kind), Some(turn)) => (kind, turn),
+                _ => {
+                    debug!("Ignoring version 1 frame of unknown type {:#04x} or turn {:#04x}", first, flags);
+                    return None;
+                },
+            };
+            let mut header = Header::new(turn);
+            header.kind = kind;
+            header.datagram = flags & DATAGRAM != 0;
+            if flags & HASSEQ != 0 {
+                let (seq, r) = takebytes(rest, 3)?;
+                header.seq = Some(Sequence { num: u16::from_be_bytes([seq[0], seq[1]]), unacked: seq[2] });
+                rest = r;
+            }
+            if flags & HASACK != 0 {
+                let (ack, r) = takebytes(rest, 6)?;
+                header.ack = Some(Ack { next: u16::from_be_bytes([ack[0], ack[1]]),
+                                        sack: u32::from_be_bytes([ack[2], ack[3], ack[4], ack[5]]) });
+                rest = r;
+            }
+            if flags & HASFEC != 0 {
+                let (fec, r) = takebytes(rest, 4)?;
+                header.fec = Some(Fec { group: fec[0], index: fec[1], data: fec[2], parity: fec[3] });
+                rest = r;
+            }
+            if flags & HASADDR != 0 {
+                let (addr, r) = takebytes(rest, 2)?;
+                header.addr = Some((addr[0], addr[1]));
+                rest = r;
+            }
+            if flags & HASCONTROL != 0 {
+                let (control, r) = takecontrol(rest)?;
+                header.control = control;
+                rest = r;
+            }
+            Some((header, Framing::V1, rest))
+        } else {
+            match Turn::from_bits(*first) {
+                Some(turn) => Some((Header::new(turn), Framing::Legacy, rest)),
+                None => {
+                    debug!("Ignoring frame with unknown header byte {:#04x}", first);
+                    None
+                },
+            }
+        }
+    }
+}
+
+fn takebytes(data: &[u8], len: usize) -> Option<(&[u8], &[u8])> {
+    if data.len() < len {
+        debug!("Ignoring truncated frame");
+        return None;
+    }
+    Some(data.split_at(len))
+}
+
+fn takecontrol(data: &[u8]) -> Option<(Vec<u8>, &[u8])> {
+    let (len, rest) = data.split_first()?;
+    let (control, rest) = takebytes(rest, usize::from(*len))?;
+    Some((control.to_vec(), rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn v1roundtrip() {
+        let payload = b"payload";
+        for bits in 0..64u8 {
+            for turn in [Turn::Done, Turn::More, Turn::Yield] {
+                let mut header = Header::new(turn);
+                if bits & 1 != 0 { header.seq = Some(Sequence { num: 0xfffe, unacked: 3 }); }
+                if bits & 2 != 0 { header.ack = Some(Ack { next: 0x1234, sack: 0x8000_0001 }); }
+                if bits & 4 != 0 { header.fec = Some(Fec { group: 7, index: 5, data: 4, parity: 2 }); }
+                if bits & 8 != 0 { header.addr = Some((1, BROADCAST)); }
+                if bits & 16 != 0 { header.control = vec![1, 2, 3]; }
+                header.datagram = bits & 32 != 0;
+                header.kind = [FrameType::Data, FrameType::Parity, FrameType::Beacon][usize::from(bits % 3)];
+                let frame = header.encode(Framing::V1, payload);
+                let (decoded, framing, rest) = Header::decode(&frame).unwrap();
+                assert_eq!(decoded, header);
+                assert_eq!(framing, Framing::V1);
+                assert_eq!(rest, payload);
+            }
+        }
+    }
+
+    #[test]
+    fn legacy() {
+        for (first, turn) in [(0x00, Turn::Done), (0x01, Turn::More), (0x02, Turn::Yield)] {
+            let frame = [first, b'x'];
+            let (header, framing, rest) = Header::decode(&frame).unwrap();
+            assert_eq!(header, Header::new(turn));
+            assert_eq!(framing, Framing::Legacy);
+            assert_eq!(rest, b"x");
+        }
+        assert!(Header::decode(&[0x03, b'x']).is_none());
+        // Legacy frames don't carry control blocks.
+        assert!(Header::decode(&[0x81, 2, 9, 8, b'x']).is_none());
+        assert!(Header::decode(&[]).is_none());
+    }
+
+    #[test]
+    fn legacyencode() {
+        let mut header = Header::new(Turn::More);
+        header.seq = Some(Sequence { num: 1, unacked: 0 });
+        header.addr = Some((1, 2));
+        assert_eq!(header.encode(Framing::Legacy, b"x"), vec![0x01, b'x']);
+        header.control = vec![5];
+        assert_eq!(header.encode(Framing::Legacy, b"x"), vec![0x01, b'x']);
+    }
+
+    #[test]
+    fn truncated() {
+        let mut header = Header::new(Turn::Done);
+        header.seq = Some(Sequence { num: 1, unacked: 0 });
+        header.ack = Some(Ack { next: 2, sack: 0 });
+        header.fec = Some(Fec { group: 1, index: 0, data: 2, parity: 1 });
+        header.addr = Some((1, 2));
+        header.control = vec![1, 2];
+        let frame = header.encode(Framing::V1, b"");
+        for len in 1..frame.len() {
+            assert!(Header::decode(&frame[..len]).is_none(), "{} of {} bytes", len, frame.len());
+        }
+        assert!(Header::decode(&frame).is_some());
+        // Unknown frame type
+        assert!(Header::decode(&[VERSION1 << 4 | 0x0f, 0]).is_none());
+    }
+}
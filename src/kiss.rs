@@ -18,7 +18,8 @@
 use std::io;
 use std::io::{BufRead};
 use crate::lorastik::{LoraStik};
-pub use crate::pipe::loratostdout;
+use crate::radio::RadioDriver;
+pub use crate::pipe::{loratostdout, loratowriter};
 use format_escape_default::format_escape_default;
 use log::*;
 
@@ -30,9 +31,15 @@ const FEND: u8 = 0xC0;
 // const TFESC: u8 = 0xDD;
 
 /// A thread for stdin processing
-pub fn stdintolorakiss(ls: &mut LoraStik) -> io::Result<()> {
-    let stdin = io::stdin();
-    let mut br = io::BufReader::new(stdin);
+pub fn stdintolorakiss<D: RadioDriver>(ls: &mut LoraStik<D>) -> io::Result<()> {
+    kisstolora(io::stdin(), ls)
+}
+
+/// Transmit each KISS data frame read from `input`, until EOF.  The
+/// frames are sent with their KISS framing intact, so the other end can
+/// write them straight out with [`loratowriter`].
+pub fn kisstolora<R: io::Read, D: RadioDriver>(input: R, ls: &mut LoraStik<D>) -> io::Result<()> {
+    let mut br = io::BufReader::new(input);
 
     loop {
         let mut buf = Vec::new();
@@ -0,0 +1,86 @@
+/*
+    Copyright (C) 2019  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+*/
+
+//! Tools for running a link over LoRa radios such as the LoStik.
+//!
+//! This is the library behind the `lorapipe` command.  It opens the radio
+//! ([`LoraSer`]), drives it ([`Rn2903`], or anything else implementing
+//! [`RadioDriver`]), and takes care of taking turns with the other end
+//! ([`LoraStik`]).  [`LoraStream`] wraps the link in [`std::io::Read`] and
+//! [`std::io::Write`].  With the `async` feature, `asyncstik` offers the
+//! same link under tokio.
+//!
+//! ```no_run
+//! use lorapipe::{LinkConfig, LoraSer, LoraStik, RadioInit, Rn2903};
+//! use std::thread;
+//!
+//! # fn main() -> lorapipe::Result<()> {
+//! let ser = LoraSer::new("/dev/ttyUSB0".into())?;
+//! let config = LinkConfig { readqual: true, ..LinkConfig::default() };
+//! let (mut ls, received) = LoraStik::new(Rn2903::new(ser), config);
+//! ls.radiocfg(RadioInit::Default)?;
+//!
+//! let mut ls2 = ls.clone();
+//! thread::spawn(move || ls2.mainloop().expect("Failure in mainloop"));
+//!
+//! ls.transmit(b"Hello");
+//! let frame = received.recv().unwrap();
+//! println!("{:?} (SNR, RSSI: {:?})", frame.payload, frame.qual);
+//! # Ok(())
+//! # }
+//! ```
+
+mod net;
+mod arq;
+mod datagram;
+pub mod adr;
+pub mod airtime;
+pub mod ser;
+pub mod cmd;
+pub mod dutycycle;
+pub mod error;
+pub mod fec;
+pub mod frame;
+pub mod radio;
+pub mod radioconfig;
+pub mod region;
+pub mod rn2903;
+pub mod lorastik;
+pub mod pipe;
+pub mod stream;
+pub mod scan;
+pub mod tdma;
+mod turns;
+#[cfg(feature = "async")]
+pub mod asyncstik;
+pub mod kiss;
+
+pub use adr::{AdrConfig, Modulation, TpcConfig};
+pub use error::{Error, Result};
+pub use fec::FecConfig;
+pub use frame::{Framing, BROADCAST};
+pub use lorastik::{LbtConfig, LinkConfig, LinkStats, LoraStik, ReceivedFrames};
+pub use radio::RadioDriver;
+pub use radioconfig::{Module, RadioConfig, RadioInit, RadioSettings, RadioState, SettingChange, Version};
+pub use region::{Profile, Region};
+pub use rn2903::Rn2903;
+pub use ser::LoraSer;
+pub use stream::LoraStream;
+pub use tdma::TdmaConfig;
+#[cfg(feature = "async")]
+pub use asyncstik::{AsyncLoraStik, ReceivedStream};
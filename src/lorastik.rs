@@ -16,34 +16,186 @@
 
 */
 
-use crate::ser::LoraSer;
+use crate::adr::{AdrConfig, TpcConfig};
+use crate::error::{Error, Result};
+use crate::fec::FecConfig;
+use crate::frame::{Framing, BROADCAST};
+use crate::radio::RadioDriver;
+use crate::radioconfig::{RadioInit, RadioSettings};
+use crate::rn2903::Rn2903;
+use crate::tdma::TdmaConfig;
+use crate::turns::{Received, Turns};
 use log::*;
-use std::fs;
-use std::io::{BufRead, BufReader, Error, ErrorKind};
-use std::io;
-use crossbeam_channel;
-use hex;
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::Duration;
 use format_escape_default::format_escape_default;
-use std::path::PathBuf;
+use serde::Serialize;
 
-pub fn mkerror(msg: &str) -> Error {
-    Error::new(ErrorKind::Other, msg)
+/// Settings for a [`LoraStik`].  Start from `LinkConfig::default()`,
+/// which matches the defaults of the lorapipe command, and change what
+/// you need.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LinkConfig {
+    /// Read the SNR and RSSI from the radio after each received packet.
+    pub readqual: bool,
+
+    /// How long to pause before transmitting a packet, giving the other
+    /// radio a chance to get back into receive mode.
+    pub txwait: Duration,
+
+    /// How long to wait for the end-of-transmission signal before
+    /// transmitting anyway.  None works it out from the radio settings,
+    /// allowing for two of the largest frames on the air.
+    pub eotwait: Option<Duration>,
+
+    /// The largest frame to hand to the radio.
+    pub maxpacketsize: usize,
+
+    /// The header to send.  Both kinds are understood when received.
+    pub framing: Framing,
+
+    /// Pack as many bytes as possible into each frame, regardless of how
+    /// they were passed to [`LoraStik::transmit`].
+    pub pack: bool,
+
+    /// The longest to transmit at once before giving the other end a
+    /// chance.  None means no limit.
+    pub txslot: Option<Duration>,
+
+    /// The fraction of each hour that may be spent transmitting; 1.0 for
+    /// no limit.  None applies the legal limit of the band in use, if it
+    /// has one.
+    pub dutycycle: Option<f64>,
+
+    /// Listen before each transmission, and back off if the channel is
+    /// busy.  None transmits regardless.
+    pub lbt: Option<LbtConfig>,
+
+    /// Adapt the spreading factor, bandwidth, and coding rate to the
+    /// signal, by agreement with the other end, which must have it on too.
+    /// None keeps the initial settings.
+    pub adr: Option<AdrConfig>,
+
+    /// Turn the transmit power down as far as the other end can spare,
+    /// going by the SNR it reports.  The other end need not have it on,
+    /// but must have it or adaptive data rate on to send reports.  None
+    /// keeps the initial power.
+    pub tpc: Option<TpcConfig>,
+
+    /// Number frames, have the other end acknowledge them, and send again
+    /// those that are lost, handing on what is received whole and in
+    /// order.  Needs [`Framing::V1`].
+    pub reliable: bool,
+
+    /// Send parity frames after each group of data frames, from which the
+    /// other end can rebuild lost ones.  Needs [`Framing::V1`], and can't
+    /// be used with `reliable`.  Received parity is used either way.  None
+    /// sends no parity.
+    pub fec: Option<FecConfig>,
+
+    /// Send each block passed to `transmit` as a message of its own,
+    /// packing small ones together and fragmenting big ones, so the other
+    /// end gets them back whole.  Needs [`Framing::V1`].  Messages are
+    /// received either way.
+    pub datagram: bool,
+
+    /// How long to wait for the rest of a fragmented message before
+    /// dropping it.
+    pub fragtimeout: Duration,
+
+    /// This node's address.  Frames sent carry it and `dest`, and
+    /// received frames addressed to other nodes are dropped.  Needs
+    /// [`Framing::V1`].  None sends frames unaddressed, and receives all.
+    pub node: Option<u8>,
+
+    /// The address frames are sent to, with `node`; [`BROADCAST`] for
+    /// all nodes.
+    pub dest: u8,
+
+    /// Transmit only in this node's slot of a schedule beaconed by a
+    /// coordinator, rather than taking turns.  Needs `node`, and can't be
+    /// used with `adr`.  None takes turns.
+    pub tdma: Option<TdmaConfig>,
 }
 
-/// Received frames.  The option is populated only if
-/// readqual is true, and reflects the SNR and RSSI of the
-/// received packet.
+/// Settings for listen-before-talk.
 #[derive(Clone, Debug, PartialEq)]
-pub struct ReceivedFrames(pub Vec<u8>, pub Option<(String, String)>);
+pub struct LbtConfig {
+    /// The RSSI, in dBm, at or above which the channel is taken to be busy.
+    pub threshold: i32,
 
-#[derive(Clone)]
-pub struct LoraStik {
-    ser: LoraSer,
+    /// How long to listen before sampling the RSSI.
+    pub window: Duration,
+
+    /// The longest to back off for when the channel is busy; the actual
+    /// time is random, up to this.  None uses the time a full frame takes
+    /// on the air.
+    pub backoff: Option<Duration>,
+}
+
+/// Counters kept by a link, shared by all its clones.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct LinkStats {
+    /// Times the channel was checked before transmitting.
+    pub lbtchecks: u64,
+    /// Times the channel was busy, and transmitting was put off.
+    pub lbtbackoffs: u64,
+    /// The total time spent backing off.
+    pub lbtbackofftime: Duration,
+    /// Frames sent again because the other end didn't acknowledge them.
+    pub retransmissions: u64,
+}
+
+impl Default for LinkConfig {
+    fn default() -> LinkConfig {
+        LinkConfig {
+            readqual: false,
+            txwait: Duration::from_millis(120),
+            eotwait: None,
+            maxpacketsize: 100,
+            framing: Framing::default(),
+            pack: false,
+            txslot: None,
+            dutycycle: None,
+            lbt: None,
+            adr: None,
+            tpc: None,
+            reliable: false,
+            fec: None,
+            datagram: false,
+            fragtimeout: Duration::from_secs(60),
+            node: None,
+            dest: BROADCAST,
+            tdma: None,
+        }
+    }
+}
 
-    // Lines coming from the radio
-    readerlinesrx: crossbeam_channel::Receiver<String>,
+/// Data received from the other end.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReceivedFrames {
+    /// The data itself.
+    pub payload: Vec<u8>,
+    /// The SNR and RSSI of the received packet, if readqual is true.
+    pub qual: Option<(String, String)>,
+    /// The power, in dBm, that the other end sent it at, if it uses
+    /// transmit power control.
+    pub power: Option<i8>,
+    /// The address of the node that sent it, if it has one.
+    pub sender: Option<u8>,
+}
+
+/// The link over the radio.  Data given to [`transmit`](LoraStik::transmit)
+/// is sent, taking turns with the other end; received data comes out of
+/// the channel returned by [`new`](LoraStik::new).  [`mainloop`](LoraStik::mainloop)
+/// must be running in a thread of its own for anything to happen.
+///
+/// Clones share the same radio and queues.
+#[derive(Clone)]
+pub struct LoraStik<D = Rn2903> {
+    // The driver for the radio hardware
+    radio: D,
 
     // Frames going to the app
     readeroutput: crossbeam_channel::Sender<ReceivedFrames>,
@@ -55,341 +207,252 @@ pub struct LoraStik {
     // Whether or not to read quality data from the radio
     readqual: bool,
 
-    // The wait before transmitting.  Initialized from
-    // [`txwait`].
-    txwait: Duration,
-    
-    // The transmit prevention timeout.  Initialized from
-    // [`eotwait`].
-    eotwait: Duration,
+    // What to send next, and when.
+    turns: Turns,
 
-    // The maximum transmit time.
-    txslot: Option<Duration>,
+    // How the radio was initialized, kept for reinitializing after a reconnect.
+    init: RadioInit,
 
-    // Extra data, to send before the next frame.
-    extradata: Vec<u8>,
+    // The settings in effect on the radio, once it has been initialized.
+    settings: Option<RadioSettings>,
 
-    // Maximum packet size
-    maxpacketsize: usize,
-
-    // Whether or not to always try to cram as much as possible into each TX frame
-    pack: bool,
-
-    // Whether we must delay before transmit.  The Instant
-    // reflects the moment when the delay should end.
-    txdelay: Option<Instant>,
-
-    // When the current TX slot ends, if any.
-    txslotend: Option<Instant>,
-}
+    // A frame heard while listening before talking, to be handled once
+    // the send it interrupted is over.
+    heard: Option<Vec<u8>>,
 
-/// Reads the lines from the radio and sends them down the channel to
-/// the processing bits.
-fn readerlinesthread(mut ser: LoraSer, tx: crossbeam_channel::Sender<String>) {
-    loop {
-        let line = ser.readln().expect("Error reading line");
-        if let Some(l) = line {
-            tx.send(l).unwrap();
-        } else {
-            debug!("{:?}: EOF", ser.portname);
-            return;
-        }
-    }
+    // Dropped once mainloop returns, disconnecting linkdown.
+    linkup: Arc<Mutex<Option<crossbeam_channel::Sender<()>>>>,
+    linkdown: crossbeam_channel::Receiver<()>,
 }
 
-/// Assert that a given response didn't indicate an EOF, and that it
-/// matches the given text.  Return an IOError if either of these
-/// conditions aren't met.  The response type is as given by
-/// ['ser::LoraSer::readln'].
-pub fn assert_response(resp: String, expected: String) -> io::Result<()> {
-    if resp == expected {
-        Ok(())
-    } else {
-        Err(mkerror(&format!("Unexpected response: got {}, expected {}", resp, expected)))
-    }
-}
+/// How long to wait between attempts to reinitialize a lost radio.
+pub(crate) const RECOVERWAIT: Duration = Duration::from_secs(1);
 
-impl LoraStik {
+impl<D: RadioDriver> LoraStik<D> {
     /// Creates a new LoraStik.  Returns an instance to be used for sending,
     /// as well as a separate receiver to be used in a separate thread to handle
-    /// incoming frames.  The bool specifies whether or not to read the quality
-    /// parameters after a read.
-    pub fn new(ser: LoraSer, readqual: bool, txwait: u64, eotwait: u64, maxpacketsize: usize, pack: bool, txslot: u64) -> (LoraStik, crossbeam_channel::Receiver<ReceivedFrames>) {
-        let (readerlinestx, readerlinesrx) = crossbeam_channel::unbounded();
+    /// incoming frames.
+    pub fn new(radio: D, config: LinkConfig) -> (LoraStik<D>, crossbeam_channel::Receiver<ReceivedFrames>) {
         let (txblockstx, txblocksrx) = crossbeam_channel::bounded(2);
         let (readeroutput, readeroutputreader) = crossbeam_channel::unbounded();
-
-        let ser2 = ser.clone();
-        
-        thread::spawn(move || readerlinesthread(ser2, readerlinestx));
-        
-        (LoraStik { readqual, ser, readeroutput, readerlinesrx, txblockstx, txblocksrx, maxpacketsize, pack,
-                    txdelay: None,
-                    txwait: Duration::from_millis(txwait),
-                    eotwait: Duration::from_millis(eotwait),
-                    txslot: if txslot > 0 {
-                        Some(Duration::from_millis(txslot))
-                    } else { None },
-                    txslotend: None,
-                    extradata: vec![]}, readeroutputreader)
+        let (linkup, linkdown) = crossbeam_channel::bounded(0);
+
+        (LoraStik { radio, readeroutput, txblockstx, txblocksrx,
+                    readqual: config.readqual,
+                    turns: Turns::new(&config),
+                    init: RadioInit::Default,
+                    settings: None,
+                    heard: None,
+                    linkup: Arc::new(Mutex::new(Some(linkup))),
+                    linkdown}, readeroutputreader)
     }
 
-    /// Utility to read the response from initialization
-    fn initresp(&mut self) -> io::Result<()> {
-        let line = self.readerlinesrx.recv().unwrap();
-        if line == "invalid_param" {
-            Err(mkerror("Bad response from radio during initialization"))
-        } else {
-            Ok(())
-        }
+    /// Initialize the radio.  The settings are remembered, so the radio
+    /// can be initialized the same way if it has to be reconnected.
+    pub fn radiocfg(&mut self, init: RadioInit) -> Result<RadioSettings> {
+        let settings = self.radio.configure(&init)?;
+        self.turns.tune(&settings);
+        self.init = init;
+        self.settings = Some(settings.clone());
+        Ok(settings)
     }
 
-    pub fn radiocfg(&mut self, initfile: Option<PathBuf>) -> io::Result<()> {
-        // First, send it an invalid command.  Then, consume everything it sends back
-        self.ser.writeln(String::from("INVALIDCOMMAND"))?;
-
-        // Give it a chance to do its thing.
-        thread::sleep(Duration::from_secs(1));
-
-        // Consume all data.
-        while let Ok(_) = self.readerlinesrx.try_recv() {
-        }
-                         
-        debug!("Configuring radio");
-        let default = vec![
-            "sys get ver",
-            "mac reset",
-            "mac pause",
-            "radio get mod",
-            "radio get freq",
-            "radio get pwr",
-            "radio get sf",
-            "radio get bw",
-            "radio get cr",
-            "radio get wdt",
-            "radio set pwr 20",
-            "radio set sf sf12",
-            "radio set bw 125",
-            "radio set cr 4/5",
-            "radio set wdt 60000"];
-
-        let initlines: Vec<String> = if let Some(file) = initfile {
-            let f = fs::File::open(file)?;
-            let reader = BufReader::new(f);
-            reader.lines().map(|l| l.unwrap()).collect()
-        } else {
-            default.iter().map(|l| String::from(*l)).collect()
-        };
+    /// The settings in effect on the radio, once [`radiocfg`](LoraStik::radiocfg)
+    /// has been called.
+    pub fn settings(&self) -> Option<&RadioSettings> {
+        self.settings.as_ref()
+    }
 
-        for line in initlines {
-            if line.len() > 0 {
-                self.ser.writeln(line)?;
-                self.initresp()?;
-            }
+    /// Utililty function to handle actual sending.  Assumes radio is idle.
+    /// A frame heard while listening before talking is handled after.
+    fn dosend(&mut self, data: Vec<u8>) -> Result<()> {
+        let sent = self.sendframe(data);
+        let heard = self.heard.take();
+        sent?;
+        if let Some(frame) = heard {
+            self.handleframe(frame, false)?;
         }
         Ok(())
     }
 
-    /// Utililty function to handle actual sending.  Assumes radio is idle.
-    fn dosend(&mut self, data: Vec<u8>) -> io::Result<()> {
-        let mut tosend = vec![];
-        tosend.append(&mut self.extradata);   // drains self.extradata!
-        tosend.append(&mut data.clone());
-        let mut data = tosend;                // hide the original 'data'
-
-        if data.len() > self.maxpacketsize {
-            self.extradata = data.split_off(self.maxpacketsize);
-        }
-        
-        while data.len() < self.maxpacketsize && self.extradata.is_empty() {
-            // Consider the next packet - maybe we can combine it with this one.
-            let r = self.txblocksrx.try_recv();
-            match r {
-                Ok(mut next) => {
-                    if self.pack {
-                        // Try to fill up the frame.
-                        data.append(&mut next);
-                        if data.len() > self.maxpacketsize {
-                            // Too much; put the extra into extradata.
-                            self.extradata = data.split_off(self.maxpacketsize);
-                            break;  // for clarity only -- would exit the loop anyhow
-                        }
-                    } else {
-                        // Only append the extra if it will fit entirely in the frame.
-                        if data.len() + next.len() <= self.maxpacketsize {
-                            data.append(&mut next);
-                        } else {
-                            self.extradata.append(&mut next);
-                            break;  // for clarity only -- would exit the loop anyhow
-                        }
-                    }
-                },
-                Err(e) => {
-                    if e.is_disconnected() {
-                        // other threads crashed
-                        r.unwrap();
-                    }
-                    // Otherwise - nothing to do
-                    break;
-                }
+    fn sendframe(&mut self, data: Vec<u8>) -> Result<()> {
+        let txblocksrx = &self.txblocksrx;
+        let data = self.turns.fill(data, || {
+            match txblocksrx.try_recv() {
+                Ok(next) => Some(next),
+                // other threads crashed
+                Err(e) if e.is_disconnected() => panic!("{}", e),
+                // Otherwise - nothing to do
+                Err(_) => None,
             }
-        }
-            
-        let mut flag: u8 = 0;
-        
+        });
+
         // Give receiver a change to process.
-        thread::sleep(self.txwait);
-
-        if (!self.txblocksrx.is_empty()) || (!self.extradata.is_empty()) {
-            // If there will be more data to send..
-            flag = 1;
-
-            // See if we need to signal the other end's turn.
-            match (self.txslotend, self.txslot) {
-                (None, Some(txslot)) => self.txslotend = Some(Instant::now() + txslot),
-                (Some(txslotend), _) =>
-                    if Instant::now() > txslotend {
-                        debug!("txslot exceeded; setting txdelay and sending flag 2");
-                        flag = 2;
-                        self.txdelay = Some(Instant::now() + self.eotwait);
-                        self.txslotend = None;
-                    },
-                _ => ()
-            }
-        } else {
-            self.txslotend = None;
+        thread::sleep(self.turns.txwait);
+
+        if !self.listen()? || !self.turns.dutyclear(self.turns.framelen(data.len())) {
+            self.turns.putback(data);
+            return Ok(());
         }
-        
-        // Now, send the mesage.
-        let txstr = format!("radio tx {}{}", hex::encode([flag]), hex::encode(data));
 
-        self.ser.writeln(txstr)?;
+        let turn = self.turns.txturn(!self.txblocksrx.is_empty(), &data);
         
-        // We get two responses from this.... though sometimes a lingering radio_err also.
-        let mut resp = self.readerlinesrx.recv().unwrap();
-        if resp == String::from("radio_err") {
-            resp = self.readerlinesrx.recv().unwrap();
+        // Now, send the mesage.
+        let frame = self.turns.buildframe(turn, &data);
+        if let Err(e) = self.radio.txframe(&frame) {
+            self.turns.putback(data);
+            return Err(e);
         }
-        assert_response(resp, String::from("ok"))?;
-        
-        // Second.
-        self.readerlinesrx.recv().unwrap();  // normally radio_tx_ok
-
         Ok(())
     }
 
-    // Receive a message from the incoming radio channel and process it.
-    fn handlerx(&mut self, msg: String, readqual: bool) -> io::Result<()> {
-        if msg.starts_with("radio_rx ") {
-            if let Ok(mut decoded) = hex::decode(&msg.as_bytes()[10..]) {
-                trace!("DECODED: {}", format_escape_default(&decoded));
-                let radioqual = if readqual {
-                    self.ser.writeln(String::from("radio get snr"))?;
-                    let snr = self.readerlinesrx.recv().unwrap();
-                    self.ser.writeln(String::from("radio get rssi"))?;
-                    let rssi = self.readerlinesrx.recv().unwrap();
-                    Some((snr, rssi))
-                } else {
-                    None
-                };
+    /// Listen before talking, if enabled.  Returns false, having arranged
+    /// to back off, if the channel is busy.  A frame heard is kept for
+    /// [`dosend`](LoraStik::dosend) to handle, as handling it may mean
+    /// sending.
+    fn listen(&mut self) -> Result<bool> {
+        let window = match self.turns.lbtwindow() {
+            Some(window) => window,
+            None => return Ok(true),
+        };
+        self.radio.enterrx()?;
+        thread::sleep(window);
+        let frame = self.radio.rxstop()?;
+        let heard = frame.is_some() || !self.radio.events().is_empty();
+        let rssi = self.radio.rssi()?;
+        self.heard = frame;
+        Ok(self.turns.channelclear(rssi, heard))
+    }
 
-                let flag = decoded.remove(0);  // Remove the flag from the vec
-                if flag == 1 {
-                    // More data is coming
-                    self.txdelay = Some(Instant::now() + self.eotwait);
-                } else {
-                    self.txdelay = None;
-                }
-                debug!("handlerx: txdelay set to {:?}", self.txdelay);
+    /// The counters kept by this link.
+    pub fn stats(&self) -> LinkStats {
+        self.turns.sharedstats().lock().unwrap().clone()
+    }
 
-                self.readeroutput.send(ReceivedFrames(decoded, radioqual)).unwrap();
+    // Receive an event from the radio and process it.
+    fn handlerx(&mut self, event: D::Event, readqual: bool) -> Result<()> {
+        if let Some(decoded) = self.radio.decodeevent(event)? {
+            self.handleframe(decoded, readqual)?;
+        }
+        Ok(())
+    }
 
-                if flag == 2 && self.txslot != None {
-                    // Other end has more data, but it giving us a chance to transmit.
-                    // Need to immediately send something.  dosend() will pick up
-                    // self.extradata or self.txblocksrx to fill up the frame if it can.
-                    self.dosend(vec![])?;
-                }
-            } else {
-                return Err(mkerror("Error with hex decoding"));
-            }
+    // Process a frame received from the radio.  readqual says whether the
+    // quality of the frame can be read.
+    fn handleframe(&mut self, decoded: Vec<u8>, readqual: bool) -> Result<()> {
+        trace!("DECODED: {}", format_escape_default(&decoded));
+        let radioqual = if readqual && (self.readqual || self.turns.wantsqual()) {
+            Some(self.radio.readqual()?)
+        } else {
+            None
+        };
+
+        let Received { payloads, power, sender, ourturn } = match self.turns.rxframe(decoded, radioqual.as_ref().map(|q| q.0.as_str())) {
+            Some(frame) => frame,
+            None => return Ok(()),
+        };
+
+        let radioqual = if self.readqual { radioqual } else { None };
+        for payload in payloads {
+            self.readeroutput.send(ReceivedFrames { payload, qual: radioqual.clone(), power, sender }).unwrap();
         }
 
-        // Might get radio_err here.  That's harmless.
+        if ourturn {
+            // Other end has more data, but it giving us a chance to transmit.
+            // Need to immediately send something.  dosend() will pick up
+            // self.extradata or self.txblocksrx to fill up the frame if it can.
+            // If this frame settled a change of modulation, make it first.
+            self.adapt()?;
+            self.dosend(vec![])?;
+        }
         Ok(())
     }
 
-    // Whether or not a txdelay prevents transmit at this time.  None if
-    // we are cleared to transmit; Some(Duration) gives the amount of time
-    // we'd have to wait otherwise.
-    fn txdelayrequired(&mut self) -> Option<Duration> {
-        debug!("txdelayrequired: self.txdelay = {:?}", self.txdelay);
-        match self.txdelay {
-            None => None,
-            Some(delayend) => {
-                let now = Instant::now();
-                if now >= delayend {
-                    // We're past the delay.  Clear it and return.
-                    debug!("txdelayrequired: {:?} past the required delay {:?}", now, delayend);
-                    self.txdelay = None;
-                    None
-                } else {
-                    // Indicate we're still blocked.
-                    debug!("txdelayrequired: required delay {:?}", delayend - now);
-                    Some(delayend - now)
-                }
-            }
+    // Change the modulation or power, if adaptive data rate or transmit
+    // power control calls for it.  Assumes radio is idle.
+    fn adapt(&mut self) -> Result<()> {
+        if let Some(modulation) = self.turns.adrswitch() {
+            self.radio.setmodulation(&modulation)?;
         }
+        if let Some(power) = self.turns.powerchange() {
+            self.radio.setpower(power)?;
+        }
+        Ok(())
     }
 
-    fn enterrxmode(&mut self) -> io::Result<()> {
+    fn enterrxmode(&mut self) -> Result<()> {
         // Enter read mode
-        
-        self.ser.writeln(String::from("radio rx 0"))?;
-        let mut response = self.readerlinesrx.recv().unwrap();
-        
-        // For some reason, sometimes we get a radio_err here, then an OK.  Ignore it.
-        if response == String::from("radio_err") {
-            response = self.readerlinesrx.recv().unwrap();
+        self.radio.enterrx()
+    }
+
+    fn rxstop(&mut self) -> Result<()> {
+        if let Some(frame) = self.radio.rxstop()? {
+            // We had a race.  A packet was coming in.  Deal with it; we can't
+            // try to read the quality in this scenario.
+            self.handleframe(frame, false)?;
         }
-        assert_response(response, String::from("ok"))?;
         Ok(())
     }
 
-    fn rxstop(&mut self) -> io::Result<()> {
-        self.ser.writeln(String::from("radio rxstop"))?;
-        let checkresp = self.readerlinesrx.recv().unwrap();
-        if checkresp.starts_with("radio_rx ") {
-            // We had a race.  A packet was coming in.  Decode and deal with it,
-            // then look for the 'ok' from rxstop.  We can't try to read the quality in
-            // this scenario.
-            self.handlerx(checkresp, false)?;
-            self.readerlinesrx.recv().unwrap();  // used to pop this into checkresp, but no need now.
+    /// Run the radio.  If the connection to the radio is lost, or the radio
+    /// stops answering, wait for it to come back, reinitialize it, and
+    /// carry on; other errors are returned.
+    pub fn mainloop(&mut self) -> Result<()> {
+        let res = loop {
+            match self.runradio() {
+                Err(e @ Error::Disconnected(_)) | Err(e @ Error::Timeout { .. }) => self.recover(e),
+                res => break res,
+            }
+        };
+        self.linkup.lock().unwrap().take();
+        res
+    }
+
+    /// Reinitialize the radio after the connection to it was lost, or it
+    /// stopped answering.  Data waiting to be transmitted is kept.
+    fn recover(&mut self, e: Error) {
+        warn!("{}; will reinitialize radio when it returns", e);
+        loop {
+            thread::sleep(RECOVERWAIT);
+            match self.radio.configure(&self.init) {
+                Ok(settings) => {
+                    self.settings = Some(settings);
+                    break;
+                },
+                Err(e) => debug!("Radio reinitialization failed: {}", e),
+            }
         }
-        
-        // Now, checkresp should hold 'ok'.
-        //  It might not be; I sometimes see radio_err here.  it's OK too.
-        // assert_response(checkresp, String::from("ok"))?;
-        Ok(())
+        warn!("Radio reinitialized; resuming");
+        self.turns.reset();
     }
-    
-    pub fn mainloop(&mut self) -> io::Result<()> {
+
+    fn runradio(&mut self) -> Result<()> {
         loop {
+            self.adapt()?;
+            for (sender, payload) in self.turns.expired() {
+                self.readeroutput.send(ReceivedFrames { payload, qual: None, power: None, sender }).unwrap();
+            }
+
             // First, check to see if we're allowed to transmit.  If not, just
             // try to read and ignore all else.
-            if let Some(delayamt) = self.txdelayrequired() {
+            if let Some(delayamt) = self.turns.txdelayrequired() {
                 // We can't transmit yet.  Just read, but with a time box.
                 self.enterrxmode()?;
-                let res = self.readerlinesrx.recv_timeout(delayamt);
+                let res = self.radio.events().recv_timeout(delayamt);
                 match res {
                     Ok(msg) => {
-                        self.handlerx(msg, self.readqual)?;
+                        self.handlerx(msg, true)?;
                         continue;
                     },
                     Err(e) => {
                         if e.is_timeout() {
                             debug!("readerthread: txdelay timeout expired");
-                            self.txdelay = None;
+                            self.turns.cleartxdelay();
+                            if self.turns.pending() {
+                                // The select below won't notice this; go send it.
+                                self.rxstop()?;
+                                continue;
+                            }
                             // Now we can fall through to the rest of the logic - already in read mode.
                         } else {
                             res.unwrap(); // disconnected - crash
@@ -402,12 +465,18 @@ impl LoraStik {
                 // Do we have anything to send?  Check at the top and keep checking
                 // here so we send as much as possible before going back into read
                 // mode.
-                if ! self.extradata.is_empty() {
+                if self.turns.pending() {
                     // Send the extradata immediately
                     self.dosend(vec![])?;
                     continue;
                 }
-                let r = self.txblocksrx.try_recv();
+                // With reliable delivery, new data may have to wait for what
+                // was sent to be acknowledged.
+                let r = if self.turns.cansend() {
+                    self.txblocksrx.try_recv()
+                } else {
+                    Err(crossbeam_channel::TryRecvError::Empty)
+                };
                 match r {
                     Ok(data) => {
                         self.dosend(data)?;
@@ -430,15 +499,24 @@ impl LoraStik {
             // Now we wait for either a write request or data.
 
             let mut sel = crossbeam_channel::Select::new();
-            let readeridx = sel.recv(&self.readerlinesrx);
-            let blocksidx = sel.recv(&self.txblocksrx);
-            match sel.ready() {
-                i if i == readeridx => {
+            let readeridx = sel.recv(self.radio.events());
+            let blocksidx = if self.turns.cansend() { sel.recv(&self.txblocksrx) } else { usize::MAX };
+            let ready = match self.turns.deadline() {
+                Some(deadline) => sel.ready_timeout(deadline).ok(),
+                None => Some(sel.ready()),
+            };
+            match ready {
+                None => {
+                    // Time for a keepalive, to check whether to fall back, to
+                    // send a frame again, or to stop holding frames.
+                    self.rxstop()?;
+                },
+                Some(i) if i == readeridx => {
                     // We have data coming in from the radio.
-                    let msg = self.readerlinesrx.recv().unwrap();
-                    self.handlerx(msg, self.readqual)?;
+                    let msg = self.radio.events().recv().unwrap();
+                    self.handlerx(msg, true)?;
                 },
-                i if i == blocksidx => {
+                Some(i) if i == blocksidx => {
                     // We have something to send.  Stop the receiver and then go
                     // back to the top of the loop to handle it.
 
@@ -450,9 +528,113 @@ impl LoraStik {
         }
     }
 
+    /// Queue data for transmission.  Blocks if the queue is full.
     pub fn transmit(&mut self, data: &[u8])  {
         self.txblockstx.send(data.to_vec()).unwrap();
     }
+
+    /// The queue [`transmit`](LoraStik::transmit) puts data on, and a
+    /// channel that is disconnected once [`mainloop`](LoraStik::mainloop)
+    /// has returned.
+    pub(crate) fn channels(&self) -> (crossbeam_channel::Sender<Vec<u8>>, crossbeam_channel::Receiver<()>) {
+        (self.txblockstx.clone(), self.linkdown.clone())
+    }
 }
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::{Header, Turn};
+
+    // A radio that hears the frames in `heard` as it stops receiving, and
+    // keeps the frames it is asked to transmit.
+    #[derive(Clone)]
+    struct Fake {
+        events: crossbeam_channel::Receiver<()>,
+        heard: Arc<Mutex<Vec<Vec<u8>>>>,
+        sent: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl Fake {
+        fn new() -> Fake {
+            Fake { events: crossbeam_channel::never(), heard: Arc::default(), sent: Arc::default() }
+        }
+
+        // The payloads of the frames transmitted.
+        fn payloads(&self) -> Vec<Vec<u8>> {
+            self.sent.lock().unwrap().iter().map(|f| Header::decode(f).unwrap().2.to_vec()).collect()
+        }
+    }
+
+    impl RadioDriver for Fake {
+        type Event = ();
+
+        fn configure(&mut self, _init: &RadioInit) -> Result<RadioSettings> {
+            Err(Error::Unsupported(String::from("fake radio")))
+        }
+
+        fn events(&self) -> &crossbeam_channel::Receiver<()> {
+            &self.events
+        }
+
+        fn decodeevent(&mut self, _event: ()) -> Result<Option<Vec<u8>>> {
+            Ok(None)
+        }
+
+        fn txframe(&mut self, frame: &[u8]) -> Result<()> {
+            self.sent.lock().unwrap().push(frame.to_vec());
+            Ok(())
+        }
+
+        fn enterrx(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn rxstop(&mut self) -> Result<Option<Vec<u8>>> {
+            Ok(self.heard.lock().unwrap().pop())
+        }
+
+        fn readqual(&mut self) -> Result<(String, String)> {
+            Ok((String::from("10"), String::from("-120")))
+        }
+    }
+
+    // A link listening before talking, whose radio hears the other end
+    // yield the turn while listening before the first frame.
+    fn yielding(config: LinkConfig) -> (LoraStik<Fake>, Fake, crossbeam_channel::Receiver<ReceivedFrames>) {
+        let radio = Fake::new();
+        let peer = Header::new(Turn::Yield).encode(config.framing, b"peer");
+        radio.heard.lock().unwrap().push(peer);
+        let config = LinkConfig {
+            txwait: Duration::ZERO,
+            txslot: Some(Duration::from_secs(1)),
+            lbt: Some(LbtConfig { threshold: -90, window: Duration::ZERO, backoff: Some(Duration::ZERO) }),
+            ..config
+        };
+        let (link, rx) = LoraStik::new(radio.clone(), config);
+        (link, radio, rx)
+    }
+
+    #[test]
+    fn yieldduringlbt() {
+        let (mut link, radio, rx) = yielding(LinkConfig::default());
+        link.transmit(b"two");
+        link.dosend(b"one".to_vec()).unwrap();
+        assert_eq!(rx.try_recv().unwrap().payload, b"peer");
+        // The turn given is used for what was held back, in order.
+        assert_eq!(radio.payloads().concat(), b"onetwo");
+    }
+
+    #[test]
+    fn yieldduringlbtreliable() {
+        let config = LinkConfig { framing: Framing::V1, reliable: true, ..LinkConfig::default() };
+        let (mut link, radio, rx) = yielding(config);
+        link.dosend(b"one".to_vec()).unwrap();
+        assert_eq!(rx.try_recv().unwrap().payload, b"peer");
+        assert_eq!(radio.payloads(), vec![b"one".to_vec()]);
+        let frame = radio.sent.lock().unwrap()[0].clone();
+        assert!(Header::decode(&frame).unwrap().0.seq.is_some());
+    }
+}
@@ -19,13 +19,13 @@
 use simplelog::*;
 use std::io;
 use log::*;
+use std::process;
 use std::thread;
+use std::time::Duration;
 
-mod ser;
-mod lorastik;
-mod pipe;
 mod ping;
-mod kiss;
+
+use lorapipe::{kiss, pipe, scan, AdrConfig, FecConfig, Framing, LbtConfig, LinkConfig, LoraSer, LoraStik, Profile, RadioConfig, RadioDriver, RadioInit, Region, Rn2903, TdmaConfig, TpcConfig, BROADCAST};
 
 use std::path::PathBuf;
 use structopt::StructOpt;
@@ -47,17 +47,101 @@ struct Opt {
     pack: bool,
     
     /// Radio initialization command file
-    #[structopt(long, parse(from_os_str))]
+    #[structopt(long, global = true, parse(from_os_str))]
     initfile: Option<PathBuf>,
 
+    /// Radio configuration file (TOML), instead of --initfile
+    #[structopt(long, global = true, parse(from_os_str), conflicts_with = "initfile")]
+    config: Option<PathBuf>,
+
+    /// Regional band plan to configure the radio for: us915, eu868, eu433, au915 or as923
+    #[structopt(long, global = true, conflicts_with_all = &["initfile", "config"])]
+    region: Option<Region>,
+
+    /// Trade speed for range within the region: longrange, balanced or fast
+    #[structopt(long, global = true, requires = "region")]
+    profile: Option<Profile>,
+
     /// Maximum frame size sent to radio [10..250] (valid only for ping and kiss)
     #[structopt(long, default_value = "100")]
     maxpacketsize: usize,
 
+    /// Header to send: legacy, understood by older versions, or v1; both are understood when received
+    #[structopt(long, default_value = "legacy")]
+    framing: Framing,
+
     /// Maximum time to transmit at once before giving a chance to receive (in ms). 0=infinite
     #[structopt(long, default_value = "0")]
     txslot: u64,
 
+    /// Percentage of each hour that may be spent transmitting; 100 for no limit [default: the band's legal limit]
+    #[structopt(long)]
+    dutycycle: Option<f64>,
+
+    /// Listen before talking: hold off transmitting while the RSSI is at or above this (dBm), e.g. -90
+    #[structopt(long, allow_hyphen_values = true)]
+    lbt: Option<i32>,
+
+    /// Time (ms) to listen before sampling the RSSI, with --lbt
+    #[structopt(long, default_value = "50")]
+    lbtwindow: u64,
+
+    /// Longest random backoff (ms) when the channel is busy, with --lbt [default: one frame's airtime]
+    #[structopt(long)]
+    lbtbackoff: Option<u64>,
+
+    /// Adapt the spreading factor, bandwidth and coding rate to the link, by agreement with the other end, which must also use --adr (needs --framing v1)
+    #[structopt(long)]
+    adr: bool,
+
+    /// Time (s) without hearing the other end before --adr falls back to the initial settings
+    #[structopt(long, default_value = "60")]
+    adrtimeout: u64,
+
+    /// Turn the transmit power down as far as the other end's reports allow; it must use --adr or --tpc to send them (needs --framing v1)
+    #[structopt(long)]
+    tpc: bool,
+
+    /// Lowest power (dBm) for --tpc [default: the radio's lowest]
+    #[structopt(long, allow_hyphen_values = true)]
+    tpcmin: Option<i8>,
+
+    /// Highest power (dBm) for --tpc [default: the initial power]
+    #[structopt(long, allow_hyphen_values = true)]
+    tpcmax: Option<i8>,
+
+    /// SNR margin (dB) above what the other end needs to hear us that --tpc aims for
+    #[structopt(long, default_value = "10")]
+    tpcmargin: f32,
+
+    /// Send PARITY frames after every DATA frames, so up to PARITY lost ones can be rebuilt (DATA:PARITY, e.g. 8:2)
+    #[structopt(long)]
+    fec: Option<FecConfig>,
+
+    /// This node's address [0..254]; frames sent carry it, and received frames for other nodes are dropped (needs --framing v1)
+    #[structopt(long)]
+    node: Option<u8>,
+
+    /// The address to send frames to, with --node [default: 255, every node]
+    #[structopt(long, requires = "node")]
+    dest: Option<u8>,
+
+    /// Transmit only in the slot given this node by the TDMA coordinator's beacons, instead of taking turns (needs --node)
+    #[structopt(long, requires = "node", conflicts_with = "adr")]
+    tdma: bool,
+
+    /// Be the TDMA coordinator, beaconing and giving slots to these nodes in order (comma-separated addresses)
+    #[structopt(long, requires = "tdma", require_delimiter = true)]
+    tdmamembers: Vec<u8>,
+
+    /// Length (ms) of each TDMA slot, set by the coordinator [default: room for 4 full frames]
+    #[structopt(long, requires = "tdmamembers")]
+    tdmaslot: Option<u64>,
+
+    /// Time (ms) kept clear at each end of a TDMA slot
+    #[structopt(long, default_value = "200")]
+    tdmaguard: u64,
+
     /// Amount of time (ms) to pause before transmitting a packet
     /* The
     main purpose of this is to give the othe rradio a chance to finish
@@ -68,17 +152,18 @@ struct Opt {
     #[structopt(long, default_value = "120")]
     txwait: u64,
 
-    /// Amount of time (ms) to wait for end-of-transmission signal before transmitting
+    /// Amount of time (ms) to wait for end-of-transmission signal before transmitting [default: from airtime]
     /* The amount of time to wait before transmitting after receiving a
     packet that indicated more data was forthcoming.  The purpose of this is
     to compensate for a situation in which the "last" incoming packet was lost,
     to prevent the receiver from waiting forever for more packets before
-    transmitting.  Given in ms. */
-    #[structopt(long, default_value = "1000")]
-    eotwait: u64,
+    transmitting.  Given in ms.  If not given, it is worked out from the
+    time a full frame takes on the air. */
+    #[structopt(long)]
+    eotwait: Option<u64>,
     
     #[structopt(parse(from_os_str))]
-    /// Serial port to use to communicate with radio
+    /// Serial port to use to communicate with radio, or tcp://HOST:PORT or rfc2217://HOST:PORT
     port: PathBuf,
 
     #[structopt(subcommand)]
@@ -88,34 +173,236 @@ struct Opt {
 #[derive(Debug, StructOpt)]
 enum Command {
     /// Pipe data across raios
-    Pipe,
+    Pipe(PipeOpts),
     /// Transmit ping requests
     Ping,
     /// Receive ping requests and transmit pongs
     Pong,
     /// Pipe KISS data across the radios
     Kiss,
+    /// Report on the radio's settings as JSON
+    Config(ConfigCmd),
+    /// Listen across a range of frequencies and report the activity on each
+    Scan(ScanOpts),
+}
+
+#[derive(Debug, StructOpt)]
+struct PipeOpts {
+    /// Acknowledge frames and send lost ones again, for a lossless, in-order stream (needs --framing v1)
+    #[structopt(long)]
+    reliable: bool,
+
+    /// Send each line from stdin as a message of its own, which the other end gets back whole (needs --framing v1)
+    #[structopt(long)]
+    datagram: bool,
+
+    /// Time (s) to wait for the rest of a fragmented message before dropping it
+    #[structopt(long, default_value = "60")]
+    fragtimeout: u64,
+}
+
+#[derive(Debug, StructOpt)]
+struct ScanOpts {
+    /// First frequency to scan (Hz)
+    #[structopt(long)]
+    from: u32,
+
+    /// Last frequency to scan (Hz)
+    #[structopt(long)]
+    to: u32,
+
+    /// Distance between channels (Hz)
+    #[structopt(long, default_value = "200000")]
+    step: u32,
+
+    /// Time (ms) to listen on each channel
+    #[structopt(long, default_value = "2000")]
+    dwell: u64,
+
+    /// Time (ms) between RSSI samples
+    #[structopt(long, default_value = "250")]
+    interval: u64,
+
+    /// Print CSV instead of a table
+    #[structopt(long)]
+    csv: bool,
+}
+
+#[derive(Debug, StructOpt)]
+enum ConfigCmd {
+    /// Show everything the radio reports about itself
+    Show,
+    /// Show what --initfile or --config would change
+    Diff,
+}
+
+/// Query the radio, without configuring it, and print JSON to stdout.
+fn radioconfig(radio: &mut Rn2903, cmd: &ConfigCmd, init: &RadioInit) -> lorapipe::Result<()> {
+    let state = radio.query()?;
+    let json = match cmd {
+        ConfigCmd::Show => serde_json::to_string_pretty(&state),
+        ConfigCmd::Diff => {
+            let lines = init.lines(state.version()?.module)?;
+            serde_json::to_string_pretty(&state.diff(&lines))
+        },
+    };
+    println!("{}", json.map_err(io::Error::other)?);
+    Ok(())
+}
+
+/// Configure the radio, then scan each channel in turn, printing the
+/// results as they come.
+fn scan(radio: &mut Rn2903, opts: &ScanOpts, init: &RadioInit) -> lorapipe::Result<()> {
+    let settings = radio.configure(init)?;
+    let freqs = scan::channels(settings.version.module, opts.from, opts.to, opts.step)?;
+    let dbm = |v: Option<i32>| v.map(|v| v.to_string()).unwrap_or_default();
+    if opts.csv {
+        println!("freq,noisefloor,peakrssi,samples,packets,errors");
+    } else {
+        println!("{:>11} {:>11} {:>10} {:>7} {:>7} {:>6}", "Freq (MHz)", "Noise (dBm)", "Peak (dBm)", "Samples", "Packets", "Errors");
+    }
+    for freq in freqs {
+        let a = radio.scanchannel(freq, Duration::from_millis(opts.dwell), Duration::from_millis(opts.interval))?;
+        if opts.csv {
+            println!("{},{},{},{},{},{}", a.freq, dbm(a.noisefloor), dbm(a.peakrssi), a.samples, a.packets, a.errors);
+        } else {
+            println!("{:>11.3} {:>11} {:>10} {:>7} {:>7} {:>6}", f64::from(a.freq) / 1_000_000.0,
+                     dbm(a.noisefloor), dbm(a.peakrssi), a.samples, a.packets, a.errors);
+        }
+    }
+    Ok(())
 }
 
 fn main() {
     let opt = Opt::from_args();
 
-    if opt.debug {
-        WriteLogger::init(LevelFilter::Trace, Config::default(), io::stderr()).expect("Failed to init log");
-    }
+    // Warnings, such as a lost radio connection, are always logged.
+    let level = if opt.debug { LevelFilter::Trace } else { LevelFilter::Warn };
+    WriteLogger::init(level, Config::default(), io::stderr()).expect("Failed to init log");
     info!("lora starting");
 
-    let maxpacketsize = opt.maxpacketsize;
+    let reliable = matches!(&opt.cmd, Command::Pipe(p) if p.reliable);
+    let datagram = matches!(&opt.cmd, Command::Pipe(p) if p.datagram);
+    let fragtimeout = match &opt.cmd {
+        Command::Pipe(p) => Duration::from_secs(p.fragtimeout),
+        _ => LinkConfig::default().fragtimeout,
+    };
+    if !(10..=250).contains(&opt.maxpacketsize) {
+        structopt::clap::Error::with_description("--maxpacketsize must be from 10 to 250",
+                                                 structopt::clap::ErrorKind::InvalidValue).exit();
+    }
+    if reliable && opt.framing == Framing::Legacy {
+        structopt::clap::Error::with_description("--reliable needs --framing v1",
+                                                 structopt::clap::ErrorKind::ArgumentConflict).exit();
+    }
+    if datagram && opt.framing == Framing::Legacy {
+        structopt::clap::Error::with_description("--datagram needs --framing v1",
+                                                 structopt::clap::ErrorKind::ArgumentConflict).exit();
+    }
+    if (opt.adr || opt.tpc) && opt.framing == Framing::Legacy {
+        structopt::clap::Error::with_description("--adr and --tpc need --framing v1",
+                                                 structopt::clap::ErrorKind::ArgumentConflict).exit();
+    }
+    if opt.fec.is_some() && (reliable || opt.framing == Framing::Legacy) {
+        structopt::clap::Error::with_description("--fec needs --framing v1, and can't be used with --reliable",
+                                                 structopt::clap::ErrorKind::ArgumentConflict).exit();
+    }
+
+    if opt.node.is_some() && opt.framing == Framing::Legacy {
+        structopt::clap::Error::with_description("--node needs --framing v1",
+                                                 structopt::clap::ErrorKind::ArgumentConflict).exit();
+    }
+    if opt.node == Some(BROADCAST) {
+        structopt::clap::Error::with_description("--node must be less than 255, the broadcast address",
+                                                 structopt::clap::ErrorKind::InvalidValue).exit();
+    }
+    if opt.node.is_some() && opt.dest.unwrap_or(BROADCAST) == BROADCAST && (reliable || opt.adr || opt.tpc) {
+        structopt::clap::Error::with_description("--reliable, --adr and --tpc with --node need a --dest other than broadcast",
+                                                 structopt::clap::ErrorKind::ArgumentConflict).exit();
+    }
+
+    if opt.tdmamembers.iter().any(|m| Some(*m) == opt.node || *m == BROADCAST) {
+        structopt::clap::Error::with_description("--tdmamembers can't include this node or the broadcast address",
+                                                 structopt::clap::ErrorKind::InvalidValue).exit();
+    }
+
+    let config = LinkConfig {
+        readqual: opt.readqual,
+        txwait: Duration::from_millis(opt.txwait),
+        eotwait: opt.eotwait.map(Duration::from_millis),
+        maxpacketsize: opt.maxpacketsize,
+        framing: opt.framing,
+        pack: opt.pack,
+        txslot: if opt.txslot > 0 {
+            Some(Duration::from_millis(opt.txslot))
+        } else { None },
+        dutycycle: opt.dutycycle.map(|percent| percent / 100.0),
+        lbt: opt.lbt.map(|threshold| LbtConfig {
+            threshold,
+            window: Duration::from_millis(opt.lbtwindow),
+            backoff: opt.lbtbackoff.map(Duration::from_millis),
+        }),
+        adr: if opt.adr {
+            Some(AdrConfig { timeout: Duration::from_secs(opt.adrtimeout) })
+        } else { None },
+        tpc: if opt.tpc {
+            Some(TpcConfig { min: opt.tpcmin, max: opt.tpcmax, margin: opt.tpcmargin })
+        } else { None },
+        reliable,
+        fec: opt.fec,
+        datagram,
+        fragtimeout,
+        node: opt.node,
+        dest: opt.dest.unwrap_or(BROADCAST),
+        tdma: if opt.tdma {
+            Some(TdmaConfig {
+                members: opt.tdmamembers.clone(),
+                slot: opt.tdmaslot.map(Duration::from_millis),
+                guard: Duration::from_millis(opt.tdmaguard),
+            })
+        } else { None },
+    };
     
-    let loraser = ser::LoraSer::new(opt.port).expect("Failed to initialize serial port");
-    let (mut ls, radioreceiver) = lorastik::LoraStik::new(loraser, opt.readqual, opt.txwait, opt.eotwait, maxpacketsize, opt.pack, opt.txslot);
-    ls.radiocfg(opt.initfile).expect("Failed to configure radio");
+    let init = match (opt.initfile, opt.config, opt.region) {
+        (Some(initfile), _, _) => RadioInit::File(initfile),
+        (None, Some(config), _) => RadioInit::Config(RadioConfig::load(&config).expect("Failed to load radio configuration")),
+        (None, None, Some(region)) =>
+            RadioInit::Config(region.config(opt.profile.unwrap_or_default()).expect("Failed to configure region")),
+        (None, None, None) => RadioInit::Default,
+    };
+
+    let loraser = LoraSer::new(opt.port).expect("Failed to initialize serial port");
+    let mut radio = Rn2903::new(loraser);
+    match &opt.cmd {
+        Command::Config(cmd) => {
+            radioconfig(&mut radio, cmd, &init).expect("Failed to query radio");
+            return;
+        },
+        Command::Scan(opts) => {
+            scan(&mut radio, opts, &init).expect("Failed to scan");
+            return;
+        },
+        _ => (),
+    }
+    let (mut ls, radioreceiver) = LoraStik::new(radio, config);
+    let settings = ls.radiocfg(init).expect("Failed to configure radio");
+    eprintln!("Radio: {}", settings);
 
     let mut ls2 = ls.clone();
-    thread::spawn(move || ls2.mainloop().expect("Failure in readerthread"));
+    thread::spawn(move || {
+        // The other threads may be left waiting forever on the link.
+        if let Err(e) = ls2.mainloop() {
+            error!("Failure in readerthread: {}", e);
+            process::exit(1);
+        }
+    });
 
     match opt.cmd {
-        Command::Pipe => {
+        Command::Pipe(PipeOpts { datagram: true, .. }) => {
+            thread::spawn(move || pipe::stdintoloralines(&mut ls).expect("Failure in stdintoloralines"));
+            pipe::loratostdout(radioreceiver).expect("Failure in loratostdout");
+        },
+        Command::Pipe(_) => {
             thread::spawn(move || pipe::stdintolora(&mut ls).expect("Failure in stdintolora"));
             pipe::loratostdout(radioreceiver).expect("Failure in loratostdout");
         },
@@ -129,7 +416,8 @@ fn main() {
         },
         Command::Pong => {
             ping::pong(&mut ls, radioreceiver).expect("Failure in loratostdout");
-        }
+        },
+        Command::Config(_) | Command::Scan(_) => unreachable!(),
     }
 
 }
@@ -0,0 +1,199 @@
+/*
+    Copyright (C) 2019  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+*/
+
+use std::io;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use log::*;
+
+// Telnet protocol bytes.  Spec: RFC 854 and RFC 2217.
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+const OPT_BINARY: u8 = 0;
+const OPT_SGA: u8 = 3;
+const OPT_COMPORT: u8 = 44;
+
+// COM-PORT-OPTION subcommands, client to server.
+const SET_BAUDRATE: u8 = 1;
+const SET_DATASIZE: u8 = 2;
+const SET_PARITY: u8 = 3;
+const SET_STOPSIZE: u8 = 4;
+const SET_CONTROL: u8 = 5;
+
+/// Options we ask for ourselves; the server's replies about these are
+/// acknowledgements, not requests, and must not be answered.
+const WANTED: [u8; 3] = [OPT_BINARY, OPT_SGA, OPT_COMPORT];
+
+/// Connect to a raw TCP serial server such as ser2net in raw mode.
+pub fn opentcp(hostport: &str) -> io::Result<(Box<dyn Read + Send>, Box<dyn Write + Send>)> {
+    debug!("Connecting to raw TCP port {}", hostport);
+    let stream = TcpStream::connect(hostport)?;
+    stream.set_nodelay(true)?;
+    let writer = stream.try_clone()?;
+    Ok((Box::new(stream), Box::new(writer)))
+}
+
+/// Connect to an RFC 2217 server, and ask it to set the serial port to
+/// the given baud rate, 8N1, no flow control.
+pub fn openrfc2217(hostport: &str, baud: u32) -> io::Result<(Box<dyn Read + Send>, Box<dyn Write + Send>)> {
+    debug!("Connecting to RFC 2217 port {}", hostport);
+    let mut stream = TcpStream::connect(hostport)?;
+    stream.set_nodelay(true)?;
+
+    let mut neg = vec![];
+    for opt in WANTED.iter() {
+        neg.extend_from_slice(&[IAC, WILL, *opt, IAC, DO, *opt]);
+    }
+    let mut baudbytes = vec![];
+    for b in baud.to_be_bytes().iter() {
+        // Inside a subnegotiation, IAC must be doubled too.
+        baudbytes.push(*b);
+        if *b == IAC {
+            baudbytes.push(IAC);
+        }
+    }
+    subneg(&mut neg, SET_BAUDRATE, &baudbytes);
+    subneg(&mut neg, SET_DATASIZE, &[8]);
+    subneg(&mut neg, SET_PARITY, &[1]);    // NONE
+    subneg(&mut neg, SET_STOPSIZE, &[1]);  // 1 stop bit
+    subneg(&mut neg, SET_CONTROL, &[1]);   // no flow control
+    stream.write_all(&neg)?;
+    stream.flush()?;
+
+    let reader = TelnetReader { inner: stream.try_clone()?, replies: stream.try_clone()?,
+                                state: TelnetState::Data, buf: vec![0u8; 1024] };
+    Ok((Box::new(reader), Box::new(TelnetWriter { inner: stream })))
+}
+
+fn subneg(out: &mut Vec<u8>, cmd: u8, value: &[u8]) {
+    out.extend_from_slice(&[IAC, SB, OPT_COMPORT, cmd]);
+    out.extend_from_slice(value);
+    out.extend_from_slice(&[IAC, SE]);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TelnetState {
+    Data,
+    Iac,
+    /// Got IAC and one of DO/DONT/WILL/WONT; waiting for the option byte.
+    Negotiate(u8),
+    Sub,
+    SubIac,
+}
+
+/// Strips Telnet commands out of the incoming stream, leaving only the
+/// serial data.  Requests from the server for options we didn't ask for
+/// are refused.
+struct TelnetReader {
+    inner: TcpStream,
+    replies: TcpStream,
+    state: TelnetState,
+    buf: Vec<u8>,
+}
+
+impl TelnetReader {
+    fn negotiate(&mut self, cmd: u8, opt: u8) -> io::Result<()> {
+        trace!("RFC 2217: received command {} for option {}", cmd, opt);
+        if WANTED.contains(&opt) {
+            return Ok(());
+        }
+        match cmd {
+            DO => self.replies.write_all(&[IAC, WONT, opt]),
+            WILL => self.replies.write_all(&[IAC, DONT, opt]),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Read for TelnetReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let want = self.buf.len().min(out.len());
+            let size = self.inner.read(&mut self.buf[..want])?;
+            if size == 0 {
+                return Ok(0);
+            }
+
+            let mut count = 0;
+            for i in 0..size {
+                let b = self.buf[i];
+                self.state = match (self.state, b) {
+                    (TelnetState::Data, IAC) => TelnetState::Iac,
+                    (TelnetState::Data, _) => {
+                        out[count] = b;
+                        count += 1;
+                        TelnetState::Data
+                    },
+                    (TelnetState::Iac, IAC) => {
+                        out[count] = IAC;
+                        count += 1;
+                        TelnetState::Data
+                    },
+                    (TelnetState::Iac, SB) => TelnetState::Sub,
+                    (TelnetState::Iac, DO) | (TelnetState::Iac, DONT) |
+                    (TelnetState::Iac, WILL) | (TelnetState::Iac, WONT) => TelnetState::Negotiate(b),
+                    (TelnetState::Iac, _) => TelnetState::Data,
+                    (TelnetState::Negotiate(cmd), _) => {
+                        self.negotiate(cmd, b)?;
+                        TelnetState::Data
+                    },
+                    // Subnegotiation replies from the server just confirm our settings.
+                    (TelnetState::Sub, IAC) => TelnetState::SubIac,
+                    (TelnetState::Sub, _) => TelnetState::Sub,
+                    (TelnetState::SubIac, SE) => TelnetState::Data,
+                    (TelnetState::SubIac, _) => TelnetState::Sub,
+                }
+            }
+
+            // Returning 0 would look like EOF, so keep going if all we got
+            // was Telnet traffic.
+            if count > 0 {
+                return Ok(count);
+            }
+        }
+    }
+}
+
+/// Escapes IAC bytes in outgoing serial data.
+struct TelnetWriter {
+    inner: TcpStream,
+}
+
+impl Write for TelnetWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut escaped = Vec::with_capacity(buf.len());
+        for b in buf {
+            escaped.push(*b);
+            if *b == IAC {
+                escaped.push(IAC);
+            }
+        }
+        self.inner.write_all(&escaped)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -16,8 +16,7 @@
 */
 
 use std::io;
-use crate::lorastik::{LoraStik, ReceivedFrames};
-use crossbeam_channel;
+use lorapipe::{LoraStik, ReceivedFrames};
 use std::thread;
 use std::time::Duration;
 
@@ -28,7 +27,7 @@ pub fn genpings(ls: &mut LoraStik) -> io::Result<()> {
     loop {
         let sendstr = format!("Ping {}", counter);
         println!("SEND: {}", sendstr);
-        ls.transmit(&sendstr.as_bytes());
+        ls.transmit(sendstr.as_bytes());
         thread::sleep(Duration::from_secs(INTERVAL));
         counter += 1;
     }
@@ -38,7 +37,7 @@ pub fn genpings(ls: &mut LoraStik) -> io::Result<()> {
 pub fn pong(ls: &mut LoraStik, receiver: crossbeam_channel::Receiver<ReceivedFrames>) -> io::Result<()> {
     loop {
         let data = receiver.recv().unwrap();
-        let resp = format!("Pong {}, {:?}", String::from_utf8_lossy(&data.0), data.1);
+        let resp = format!("Pong {}, {:?}", String::from_utf8_lossy(&data.payload), data.qual);
         println!("SEND: {}", resp);
         ls.transmit(resp.as_bytes());
     }
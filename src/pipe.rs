@@ -16,14 +16,18 @@
 */
 
 use std::io;
-use std::io::{Read, Write};
+use std::io::{BufRead, Read, Write};
 use crate::lorastik::{LoraStik, ReceivedFrames};
-use crossbeam_channel;
+use crate::radio::RadioDriver;
 
 /// A thread for stdin processing
-pub fn stdintolora(ls: &mut LoraStik) -> io::Result<()> {
-    let stdin = io::stdin();
-    let mut br = io::BufReader::new(stdin);
+pub fn stdintolora<D: RadioDriver>(ls: &mut LoraStik<D>) -> io::Result<()> {
+    readertolora(io::stdin(), ls)
+}
+
+/// Transmit everything read from `input` until EOF.
+pub fn readertolora<R: Read, D: RadioDriver>(input: R, ls: &mut LoraStik<D>) -> io::Result<()> {
+    let mut br = io::BufReader::new(input);
 
     let mut buf = vec![0u8; 1024];
 
@@ -38,13 +42,35 @@ pub fn stdintolora(ls: &mut LoraStik) -> io::Result<()> {
     }
 }
 
+/// A thread for stdin processing, sending each line as a message.
+pub fn stdintoloralines<D: RadioDriver>(ls: &mut LoraStik<D>) -> io::Result<()> {
+    readertoloralines(io::stdin().lock(), ls)
+}
+
+/// Transmit each line read from `input`, with its newline, until EOF.
+/// A last line without one is sent as it is.
+pub fn readertoloralines<R: BufRead, D: RadioDriver>(mut input: R, ls: &mut LoraStik<D>) -> io::Result<()> {
+    let mut line = vec![];
+    loop {
+        line.clear();
+        if input.read_until(b'\n', &mut line)? == 0 {
+            // EOF
+            return Ok(());
+        }
+        ls.transmit(&line);
+    }
+}
+
 pub fn loratostdout(receiver: crossbeam_channel::Receiver<ReceivedFrames>) -> io::Result<()> {
-    let mut stdout = io::stdout();
+    loratowriter(receiver, io::stdout())
+}
 
+/// Write the data from each received frame to `output`.
+pub fn loratowriter<W: Write>(receiver: crossbeam_channel::Receiver<ReceivedFrames>, mut output: W) -> io::Result<()> {
     loop {
         let data = receiver.recv().unwrap();
-        stdout.write_all(&data.0)?;
-        stdout.flush()?;
+        output.write_all(&data.payload)?;
+        output.flush()?;
     }
 }
 
@@ -0,0 +1,80 @@
+/*
+    Copyright (C) 2019  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+*/
+
+use crate::adr::Modulation;
+use crate::error::{Error, Result};
+use crate::radioconfig::{RadioInit, RadioSettings};
+
+/// The operations that [`crate::lorastik::LoraStik`] needs from a radio.
+///
+/// LoraStik handles the turn-taking, packing, and txslot logic; a driver
+/// only needs to know how to speak to one particular kind of radio module.
+/// All methods other than `events` assume they are called from the thread
+/// running the main loop, and that the radio is not otherwise in use.
+pub trait RadioDriver {
+    /// Something that the radio sent us without being asked -- for instance,
+    /// a received packet.
+    type Event: Send;
+
+    /// Initialize the radio, with the commands from `init`.  Returns the
+    /// settings in effect afterwards, having checked that they are what
+    /// was asked for.
+    fn configure(&mut self, init: &RadioInit) -> Result<RadioSettings>;
+
+    /// The channel on which unsolicited events from the radio arrive.
+    /// This is waited on while the radio is in receive mode.
+    fn events(&self) -> &crossbeam_channel::Receiver<Self::Event>;
+
+    /// Interpret an event from [`events`](RadioDriver::events).  Returns
+    /// the frame if the event was a received packet, or None if it was
+    /// something else that the caller can ignore.
+    fn decodeevent(&mut self, event: Self::Event) -> Result<Option<Vec<u8>>>;
+
+    /// Transmit one frame, returning once it has been sent.  The radio must
+    /// be idle (not in receive mode).
+    fn txframe(&mut self, frame: &[u8]) -> Result<()>;
+
+    /// Put the radio into continuous receive mode.
+    fn enterrx(&mut self) -> Result<()>;
+
+    /// Take the radio out of receive mode.  If a packet arrived while we
+    /// were trying to stop, it is returned.
+    fn rxstop(&mut self) -> Result<Option<Vec<u8>>>;
+
+    /// Read the SNR and RSSI of the most recently received packet.
+    fn readqual(&mut self) -> Result<(String, String)>;
+
+    /// The RSSI in dBm, as sampled for listen-before-talk.  The radio must
+    /// be idle.
+    fn rssi(&mut self) -> Result<i32> {
+        let (_, rssi) = self.readqual()?;
+        rssi.parse().map_err(|_| Error::UnexpectedReply { command: String::from("rssi"), reply: rssi })
+    }
+
+    /// Change the spreading factor, bandwidth, and coding rate, for
+    /// adaptive data rate.  The radio must be idle.
+    fn setmodulation(&mut self, modulation: &Modulation) -> Result<()> {
+        Err(Error::Unsupported(format!("this radio can't be switched to {}", modulation)))
+    }
+
+    /// Change the transmit power, in dBm, for transmit power control.  The
+    /// radio must be idle.
+    fn setpower(&mut self, power: i8) -> Result<()> {
+        Err(Error::Unsupported(format!("this radio can't be switched to {} dBm", power)))
+    }
+}
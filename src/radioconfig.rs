@@ -0,0 +1,582 @@
+/*
+    Copyright (C) 2019  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+*/
+
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+/// Which radio module is being configured.  They speak the same commands,
+/// but on different bands.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Module {
+    /// The RN2903, for 902-928 MHz, as in the LoStik.
+    #[default]
+    Rn2903,
+    /// The RN2483, for 433 and 868 MHz.
+    Rn2483,
+}
+
+impl Module {
+    /// The legal frequency ranges, in Hz.
+    pub fn freqranges(self) -> &'static [(u32, u32)] {
+        match self {
+            Module::Rn2903 => &[(902_000_000, 928_000_000)],
+            Module::Rn2483 => &[(433_050_000, 434_790_000), (863_000_000, 870_000_000)],
+        }
+    }
+
+    /// The legal transmit power settings.
+    pub fn pwrrange(self) -> (i8, i8) {
+        match self {
+            Module::Rn2903 => (2, 20),
+            Module::Rn2483 => (-3, 15),
+        }
+    }
+
+    /// The transmit power used if none is given.
+    pub fn defaultpwr(self) -> i8 {
+        match self {
+            Module::Rn2903 => 20,
+            Module::Rn2483 => 14,
+        }
+    }
+}
+
+impl fmt::Display for Module {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Module::Rn2903 => write!(f, "RN2903"),
+            Module::Rn2483 => write!(f, "RN2483"),
+        }
+    }
+}
+
+/// The oldest firmware lorapipe works with.  Earlier versions lack
+/// `radio rxstop`.
+pub const MINFIRMWARE: (u32, u32, u32) = (1, 0, 5);
+
+/// What the radio says about itself in answer to `sys get ver`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Version {
+    pub module: Module,
+    /// Major, minor, and patch
+    pub firmware: (u32, u32, u32),
+    /// The whole answer, including the build date
+    pub raw: String,
+}
+
+impl Version {
+    /// Parse an answer such as `RN2903 1.0.5 Nov 06 2018 10:45:27`.
+    pub fn parse(ver: &str) -> Result<Version> {
+        let bad = || Error::Unsupported(format!("can't make sense of version {:?}", ver));
+        let mut words = ver.split_whitespace();
+        let module = match words.next() {
+            Some("RN2903") => Module::Rn2903,
+            Some("RN2483") => Module::Rn2483,
+            _ => return Err(bad()),
+        };
+        let nums = words.next().ok_or_else(bad)?
+            .split('.')
+            .map(|n| n.parse::<u32>())
+            .collect::<std::result::Result<Vec<u32>, _>>()
+            .map_err(|_| bad())?;
+        match nums.as_slice() {
+            [major, minor, patch] =>
+                Ok(Version { module, firmware: (*major, *minor, *patch), raw: String::from(ver) }),
+            _ => Err(bad()),
+        }
+    }
+
+    /// Fail unless the firmware is new enough.
+    pub fn check(&self) -> Result<()> {
+        if self.firmware < MINFIRMWARE {
+            Err(Error::Unsupported(format!(
+                "{} firmware {} is older than {}.{}.{}, which lorapipe needs for radio rxstop; please upgrade it",
+                self.module, self.firmwarestr(), MINFIRMWARE.0, MINFIRMWARE.1, MINFIRMWARE.2)))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The firmware version, as in `1.0.5`.
+    pub fn firmwarestr(&self) -> String {
+        format!("{}.{}.{}", self.firmware.0, self.firmware.1, self.firmware.2)
+    }
+}
+
+/// Radio settings, as read from a TOML file.  Settings left out take the
+/// values lorapipe has always used; `freq`, `sync`, `prlen`, `crc` and
+/// `iqi` are left as the radio has them unless given.  If `module` is
+/// left out, it is taken from the radio.
+///
+/// ```toml
+/// module = "rn2903"
+/// freq = 915000000
+/// sf = 9
+/// bw = 250
+/// cr = "4/6"
+/// pwr = 14
+/// sync = 0x34
+/// ```
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RadioConfig {
+    pub module: Option<Module>,
+    /// Frequency in Hz
+    pub freq: Option<u32>,
+    /// Spreading factor, 7 to 12
+    pub sf: u8,
+    /// Bandwidth in kHz: 125, 250 or 500
+    pub bw: u16,
+    /// Coding rate, "4/5" to "4/8"
+    pub cr: String,
+    /// Transmit power; the module's default if not given
+    pub pwr: Option<i8>,
+    /// Sync word, one byte
+    pub sync: Option<u8>,
+    /// Preamble length
+    pub prlen: Option<u16>,
+    /// Whether packets carry a CRC
+    pub crc: Option<bool>,
+    /// Whether IQ is inverted
+    pub iqi: Option<bool>,
+    /// Receive watchdog in ms; 0 disables it
+    pub wdt: u32,
+}
+
+impl Default for RadioConfig {
+    fn default() -> RadioConfig {
+        RadioConfig {
+            module: None,
+            freq: None,
+            sf: 12,
+            bw: 125,
+            cr: String::from("4/5"),
+            pwr: None,
+            sync: None,
+            prlen: None,
+            crc: None,
+            iqi: None,
+            wdt: 60000,
+        }
+    }
+}
+
+fn onoff(b: bool) -> &'static str {
+    if b { "on" } else { "off" }
+}
+
+impl RadioConfig {
+    /// Read a config file, and check that it is legal for its module, as
+    /// far as that can be done before knowing what the radio is.
+    pub fn load(path: &Path) -> Result<RadioConfig> {
+        let text = fs::read_to_string(path)?;
+        let config: RadioConfig = toml::from_str(&text)
+            .map_err(|e| Error::Config(format!("{:?}: {}", path, e)))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check every setting against what the configured module allows,
+    /// reporting all the problems at once.  Frequency and power can't be
+    /// checked if no module is given.
+    pub fn validate(&self) -> Result<()> {
+        self.checkproblems(self.module)
+    }
+
+    /// Check every setting against what `module` allows.  It is an error
+    /// for the configuration to be meant for a different module.
+    pub fn validatefor(&self, module: Module) -> Result<()> {
+        match self.module {
+            Some(m) if m != module =>
+                Err(Error::Config(format!("configuration is for the {}, but the radio is an {}", m, module))),
+            _ => self.checkproblems(Some(module)),
+        }
+    }
+
+    fn checkproblems(&self, module: Option<Module>) -> Result<()> {
+        let mut problems = vec![];
+        if let (Some(freq), Some(module)) = (self.freq, module) {
+            let ranges = module.freqranges();
+            if !ranges.iter().any(|(lo, hi)| (*lo..=*hi).contains(&freq)) {
+                let legal: Vec<String> = ranges.iter()
+                    .map(|(lo, hi)| format!("{}-{}", lo, hi))
+                    .collect();
+                problems.push(format!("freq {} is outside the {}'s bands ({} Hz)",
+                                      freq, module, legal.join(", ")));
+            }
+        }
+        if !(7..=12).contains(&self.sf) {
+            problems.push(format!("sf {} is not between 7 and 12", self.sf));
+        }
+        if ![125, 250, 500].contains(&self.bw) {
+            problems.push(format!("bw {} is not 125, 250 or 500", self.bw));
+        }
+        if !["4/5", "4/6", "4/7", "4/8"].contains(&self.cr.as_str()) {
+            problems.push(format!("cr {:?} is not 4/5, 4/6, 4/7 or 4/8", self.cr));
+        }
+        if let (Some(pwr), Some(module)) = (self.pwr, module) {
+            let (lo, hi) = module.pwrrange();
+            if !(lo..=hi).contains(&pwr) {
+                problems.push(format!("pwr {} is not between {} and {} for the {}",
+                                      pwr, lo, hi, module));
+            }
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Config(problems.join("; ")))
+        }
+    }
+
+    /// The reset command for the module's band.
+    fn resetcmd(&self, module: Module) -> &'static str {
+        match (module, self.freq) {
+            (Module::Rn2903, _) => "mac reset",
+            (Module::Rn2483, Some(freq)) if freq < 600_000_000 => "mac reset 433",
+            (Module::Rn2483, _) => "mac reset 868",
+        }
+    }
+
+    /// The commands that put this configuration into effect on `module`.
+    /// The `get` commands show the settings from before, with `--debug`.
+    pub fn commands(&self, module: Module) -> Vec<String> {
+        let mut cmds: Vec<String> = vec![self.resetcmd(module), "mac pause",
+                                         "radio get mod", "radio get freq", "radio get pwr",
+                                         "radio get sf", "radio get bw", "radio get cr",
+                                         "radio get wdt"]
+            .into_iter().map(String::from).collect();
+        if let Some(freq) = self.freq {
+            cmds.push(format!("radio set freq {}", freq));
+        }
+        cmds.push(format!("radio set pwr {}", self.pwr.unwrap_or_else(|| module.defaultpwr())));
+        cmds.push(format!("radio set sf sf{}", self.sf));
+        cmds.push(format!("radio set bw {}", self.bw));
+        cmds.push(format!("radio set cr {}", self.cr));
+        if let Some(sync) = self.sync {
+            cmds.push(format!("radio set sync {:02x}", sync));
+        }
+        if let Some(prlen) = self.prlen {
+            cmds.push(format!("radio set prlen {}", prlen));
+        }
+        if let Some(crc) = self.crc {
+            cmds.push(format!("radio set crc {}", onoff(crc)));
+        }
+        if let Some(iqi) = self.iqi {
+            cmds.push(format!("radio set iqi {}", onoff(iqi)));
+        }
+        cmds.push(format!("radio set wdt {}", self.wdt));
+        cmds
+    }
+}
+
+/// How to initialize the radio.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum RadioInit {
+    /// lorapipe's default settings.
+    #[default]
+    Default,
+    /// The lines of an init file, sent as they are.
+    File(PathBuf),
+    /// A configuration, usually from [`RadioConfig::load`].
+    Config(RadioConfig),
+}
+
+impl RadioInit {
+    /// The commands to send to `module`.  Empty lines are left out.  A
+    /// bare `mac reset` in an init file is sent as the module needs it,
+    /// for the band of the file's `radio set freq`, if any.
+    pub fn lines(&self, module: Module) -> Result<Vec<String>> {
+        let lines: Vec<String> = match self {
+            RadioInit::Default => RadioConfig::default().commands(module),
+            RadioInit::File(file) => {
+                let f = fs::File::open(file)?;
+                let reader = BufReader::new(f);
+                let lines: Vec<String> = reader.lines().collect::<io::Result<_>>()?;
+                let freq = lines.iter()
+                    .filter_map(|l| l.trim().strip_prefix("radio set freq "))
+                    .find_map(|f| f.trim().parse().ok());
+                let reset = RadioConfig { freq, ..RadioConfig::default() }.resetcmd(module);
+                lines.into_iter()
+                    .map(|l| if l.trim() == "mac reset" { String::from(reset) } else { l })
+                    .collect()
+            },
+            RadioInit::Config(config) => {
+                config.validatefor(module)?;
+                config.commands(module)
+            },
+        };
+        Ok(lines.into_iter().filter(|l| !l.is_empty()).collect())
+    }
+}
+
+/// The radio parameters read back after initialization.
+pub const READBACK: [&str; 10] = ["freq", "pwr", "sf", "bw", "cr", "wdt", "sync", "prlen", "crc", "iqi"];
+
+/// The settings actually in effect on the radio, as read back from it
+/// after initialization.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RadioSettings {
+    pub version: Version,
+    /// Frequency in Hz
+    pub freq: u32,
+    pub pwr: i8,
+    pub sf: u8,
+    /// Bandwidth in kHz
+    pub bw: u16,
+    pub cr: String,
+    /// Receive watchdog in ms
+    pub wdt: u32,
+    /// Sync word, in hex as the radio gives it
+    pub sync: String,
+    pub prlen: u16,
+    pub crc: bool,
+    pub iqi: bool,
+}
+
+/// Compare a requested setting of `param` with what the radio says, as
+/// the radio says it: numbers may come back formatted differently, and
+/// words in a different case.  The sync word is in hex both ways.
+fn samesetting(param: &str, requested: &str, actual: &str) -> bool {
+    let parse = |v: &str| if param == "sync" { i64::from_str_radix(v, 16) } else { v.parse::<i64>() };
+    match (parse(requested), parse(actual)) {
+        (Ok(r), Ok(a)) => r == a,
+        _ => requested.eq_ignore_ascii_case(actual),
+    }
+}
+
+impl RadioSettings {
+    /// Build the settings from the answers to `radio get` for each of
+    /// [`READBACK`], checking them against the last `radio set` for each
+    /// in `lines`.
+    pub fn verify(version: Version, lines: &[String], values: &[(&str, String)]) -> Result<RadioSettings> {
+        for (param, actual) in values {
+            let prefix = format!("radio set {} ", param);
+            let requested = lines.iter().rev()
+                .find_map(|l| l.strip_prefix(prefix.as_str()));
+            if let Some(requested) = requested {
+                if !samesetting(param, requested.trim(), actual) {
+                    return Err(Error::SettingMismatch { param: String::from(*param),
+                                                        requested: String::from(requested.trim()),
+                                                        actual: actual.clone() });
+                }
+            }
+        }
+
+        let get = |param: &str| -> Result<&str> {
+            values.iter()
+                .find(|(p, _)| *p == param)
+                .map(|(_, v)| v.as_str())
+                .ok_or_else(|| Error::Config(format!("{} was not read back", param)))
+        };
+        let num = |param: &str, value: &str| -> Result<i64> {
+            value.parse().map_err(|_| Error::UnexpectedReply { command: format!("radio get {}", param),
+                                                               reply: String::from(value) })
+        };
+        let getnum = |param: &str| -> Result<i64> { num(param, get(param)?) };
+        let getbool = |param: &str| -> Result<bool> {
+            match get(param)? {
+                "on" => Ok(true),
+                "off" => Ok(false),
+                v => Err(Error::UnexpectedReply { command: format!("radio get {}", param), reply: String::from(v) }),
+            }
+        };
+        let sf = get("sf")?;
+
+        Ok(RadioSettings {
+            version,
+            freq: getnum("freq")? as u32,
+            pwr: getnum("pwr")? as i8,
+            sf: num("sf", sf.trim_start_matches("sf"))? as u8,
+            bw: getnum("bw")? as u16,
+            cr: String::from(get("cr")?),
+            wdt: getnum("wdt")? as u32,
+            sync: String::from(get("sync")?),
+            prlen: getnum("prlen")? as u16,
+            crc: getbool("crc")?,
+            iqi: getbool("iqi")?,
+        })
+    }
+}
+
+impl fmt::Display for RadioSettings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} firmware {}: {:.3} MHz, {} dBm, SF{}, BW {} kHz, CR {}, sync {}, preamble {}, CRC {}, IQ {}, watchdog {} ms",
+               self.version.module, self.version.firmwarestr(),
+               self.freq as f64 / 1_000_000.0, self.pwr, self.sf, self.bw, self.cr,
+               self.sync, self.prlen, onoff(self.crc),
+               if self.iqi { "inverted" } else { "normal" }, self.wdt)
+    }
+}
+
+/// The `sys get` parameters reported by [`RadioState`].
+pub const SYSQUERY: [&str; 3] = ["ver", "vdd", "hweui"];
+
+/// The `radio get` parameters reported by [`RadioState`].  `snr` and
+/// `rssi` describe the last packet received.
+pub const RADIOQUERY: [&str; 18] = ["mod", "freq", "pwr", "sf", "bw", "cr", "wdt", "sync", "prlen",
+                                    "crc", "iqi", "bt", "afcbw", "rxbw", "bitrate", "fdev", "snr", "rssi"];
+
+/// Everything the radio will tell us about itself, as it answers each
+/// `sys get` and `radio get`, without changing any of it.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RadioState {
+    pub sys: BTreeMap<String, String>,
+    pub radio: BTreeMap<String, String>,
+}
+
+/// A setting an init script would change.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SettingChange {
+    /// What the radio has now; None if it wasn't read.
+    pub current: Option<String>,
+    pub new: String,
+}
+
+impl RadioState {
+    /// What the radio is, from `sys get ver`.
+    pub fn version(&self) -> Result<Version> {
+        Version::parse(self.sys.get("ver").map(|v| v.as_str()).unwrap_or(""))
+    }
+
+    /// The settings the `radio set` commands in `lines` would change, keyed
+    /// by parameter.  The last `radio set` of a parameter is the one that
+    /// counts; other commands aren't considered.
+    pub fn diff(&self, lines: &[String]) -> BTreeMap<String, SettingChange> {
+        let mut requested = BTreeMap::new();
+        for line in lines {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if let ["radio", "set", param, value] = words.as_slice() {
+                requested.insert(String::from(*param), String::from(*value));
+            }
+        }
+
+        requested.into_iter()
+            .filter_map(|(param, new)| {
+                let current = self.radio.get(&param).cloned();
+                match current {
+                    Some(ref c) if samesetting(&param, &new, c) => None,
+                    _ => Some((param, SettingChange { current, new })),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version() {
+        let v = Version::parse("RN2483 1.0.5 Oct 31 2018 15:06:52").unwrap();
+        assert_eq!((v.module, v.firmware), (Module::Rn2483, (1, 0, 5)));
+        assert_eq!(v.raw, "RN2483 1.0.5 Oct 31 2018 15:06:52");
+        assert_eq!(v.firmwarestr(), "1.0.5");
+        assert!(v.check().is_ok());
+        assert_eq!(Version::parse("RN2903 1.0.3 Aug 8 2017 15:11:09").unwrap().firmware, (1, 0, 3));
+        for ver in ["", "RN2903", "RN2904 1.0.5", "RN2903 1.0", "RN2903 1.0.x", "invalid_param"] {
+            assert!(Version::parse(ver).is_err(), "{:?}", ver);
+        }
+    }
+
+    #[test]
+    fn oldfirmware() {
+        for ver in ["RN2903 1.0.3 Aug 8 2017 15:11:09", "RN2483 0.9.9"] {
+            let err = Version::parse(ver).unwrap().check().unwrap_err().to_string();
+            assert!(err.contains("older than 1.0.5"), "{}", err);
+        }
+        assert!(Version::parse("RN2903 1.1.0").unwrap().check().is_ok());
+    }
+
+    // The lines of an init file of `text`, for `module`.
+    fn filelines(name: &str, text: &str, module: Module) -> Vec<String> {
+        let path = std::env::temp_dir().join(format!("lorapipe-{}-{}", name, std::process::id()));
+        fs::write(&path, text).unwrap();
+        let lines = RadioInit::File(path.clone()).lines(module);
+        fs::remove_file(&path).unwrap();
+        lines.unwrap()
+    }
+
+    // What the default settings read back as, with `changes`.
+    fn readback(changes: &[(&'static str, &str)]) -> Vec<(&'static str, String)> {
+        let defaults = [("freq", "923300000"), ("pwr", "20"), ("sf", "sf12"), ("bw", "125"), ("cr", "4/5"),
+                        ("wdt", "60000"), ("sync", "34"), ("prlen", "8"), ("crc", "on"), ("iqi", "off")];
+        defaults.iter().map(|(p, v)| {
+            let v = changes.iter().find(|(c, _)| c == p).map_or(*v, |(_, v)| *v);
+            (*p, String::from(v))
+        }).collect()
+    }
+
+    fn verify(lines: &[&str], values: &[(&'static str, String)]) -> Result<RadioSettings> {
+        let lines: Vec<String> = lines.iter().map(|l| String::from(*l)).collect();
+        RadioSettings::verify(Version::parse("RN2903 1.0.5").unwrap(), &lines, values)
+    }
+
+    #[test]
+    fn verifymatch() {
+        let settings = verify(&RadioConfig::default().commands(Module::Rn2903).iter().map(|l| l.as_str())
+                                  .collect::<Vec<_>>(), &readback(&[])).unwrap();
+        assert_eq!((settings.sf, settings.bw, settings.pwr, settings.wdt), (12, 125, 20, 60000));
+        assert_eq!((settings.crc, settings.iqi), (true, false));
+        // The radio answers as it pleases: hex without leading zeros, and
+        // words in its own case.
+        assert!(verify(&["radio set sync 0a", "radio set crc ON", "radio set pwr 07"],
+                       &readback(&[("sync", "a"), ("pwr", "7")])).is_ok());
+        // Only the last of several sets counts.
+        assert!(verify(&["radio set sf sf9", "radio set sf sf12"], &readback(&[])).is_ok());
+    }
+
+    #[test]
+    fn verifymismatch() {
+        let mismatch = |lines: &[&str], changes: &[(&'static str, &str)]| {
+            match verify(lines, &readback(changes)) {
+                Err(Error::SettingMismatch { param, requested, actual }) => (param, requested, actual),
+                r => panic!("{:?}", r),
+            }
+        };
+        assert_eq!(mismatch(&["radio set pwr 14"], &[]),
+                   (String::from("pwr"), String::from("14"), String::from("20")));
+        // 10 in hex is not 10.
+        assert_eq!(mismatch(&["radio set sync 10"], &[("sync", "a")]),
+                   (String::from("sync"), String::from("10"), String::from("a")));
+        assert_eq!(mismatch(&["radio set sf sf12", "radio set bw 500 "], &[]),
+                   (String::from("bw"), String::from("500"), String::from("125")));
+        // A setting that can't be read as what it is.
+        assert!(matches!(verify(&[], &readback(&[("crc", "maybe")])), Err(Error::UnexpectedReply { .. })));
+    }
+
+    #[test]
+    fn filereset() {
+        let text = "mac reset\n\nmac pause\nradio set sf sf9\n";
+        assert_eq!(filelines("reset2903", text, Module::Rn2903),
+                   ["mac reset", "mac pause", "radio set sf sf9"]);
+        assert_eq!(filelines("reset868", text, Module::Rn2483),
+                   ["mac reset 868", "mac pause", "radio set sf sf9"]);
+        assert_eq!(filelines("reset433", "mac reset\nradio set freq 433500000\n", Module::Rn2483),
+                   ["mac reset 433", "radio set freq 433500000"]);
+        // A band given in the file is kept.
+        assert_eq!(filelines("reset433given", "mac reset 433\n", Module::Rn2483), ["mac reset 433"]);
+    }
+}
@@ -0,0 +1,181 @@
+/*
+    Copyright (C) 2019  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+*/
+
+use crate::error::{Error, Result};
+use crate::radioconfig::{Module, RadioConfig};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// A regional band plan: which module, frequency and power are legal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Region {
+    /// United States, 902-928 MHz
+    Us915,
+    /// Europe, 863-870 MHz
+    Eu868,
+    /// Europe, 433.05-434.79 MHz
+    Eu433,
+    /// Australia, 915-928 MHz
+    Au915,
+    /// Much of Asia, 915-928 MHz
+    As923,
+}
+
+/// How to trade speed against range.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    /// The slowest, most robust settings the region allows.
+    LongRange,
+    #[default]
+    Balanced,
+    /// The fastest settings the region allows.
+    Fast,
+}
+
+pub const REGIONS: [&str; 5] = ["us915", "eu868", "eu433", "au915", "as923"];
+pub const PROFILES: [&str; 3] = ["longrange", "balanced", "fast"];
+
+impl Region {
+    /// The module that can operate in this region.
+    pub fn module(self) -> Module {
+        match self {
+            Region::Us915 | Region::Au915 | Region::As923 => Module::Rn2903,
+            Region::Eu868 | Region::Eu433 => Module::Rn2483,
+        }
+    }
+
+    /// The frequency to use, in Hz.  These are the first downlink or
+    /// default channels of each region's LoRaWAN plan.
+    pub fn freq(self) -> u32 {
+        match self {
+            Region::Us915 | Region::Au915 => 923_300_000,
+            Region::As923 => 923_200_000,
+            Region::Eu868 => 868_100_000,
+            Region::Eu433 => 433_175_000,
+        }
+    }
+
+    /// The highest transmit power to use, in dBm.
+    pub fn maxpwr(self) -> i8 {
+        match self {
+            Region::Us915 | Region::Au915 => 20,
+            Region::As923 => 16,
+            Region::Eu868 => 14,
+            Region::Eu433 => 10,
+        }
+    }
+
+    /// Spreading factor, bandwidth and coding rate for `profile`, or an
+    /// explanation of why the region doesn't allow it.
+    ///
+    /// The US only allows 400 ms on air at a time on 125 kHz channels, so
+    /// 500 kHz is used there.  AS923 has the same limit and no 500 kHz
+    /// channels, so the slowest settings are refused.
+    pub fn modulation(self, profile: Profile) -> std::result::Result<(u8, u16, &'static str), &'static str> {
+        match (self, profile) {
+            (Region::Us915, Profile::LongRange) => Ok((12, 500, "4/8")),
+            (Region::Us915, Profile::Balanced) => Ok((9, 500, "4/5")),
+            (Region::As923, Profile::LongRange) =>
+                Err("SF12 at 125 kHz exceeds the 400 ms dwell time limit"),
+            (Region::As923, Profile::Balanced) => Ok((8, 125, "4/5")),
+            (_, Profile::LongRange) => Ok((12, 125, "4/8")),
+            (_, Profile::Balanced) => Ok((9, 125, "4/5")),
+            (Region::Us915, Profile::Fast) | (Region::Au915, Profile::Fast) => Ok((7, 500, "4/5")),
+            (_, Profile::Fast) => Ok((7, 250, "4/5")),
+        }
+    }
+
+    /// The radio configuration for `profile` in this region.
+    pub fn config(self, profile: Profile) -> Result<RadioConfig> {
+        let (sf, bw, cr) = self.modulation(profile)
+            .map_err(|why| Error::Config(format!("profile {} is not allowed in {}: {}", profile, self, why)))?;
+        Ok(RadioConfig {
+            module: Some(self.module()),
+            freq: Some(self.freq()),
+            pwr: Some(self.maxpwr()),
+            sf,
+            bw,
+            cr: String::from(cr),
+            ..RadioConfig::default()
+        })
+    }
+}
+
+/// The duty cycle limits of the European sub-bands, in Hz, as a fraction
+/// of each hour (ETSI EN 300 220).  The first that matches applies; the
+/// rest of 863-870 MHz is held to 0.1%.
+pub const DUTYCYCLEBANDS: [(u32, u32, f64); 8] = [
+    (433_050_000, 434_790_000, 0.10),
+    (863_000_000, 865_000_000, 0.001),
+    (865_000_000, 868_000_000, 0.01),
+    (868_000_000, 868_600_000, 0.01),
+    (868_700_000, 869_200_000, 0.001),
+    (869_400_000, 869_650_000, 0.10),
+    (869_700_000, 870_000_000, 0.01),
+    (863_000_000, 870_000_000, 0.001),
+];
+
+/// The legal duty cycle limit for transmitting on `freq`, if there is one.
+pub fn dutycycle(freq: u32) -> Option<f64> {
+    DUTYCYCLEBANDS.iter()
+        .find(|(lo, hi, _)| (*lo..=*hi).contains(&freq))
+        .map(|(_, _, limit)| *limit)
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", REGIONS[*self as usize])
+    }
+}
+
+impl FromStr for Region {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Region> {
+        match s.to_ascii_lowercase().as_str() {
+            "us915" => Ok(Region::Us915),
+            "eu868" => Ok(Region::Eu868),
+            "eu433" => Ok(Region::Eu433),
+            "au915" => Ok(Region::Au915),
+            "as923" => Ok(Region::As923),
+            _ => Err(Error::Config(format!("unknown region {:?}; expected one of {}", s, REGIONS.join(", ")))),
+        }
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", PROFILES[*self as usize])
+    }
+}
+
+impl FromStr for Profile {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Profile> {
+        match s.to_ascii_lowercase().as_str() {
+            "longrange" => Ok(Profile::LongRange),
+            "balanced" => Ok(Profile::Balanced),
+            "fast" => Ok(Profile::Fast),
+            _ => Err(Error::Config(format!("unknown profile {:?}; expected one of {}", s, PROFILES.join(", ")))),
+        }
+    }
+}
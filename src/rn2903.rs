@@ -0,0 +1,356 @@
+/*
+    Copyright (C) 2019  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+*/
+
+use crate::adr::Modulation;
+use crate::cmd::{CmdEngine, Reply, REPLYTIMEOUT, RESETTIMEOUT};
+use crate::error::{Error, Result};
+use crate::radio::RadioDriver;
+use crate::radioconfig::{RadioInit, RadioSettings, RadioState, Version, RADIOQUERY, READBACK, SYSQUERY};
+use crate::ser::LoraSer;
+use log::*;
+use std::collections::BTreeMap;
+use std::thread;
+use std::time::Duration;
+
+/// How long a transmission may take before radio_tx_ok.  Long enough for
+/// the biggest frame at the slowest settings.
+pub(crate) const TXTIMEOUT: Duration = Duration::from_secs(30);
+
+/// Driver for the Microchip RN2903 and RN2483, as used in the LoStik.
+#[derive(Clone)]
+pub struct Rn2903 {
+    pub(crate) cmd: CmdEngine,
+}
+
+impl Rn2903 {
+    /// Creates a new driver, starting the thread that reads from the radio.
+    pub fn new(ser: LoraSer) -> Rn2903 {
+        Rn2903 { cmd: CmdEngine::new(ser) }
+    }
+
+    /// Take the radio out of whatever it was doing, and forget anything
+    /// it has said.
+    fn quiesce(&mut self) -> Result<()> {
+        for line in QUIESCE.iter() {
+            self.cmd.send(line)?;
+        }
+
+        // Give it a chance to do its thing.
+        thread::sleep(QUIESCEWAIT);
+
+        // Consume all data.
+        self.cmd.flush();
+        Ok(())
+    }
+
+    /// Ask the radio for everything in [`SYSQUERY`] and [`RADIOQUERY`],
+    /// changing nothing.  Parameters the radio won't give are left out.
+    pub fn query(&mut self) -> Result<RadioState> {
+        self.quiesce()?;
+        let mut state = RadioState { sys: BTreeMap::new(), radio: BTreeMap::new() };
+        for (kind, params, values) in [("sys", &SYSQUERY[..], &mut state.sys),
+                                       ("radio", &RADIOQUERY[..], &mut state.radio)] {
+            for param in params {
+                let line = format!("{} get {}", kind, param);
+                match self.cmd.command(&line, REPLYTIMEOUT)? {
+                    Reply::Value(v) => { values.insert(String::from(*param), v); },
+                    r => debug!("No answer to {}: {:?}", line, r),
+                }
+            }
+        }
+        Ok(state)
+    }
+
+    /// Send one line of an init script and check the response.
+    fn initcmd(&mut self, line: &str) -> Result<()> {
+        let reply = self.cmd.command(line, inittimeout(line))?;
+        checkinit(line, reply)
+    }
+}
+
+/// What to send a radio to take it out of whatever it was doing: an
+/// invalid command, and an rxstop in case we are reconnecting to a radio
+/// left in receive mode.  Everything it says until [`QUIESCEWAIT`] has
+/// passed is then thrown away.
+pub(crate) const QUIESCE: [&str; 2] = ["INVALIDCOMMAND", "radio rxstop"];
+pub(crate) const QUIESCEWAIT: Duration = Duration::from_secs(1);
+
+/// The next thing for a driver to do while configuring a radio.
+pub(crate) enum Step {
+    /// Send a command, waiting up to the duration for its reply.
+    Command(String, Duration),
+    Done(RadioSettings),
+}
+
+/// How a quiesced radio is configured, shared by [`Rn2903`] and the
+/// async link: ask it what it is, send it the init lines for that, and
+/// read the settings back to check them.  Like
+/// [`Turns`](crate::turns::Turns), this does no I/O.
+pub(crate) struct Configure<'a> {
+    init: &'a RadioInit,
+    // The command last sent.
+    cmd: String,
+    version: Option<Version>,
+    lines: Vec<String>,
+    sent: usize,
+    values: Vec<(&'static str, String)>,
+}
+
+impl<'a> Configure<'a> {
+    pub fn new(init: &'a RadioInit) -> Configure<'a> {
+        Configure { init, cmd: String::new(), version: None, lines: vec![], sent: 0, values: vec![] }
+    }
+
+    /// Take the reply to the command last sent, None at the start, and
+    /// say what to do next.
+    pub fn step(&mut self, reply: Option<Reply>) -> Result<Step> {
+        match (reply, &self.version) {
+            (None, _) => (),
+            (Some(reply), None) => {
+                let version = checkversion(&reply.value(&self.cmd)?)?;
+                self.lines = self.init.lines(version.module)?;
+                self.version = Some(version);
+            },
+            (Some(reply), Some(_)) if self.sent < self.lines.len() => {
+                checkinit(&self.lines[self.sent], reply)?;
+                self.sent += 1;
+            },
+            (Some(reply), Some(_)) => self.values.push((READBACK[self.values.len()], reply.value(&self.cmd)?)),
+        }
+
+        let (cmd, timeout) = if self.version.is_none() {
+            debug!("Configuring radio");
+            (String::from("sys get ver"), REPLYTIMEOUT)
+        } else if let Some(line) = self.lines.get(self.sent) {
+            (line.clone(), inittimeout(line))
+        } else if let Some(param) = READBACK.get(self.values.len()) {
+            (format!("radio get {}", param), REPLYTIMEOUT)
+        } else {
+            let version = self.version.take().unwrap();
+            let settings = RadioSettings::verify(version, &self.lines, &self.values)?;
+            info!("Radio settings: {}", settings);
+            return Ok(Step::Done(settings));
+        };
+        self.cmd = cmd.clone();
+        Ok(Step::Command(cmd, timeout))
+    }
+}
+
+/// The command that transmits `frame`.
+pub(crate) fn txcommand(frame: &[u8]) -> String {
+    format!("radio tx {}", hex::encode(frame))
+}
+
+/// Check the reply that ends a transmission.
+pub(crate) fn checktx(reply: Reply) {
+    if reply != Reply::RadioTxOk {
+        warn!("Transmission may have failed: got {:?} instead of radio_tx_ok", reply);
+    }
+}
+
+/// Make sense of the reply to `radio rxstop` and the packets set aside
+/// while waiting for it.  A packet may have raced in, which is returned.
+pub(crate) fn rxstopped(reply: Reply, deferred: Vec<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+    // If the radio has just finished receiving something, it will have ended
+    // receive mode with radio_err.  That's OK too.
+    match reply {
+        Reply::Ok | Reply::RadioErr => (),
+        r => return Err(Error::UnexpectedReply { command: String::from("radio rxstop"), reply: format!("{:?}", r) }),
+    }
+
+    let mut frames = deferred.into_iter();
+    let frame = frames.next();
+    for extra in frames {
+        // Shouldn't happen, since a packet ends receive mode.
+        warn!("Dropping extra packet received during rxstop: {}", hex::encode(extra));
+    }
+    Ok(frame)
+}
+
+/// How long a line of an init script may take to answer.
+pub(crate) fn inittimeout(line: &str) -> Duration {
+    if line.starts_with("mac reset") || line.starts_with("sys reset") {
+        RESETTIMEOUT
+    } else {
+        REPLYTIMEOUT
+    }
+}
+
+/// Make sense of the answer to `sys get ver`, failing if the radio can't
+/// be used.
+pub(crate) fn checkversion(ver: &str) -> Result<Version> {
+    let version = Version::parse(ver)?;
+    version.check()?;
+    info!("Radio is an {} with firmware {}", version.module, version.firmwarestr());
+    Ok(version)
+}
+
+/// Whether the reply to a line of an init script is acceptable.
+pub(crate) fn checkinit(line: &str, reply: Reply) -> Result<()> {
+    match reply {
+        r @ Reply::InvalidParam | r @ Reply::Busy =>
+            Err(Error::InitFailed { command: String::from(line), reply: format!("{:?}", r) }),
+        _ => Ok(()),
+    }
+}
+
+impl RadioDriver for Rn2903 {
+    type Event = Result<Reply>;
+
+    fn configure(&mut self, init: &RadioInit) -> Result<RadioSettings> {
+        self.quiesce()?;
+
+        let mut configure = Configure::new(init);
+        let mut reply = None;
+        loop {
+            match configure.step(reply)? {
+                Step::Command(cmd, timeout) => reply = Some(self.cmd.command(&cmd, timeout)?),
+                Step::Done(settings) => return Ok(settings),
+            }
+        }
+    }
+
+    fn events(&self) -> &crossbeam_channel::Receiver<Result<Reply>> {
+        self.cmd.events()
+    }
+
+    fn decodeevent(&mut self, event: Result<Reply>) -> Result<Option<Vec<u8>>> {
+        match event? {
+            Reply::RadioRx(frame) => Ok(Some(frame)),
+            // Might get radio_err here.  That's harmless.
+            _ => Ok(None),
+        }
+    }
+
+    fn txframe(&mut self, frame: &[u8]) -> Result<()> {
+        let txstr = txcommand(frame);
+
+        self.cmd.commandok(&txstr, REPLYTIMEOUT)?;
+        checktx(self.cmd.nextreply(&txstr, TXTIMEOUT)?);
+        Ok(())
+    }
+
+    fn enterrx(&mut self) -> Result<()> {
+        self.cmd.commandok("radio rx 0", REPLYTIMEOUT)
+    }
+
+    fn rxstop(&mut self) -> Result<Option<Vec<u8>>> {
+        let reply = self.cmd.commandkeepdeferred("radio rxstop", REPLYTIMEOUT)?;
+        rxstopped(reply, self.cmd.takedeferred())
+    }
+
+    fn readqual(&mut self) -> Result<(String, String)> {
+        let snr = self.cmd.commandvalue("radio get snr", REPLYTIMEOUT)?;
+        let rssi = self.cmd.commandvalue("radio get rssi", REPLYTIMEOUT)?;
+        Ok((snr, rssi))
+    }
+
+    fn rssi(&mut self) -> Result<i32> {
+        let rssi = self.cmd.commandvalue("radio get rssi", REPLYTIMEOUT)?;
+        rssi.parse().map_err(|_| Error::UnexpectedReply { command: String::from("radio get rssi"), reply: rssi })
+    }
+    fn setmodulation(&mut self, modulation: &Modulation) -> Result<()> {
+        for line in modulation.commands() {
+            self.initcmd(&line)?;
+        }
+        Ok(())
+    }
+
+    fn setpower(&mut self, power: i8) -> Result<()> {
+        self.initcmd(&format!("radio set pwr {}", power))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::radioconfig::{Module, RadioConfig};
+
+    // Run the configure sequence against a radio that answers with
+    // `answer`, returning the commands sent and the outcome.
+    fn configure(init: &RadioInit, answer: impl Fn(&str) -> Reply) -> (Vec<String>, Result<RadioSettings>) {
+        let mut configure = Configure::new(init);
+        let mut sent = vec![];
+        let mut reply = None;
+        loop {
+            match configure.step(reply) {
+                Ok(Step::Command(cmd, _)) => {
+                    reply = Some(answer(&cmd));
+                    sent.push(cmd);
+                },
+                Ok(Step::Done(settings)) => return (sent, Ok(settings)),
+                Err(e) => return (sent, Err(e)),
+            }
+        }
+    }
+
+    // A radio with the default settings.
+    fn radio(cmd: &str) -> Reply {
+        let value = |v: &str| Reply::Value(String::from(v));
+        match cmd {
+            "sys get ver" => value("RN2483 1.0.5 Oct 31 2018 15:06:52"),
+            "radio get freq" => value("868100000"),
+            "radio get pwr" => value("14"),
+            "radio get sf" => value("sf12"),
+            "radio get bw" => value("125"),
+            "radio get cr" => value("4/5"),
+            "radio get wdt" => value("60000"),
+            "radio get sync" => value("34"),
+            "radio get prlen" => value("8"),
+            "radio get crc" => value("on"),
+            "radio get iqi" => value("off"),
+            _ if cmd.starts_with("radio get") => value("0"),
+            _ => Reply::Ok,
+        }
+    }
+
+    #[test]
+    fn configuresequence() {
+        let init = RadioInit::Default;
+        let (sent, settings) = configure(&init, radio);
+        let settings = settings.unwrap();
+        let lines = RadioConfig::default().commands(Module::Rn2483);
+        assert_eq!(sent[0], "sys get ver");
+        assert_eq!(sent[1..=lines.len()], lines[..]);
+        assert_eq!(sent[lines.len() + 1..], READBACK.iter().map(|p| format!("radio get {}", p)).collect::<Vec<_>>()[..]);
+        assert_eq!((settings.version.module, settings.freq, settings.pwr), (Module::Rn2483, 868_100_000, 14));
+    }
+
+    #[test]
+    fn configurefails() {
+        let init = RadioInit::Default;
+        // An init line refused stops the sequence there.
+        let (sent, res) = configure(&init, |cmd| if cmd == "radio set bw 125" { Reply::InvalidParam } else { radio(cmd) });
+        assert!(matches!(res, Err(Error::InitFailed { .. })));
+        assert_eq!(sent.last().unwrap(), "radio set bw 125");
+        // So does an answer that isn't a value.
+        let (sent, res) = configure(&init, |cmd| if cmd == "radio get sf" { Reply::Ok } else { radio(cmd) });
+        assert!(matches!(res, Err(Error::UnexpectedReply { .. })));
+        assert_eq!(sent.last().unwrap(), "radio get sf");
+        // A setting the radio didn't take.
+        let (_, res) = configure(&init, |cmd| if cmd == "radio get pwr" { Reply::Value(String::from("3")) } else { radio(cmd) });
+        assert!(matches!(res, Err(Error::SettingMismatch { .. })));
+    }
+
+    #[test]
+    fn rxstopreplies() {
+        assert_eq!(rxstopped(Reply::Ok, vec![]).unwrap(), None);
+        assert_eq!(rxstopped(Reply::RadioErr, vec![vec![1], vec![2]]).unwrap(), Some(vec![1]));
+        assert!(rxstopped(Reply::Busy, vec![]).is_err());
+    }
+}
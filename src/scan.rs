@@ -0,0 +1,108 @@
+/*
+    Copyright (C) 2019  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+*/
+
+use crate::cmd::{Reply, REPLYTIMEOUT};
+use crate::error::{Error, Result};
+use crate::radioconfig::Module;
+use crate::rn2903::Rn2903;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+/// What was heard on one channel during a scan.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ChannelActivity {
+    /// Frequency in Hz
+    pub freq: u32,
+    /// The median RSSI sampled, in dBm
+    pub noisefloor: Option<i32>,
+    /// The highest RSSI sampled, in dBm
+    pub peakrssi: Option<i32>,
+    pub samples: usize,
+    /// Packets received intact
+    pub packets: usize,
+    /// Receptions that ended in radio_err, as on a CRC error
+    pub errors: usize,
+}
+
+/// The frequencies from `from` to `to` inclusive, `step` Hz apart,
+/// checked against what `module` can tune to.
+pub fn channels(module: Module, from: u32, to: u32, step: u32) -> Result<Vec<u32>> {
+    if step == 0 || from > to {
+        return Err(Error::Config(format!("can't step from {} to {} by {}", from, to, step)));
+    }
+    let freqs: Vec<u32> = (from..=to).step_by(step as usize).collect();
+    let ranges = module.freqranges();
+    match freqs.iter().find(|f| !ranges.iter().any(|(lo, hi)| (*lo..=*hi).contains(*f))) {
+        Some(f) => Err(Error::Config(format!("{} Hz is outside the {}'s bands", f, module))),
+        None => Ok(freqs),
+    }
+}
+
+impl Rn2903 {
+    /// Listen on `freq` for `dwell`, sampling the RSSI every `interval`
+    /// and counting what is received.  The radio must have been
+    /// configured, and is left tuned to `freq`.
+    pub fn scanchannel(&mut self, freq: u32, dwell: Duration, interval: Duration) -> Result<ChannelActivity> {
+        self.cmd.commandok(&format!("radio set freq {}", freq), REPLYTIMEOUT)?;
+        let mut activity = ChannelActivity { freq, noisefloor: None, peakrssi: None,
+                                             samples: 0, packets: 0, errors: 0 };
+        let mut rssis = vec![];
+        let deadline = Instant::now() + dwell;
+        while Instant::now() < deadline {
+            let sampleat = (Instant::now() + interval).min(deadline);
+            self.cmd.commandok("radio rx 0", REPLYTIMEOUT)?;
+            loop {
+                let remaining = sampleat.saturating_duration_since(Instant::now());
+                match self.cmd.events().recv_timeout(remaining) {
+                    Ok(reply) => {
+                        // Either ends receive mode; go back into it.
+                        match reply? {
+                            Reply::RadioRx(_) => activity.packets += 1,
+                            Reply::RadioErr => activity.errors += 1,
+                            _ => continue,
+                        }
+                        if Instant::now() < sampleat {
+                            self.cmd.commandok("radio rx 0", REPLYTIMEOUT)?;
+                        }
+                    },
+                    Err(e) if e.is_timeout() => break,
+                    Err(e) => return Err(Error::Disconnected(e.to_string())),
+                }
+            }
+
+            // The radio won't answer anything else while receiving.
+            self.cmd.command("radio rxstop", REPLYTIMEOUT)?;
+            if let Ok(rssi) = self.cmd.commandvalue("radio get rssi", REPLYTIMEOUT)?.parse::<i32>() {
+                rssis.push(rssi);
+            }
+            // Packets that arrived while those commands were answered are
+            // put back on the channel afterwards.
+            while let Ok(reply) = self.cmd.events().try_recv() {
+                if let Reply::RadioRx(_) = reply? {
+                    activity.packets += 1;
+                }
+            }
+        }
+
+        rssis.sort_unstable();
+        activity.samples = rssis.len();
+        activity.noisefloor = rssis.get(rssis.len() / 2).cloned();
+        activity.peakrssi = rssis.last().cloned();
+        Ok(activity)
+    }
+}
@@ -18,43 +18,71 @@
 
 use std::io;
 use serialport::prelude::*;
-use std::io::{BufReader, BufRead, Write};
+use std::io::{BufReader, BufRead, Read, Write};
 use log::*;
 use std::sync::{Arc, Mutex};
+use std::thread;
 use std::time::Duration;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use crate::error::{Error, Result};
+use crate::net;
 
+pub(crate) const BAUD: u32 = 57600;
+
+/// How long to wait between attempts to reopen a lost port.
+const RECONNECTWAIT: Duration = Duration::from_secs(2);
+
+/// A line-oriented connection to the radio.  Clones share the same
+/// underlying port.
 #[derive(Clone)]
 pub struct LoraSer {
     // BufReader can't be cloned.  Sigh.
-    pub br: Arc<Mutex<BufReader<Box<dyn SerialPort>>>>,
-    pub swrite: Arc<Mutex<Box<dyn SerialPort>>>,
+    pub br: Arc<Mutex<BufReader<Box<dyn Read + Send>>>>,
+    pub swrite: Arc<Mutex<Box<dyn Write + Send>>>,
     pub portname: PathBuf
 }
 
 impl LoraSer {
 
-    /// Initialize the serial system, configuring the port.
-    pub fn new(portname: PathBuf) -> io::Result<LoraSer> {
-        let settings = SerialPortSettings {
-            baud_rate: 57600,
-            data_bits: DataBits::Eight,
-            flow_control: FlowControl::None,
-            parity: Parity::None,
-            stop_bits: StopBits::One,
-            timeout: Duration::new(60 * 60 * 24 * 365 * 20, 0),
-        };
-        let readport = serialport::open_with_settings(&portname, &settings)?;
-        let writeport = readport.try_clone()?;
-        
+    /// Initialize the serial system, configuring the port.  Besides a
+    /// local serial device, the port may be given as tcp://HOST:PORT for a
+    /// raw TCP connection (such as ser2net), or rfc2217://HOST:PORT for a
+    /// Telnet COM port server.
+    pub fn new(portname: PathBuf) -> Result<LoraSer> {
+        let (readport, writeport) = openport(&portname)?;
+
         Ok(LoraSer {br: Arc::new(Mutex::new(BufReader::new(readport))),
                     swrite: Arc::new(Mutex::new(writeport)),
-                    portname: portname})
+                    portname})
+    }
+
+    /// Open the port again, replacing the old connection in this LoraSer
+    /// and all its clones.
+    pub fn reopen(&mut self) -> Result<()> {
+        let (readport, writeport) = openport(&self.portname)?;
+        *self.br.lock().unwrap() = BufReader::new(readport);
+        *self.swrite.lock().unwrap() = writeport;
+        Ok(())
+    }
+
+    /// Keep trying to reopen the port until it succeeds.  Used after the
+    /// device has vanished, as when a USB radio drops off the bus.
+    pub fn reconnect(&mut self) {
+        loop {
+            thread::sleep(RECONNECTWAIT);
+            match self.reopen() {
+                Ok(()) => {
+                    warn!("{:?}: reconnected", self.portname);
+                    return;
+                },
+                Err(e) => debug!("{:?}: reconnect failed: {}", self.portname, e),
+            }
+        }
     }
 
     /// Read a line from the port.  Return it with EOL characters removed.
     /// None if EOF reached.
-    pub fn readln(&mut self) -> io::Result<Option<String>> {
+    pub fn readln(&mut self) -> Result<Option<String>> {
         let mut buf = String::new();
         let size = self.br.lock().unwrap().read_line(&mut buf)?;
         if size == 0 {
@@ -68,12 +96,37 @@ impl LoraSer {
     }
 
     /// Transmits a command with terminating EOL characters
-    pub fn writeln(&mut self, mut data: String) -> io::Result<()> {
+    pub fn writeln(&mut self, mut data: String) -> Result<()> {
         trace!("{:?} SEROUT: {}", self.portname, data);
         data.push_str("\r\n");
         // Give the receiver a chance to process
-        self.swrite.lock().unwrap().write_all(data.as_bytes())?;
-        self.swrite.lock().unwrap().flush()
+        let mut swrite = self.swrite.lock().unwrap();
+        swrite.write_all(data.as_bytes())
+            .and_then(|_| swrite.flush())
+            // A failed write means the device is gone; say so in a way
+            // the main loop can recognize.
+            .map_err(|e| Error::Disconnected(e.to_string()))
+    }
+}
+
+/// Open a port, giving separate reading and writing halves.
+fn openport(portname: &Path) -> io::Result<(Box<dyn Read + Send>, Box<dyn Write + Send>)> {
+    match portname.to_str() {
+        Some(s) if s.starts_with("tcp://") => net::opentcp(&s[6..]),
+        Some(s) if s.starts_with("rfc2217://") => net::openrfc2217(&s[10..], BAUD),
+        _ => {
+            let settings = SerialPortSettings {
+                baud_rate: BAUD,
+                data_bits: DataBits::Eight,
+                flow_control: FlowControl::None,
+                parity: Parity::None,
+                stop_bits: StopBits::One,
+                timeout: Duration::new(60 * 60 * 24 * 365 * 20, 0),
+            };
+            let readport = serialport::open_with_settings(portname, &settings)?;
+            let writeport = readport.try_clone()?;
+            Ok((Box::new(readport), Box::new(writeport)))
+        }
     }
 }
 
@@ -0,0 +1,186 @@
+/*
+    Copyright (C) 2019  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+*/
+
+use crate::lorastik::{LoraStik, ReceivedFrames};
+use crate::radio::RadioDriver;
+use crossbeam_channel::TryRecvError;
+use std::io;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+
+/// The receiving side, shared between clones so that data is read in
+/// order no matter which clone reads it.
+struct RxState {
+    receiver: crossbeam_channel::Receiver<ReceivedFrames>,
+    // The part of the last frame not yet read.
+    pending: Vec<u8>,
+}
+
+/// A byte stream over a [`LoraStik`] link, for code that wants something
+/// implementing [`Read`] and [`Write`].
+///
+/// Frame boundaries are not preserved: a read may return part of a frame,
+/// or the rest of one.  Once [`LoraStik::mainloop`] has returned, reads
+/// return EOF after what was already received, and writes fail.
+pub struct LoraStream {
+    txblocks: crossbeam_channel::Sender<Vec<u8>>,
+    // Disconnected once the link is gone.
+    linkdown: crossbeam_channel::Receiver<()>,
+    rx: Arc<Mutex<RxState>>,
+}
+
+impl LoraStream {
+    /// Wraps a link and the receiver returned alongside it by
+    /// [`LoraStik::new`].  [`LoraStik::mainloop`] must still be run.
+    pub fn new<D: RadioDriver>(ls: LoraStik<D>, receiver: crossbeam_channel::Receiver<ReceivedFrames>) -> LoraStream {
+        let (txblocks, linkdown) = ls.channels();
+        LoraStream { txblocks, linkdown, rx: Arc::new(Mutex::new(RxState { receiver, pending: vec![] })) }
+    }
+
+    /// Another handle on the same stream, so reading and writing can be
+    /// done from different threads.
+    pub fn try_clone(&self) -> io::Result<LoraStream> {
+        Ok(LoraStream { txblocks: self.txblocks.clone(), linkdown: self.linkdown.clone(), rx: self.rx.clone() })
+    }
+}
+
+fn linkgone() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "radio link is gone")
+}
+
+impl Read for LoraStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut rx = self.rx.lock().unwrap();
+        while rx.pending.is_empty() {
+            match rx.receiver.try_recv() {
+                Ok(frame) => rx.pending = frame.payload,
+                Err(TryRecvError::Disconnected) => return Ok(0),
+                Err(TryRecvError::Empty) => {
+                    let mut sel = crossbeam_channel::Select::new();
+                    sel.recv(&rx.receiver);
+                    let downidx = sel.recv(&self.linkdown);
+                    // Nothing more is coming once the link is gone.
+                    if sel.ready() == downidx && rx.receiver.is_empty() {
+                        return Ok(0);
+                    }
+                },
+            }
+        }
+        let size = buf.len().min(rx.pending.len());
+        buf[..size].copy_from_slice(&rx.pending[..size]);
+        rx.pending.drain(..size);
+        Ok(size)
+    }
+}
+
+impl Write for LoraStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(buf.len());
+        }
+        if let Err(TryRecvError::Disconnected) = self.linkdown.try_recv() {
+            return Err(linkgone());
+        }
+        // The queue is not emptied once the link is gone.
+        crossbeam_channel::select! {
+            send(self.txblocks, buf.to_vec()) -> res => res.map_err(|_| linkgone())?,
+            recv(self.linkdown) -> _ => return Err(linkgone()),
+        }
+        Ok(buf.len())
+    }
+
+    /// Data is queued for the radio as soon as it is written, so there is
+    /// nothing to flush.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{Error, Result};
+    use crate::lorastik::LinkConfig;
+    use crate::radioconfig::{RadioInit, RadioSettings};
+    use std::thread;
+
+    // A radio that fails as soon as it is used.
+    #[derive(Clone)]
+    struct Broken(crossbeam_channel::Receiver<()>);
+
+    impl RadioDriver for Broken {
+        type Event = ();
+
+        fn configure(&mut self, _init: &RadioInit) -> Result<RadioSettings> {
+            Err(Error::Unsupported(String::from("broken radio")))
+        }
+
+        fn events(&self) -> &crossbeam_channel::Receiver<()> {
+            &self.0
+        }
+
+        fn decodeevent(&mut self, _event: ()) -> Result<Option<Vec<u8>>> {
+            Ok(None)
+        }
+
+        fn txframe(&mut self, _frame: &[u8]) -> Result<()> {
+            Err(Error::Unsupported(String::from("broken radio")))
+        }
+
+        fn enterrx(&mut self) -> Result<()> {
+            Err(Error::Unsupported(String::from("broken radio")))
+        }
+
+        fn rxstop(&mut self) -> Result<Option<Vec<u8>>> {
+            Err(Error::Unsupported(String::from("broken radio")))
+        }
+
+        fn readqual(&mut self) -> Result<(String, String)> {
+            Err(Error::Unsupported(String::from("broken radio")))
+        }
+    }
+
+    // A stream over a link whose mainloop has failed, and the link, which
+    // would keep its queues open.
+    fn dead() -> (LoraStream, LoraStik<Broken>) {
+        let (ls, receiver) = LoraStik::new(Broken(crossbeam_channel::never()), LinkConfig::default());
+        let stream = LoraStream::new(ls.clone(), receiver);
+        let mut mainloop = ls.clone();
+        assert!(thread::spawn(move || mainloop.mainloop()).join().unwrap().is_err());
+        (stream, ls)
+    }
+
+    #[test]
+    fn readeof() {
+        let (mut stream, _ls) = dead();
+        let mut buf = [0; 10];
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn writeerror() {
+        let (mut stream, _ls) = dead();
+        // More than the queue holds.
+        for _ in 0..3 {
+            assert_eq!(stream.write(b"data").unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+        }
+    }
+}
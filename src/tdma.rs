@@ -0,0 +1,334 @@
+/*
+    Copyright (C) 2019  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+*/
+
+//! Time-division media access, for more than two nodes on a frequency.
+//! A coordinator beacons the start of each superframe, which is divided
+//! into equal slots: the first is the coordinator's, and then one for
+//! each member it lists, in order.  Each node transmits only within its
+//! own slot, keeping a guard time clear at either end.
+//!
+//! A beacon's payload is the slot length in milliseconds (four bytes,
+//! big-endian), then the addresses of the members.  Members time the
+//! superframe from the start of the beacon, worked out from when it was
+//! received and how long it took on the air.  When beacons are lost, they
+//! carry on from the last one heard, until too many have been missed.
+
+use log::*;
+use std::time::{Duration, Instant};
+
+/// How many full frames a slot has room for, unless its length is given.
+pub const SLOTFRAMES: u32 = 4;
+
+/// How many superframes in a row a member may miss the beacon of before
+/// it stops transmitting.
+const MAXMISSED: u32 = 4;
+
+/// How long a member without a slot waits before checking again.
+const UNSYNCEDWAIT: Duration = Duration::from_secs(1);
+
+/// Settings for time-division media access.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TdmaConfig {
+    /// On the coordinator, the addresses of the member nodes, given slots
+    /// in this order.  Empty on a member, which follows the coordinator's
+    /// beacons.
+    pub members: Vec<u8>,
+
+    /// The length of each slot, set by the coordinator.  None leaves room
+    /// for [`SLOTFRAMES`] full frames.
+    pub slot: Option<Duration>,
+
+    /// The time kept clear at the start and end of each slot, for
+    /// differences in timing between nodes.
+    pub guard: Duration,
+}
+
+impl Default for TdmaConfig {
+    fn default() -> TdmaConfig {
+        TdmaConfig { members: vec![], slot: None, guard: Duration::from_millis(200) }
+    }
+}
+
+/// The slot schedule of a node.
+#[derive(Clone)]
+pub(crate) struct Tdma {
+    node: u8,
+    coordinator: bool,
+    // The slot length asked for, on the coordinator.
+    slotconfig: Option<Duration>,
+    guard: Duration,
+    // How far ahead of its time a beacon is started, for the pause before
+    // transmitting.
+    lead: Duration,
+    // The longest it takes to get a frame out, once cleared to.
+    txtime: Duration,
+    // The schedule: the slot length and members, and this node's slot,
+    // if it has one.
+    slot: Duration,
+    members: Vec<u8>,
+    index: Option<usize>,
+    // The start of the current superframe, if known, and how many beacons
+    // in a row have been missed since.
+    start: Option<Instant>,
+    missed: u32,
+}
+
+impl Tdma {
+    pub fn new(config: &TdmaConfig, node: u8, lead: Duration) -> Tdma {
+        let coordinator = !config.members.is_empty();
+        Tdma { node,
+               coordinator,
+               slotconfig: config.slot,
+               guard: config.guard,
+               lead,
+               txtime: Duration::ZERO,
+               slot: config.slot.unwrap_or_default(),
+               members: config.members.clone(),
+               index: if coordinator { Some(0) } else { None },
+               start: None,
+               missed: 0 }
+    }
+
+    /// Fit the schedule to the time it takes to get a full frame out.
+    pub fn tune(&mut self, txtime: Duration) {
+        self.txtime = txtime;
+        if self.coordinator {
+            self.slot = self.slotconfig.unwrap_or(txtime * SLOTFRAMES + self.guard * 2);
+            info!("TDMA coordinator: {} slot(s) of {:?}, for this node and {:?}",
+                  self.members.len() + 1, self.slot, self.members);
+            self.checkslot();
+        }
+    }
+
+    // Warn if a slot has no room for a frame.
+    fn checkslot(&self) {
+        if self.slot < self.txtime + self.guard * 2 {
+            warn!("TDMA slots of {:?} are too short for a {:?} frame within {:?} guard times; \
+                   this node will never transmit", self.slot, self.txtime, self.guard);
+        }
+    }
+
+    /// The time from one beacon to the next.
+    pub fn period(&self) -> Duration {
+        self.slot * (self.members.len() as u32 + 1)
+    }
+
+    /// On the coordinator, whether the next beacon is to be sent.
+    pub fn beacondue(&self) -> bool {
+        self.coordinator && match self.start {
+            None => true,
+            Some(start) => Instant::now() + self.lead >= start + self.period(),
+        }
+    }
+
+    /// The payload of a beacon.
+    pub fn beacon(&self) -> Vec<u8> {
+        let mut payload = (self.slot.as_millis() as u32).to_be_bytes().to_vec();
+        payload.extend_from_slice(&self.members);
+        payload
+    }
+
+    /// A beacon is going out now; a superframe starts.
+    pub fn beaconsent(&mut self) {
+        self.start = Some(Instant::now());
+    }
+
+    /// On a member, take in a beacon from the coordinator, which started
+    /// going out at `start`.
+    pub fn received(&mut self, payload: &[u8], start: Instant) {
+        if self.coordinator {
+            warn!("Heard a TDMA beacon from another coordinator; ignoring it");
+            return;
+        }
+        if payload.len() < 4 {
+            debug!("Ignoring short TDMA beacon");
+            return;
+        }
+        let slot = Duration::from_millis(u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]).into());
+        let members = &payload[4..];
+        let index = members.iter().position(|m| *m == self.node).map(|i| i + 1);
+        let changed = slot != self.slot || members != self.members.as_slice()
+            || index != self.index || self.start.is_none();
+        self.slot = slot;
+        self.members = members.to_vec();
+        self.index = index;
+        if changed {
+            match index {
+                Some(index) => {
+                    info!("TDMA: slot {} of {}, each {:?}", index, members.len() + 1, slot);
+                    self.checkslot();
+                },
+                None => warn!("The TDMA coordinator gives this node ({}) no slot; not transmitting", self.node),
+            }
+        }
+        self.start = Some(start);
+        self.missed = 0;
+    }
+
+    // On a member, carry the schedule on past beacons that were missed.
+    fn advance(&mut self) {
+        if self.coordinator {
+            return;
+        }
+        if let Some(start) = self.start {
+            let period = self.period();
+            if period.is_zero() {
+                return;
+            }
+            let elapsed = start.elapsed();
+            if elapsed >= period {
+                let missed = (elapsed.as_nanos() / period.as_nanos()) as u32;
+                self.missed += missed;
+                if self.missed > MAXMISSED {
+                    warn!("Missed {} TDMA beacons; not transmitting until one is heard", self.missed);
+                    self.start = None;
+                } else {
+                    debug!("Missed {} TDMA beacon(s); keeping to the schedule", missed);
+                    self.start = Some(start + period * missed);
+                }
+            }
+        }
+    }
+
+    /// How long to wait before a frame may be sent; None if one may be
+    /// sent now.
+    pub fn wait(&mut self) -> Option<Duration> {
+        if self.beacondue() {
+            return None;
+        }
+        self.advance();
+        let (start, index) = match (self.start, self.index) {
+            (Some(start), Some(index)) => (start, index),
+            _ => return Some(UNSYNCEDWAIT),
+        };
+        let now = Instant::now();
+        // The coordinator's slot starts with the beacon, which needs no
+        // guard time.
+        let guard = if index == 0 { Duration::ZERO } else { self.guard };
+        let begin = start + self.slot * index as u32 + guard;
+        let end = start + self.slot * (index as u32 + 1) - self.guard;
+        if now < begin {
+            Some(begin - now)
+        } else if now + self.txtime <= end {
+            None
+        } else if self.coordinator {
+            Some((start + self.period()).saturating_duration_since(now + self.lead))
+        } else {
+            Some(begin + self.period() - now)
+        }
+    }
+
+    /// On the coordinator, how long until the next beacon is due.
+    pub fn deadline(&self) -> Option<Duration> {
+        if !self.coordinator {
+            return None;
+        }
+        let start = self.start?;
+        Some((start + self.period()).saturating_duration_since(Instant::now() + self.lead))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SLOT: Duration = Duration::from_millis(1000);
+    const GUARD: Duration = Duration::from_millis(100);
+    const TXTIME: Duration = Duration::from_millis(200);
+
+    fn coordinator() -> Tdma {
+        let config = TdmaConfig { members: vec![2], slot: Some(SLOT), guard: GUARD };
+        let mut tdma = Tdma::new(&config, 1, Duration::ZERO);
+        tdma.tune(TXTIME);
+        tdma
+    }
+
+    // Node 2, which has the second slot, having heard a beacon that
+    // started `ago`.
+    fn member(ago: Duration) -> Tdma {
+        let config = TdmaConfig { guard: GUARD, ..TdmaConfig::default() };
+        let mut tdma = Tdma::new(&config, 2, Duration::ZERO);
+        tdma.tune(TXTIME);
+        tdma.received(&coordinator().beacon(), Instant::now() - ago);
+        tdma
+    }
+
+    fn between(wait: Option<Duration>, least: u64, most: u64) -> bool {
+        wait.is_some_and(|w| w >= Duration::from_millis(least) && w <= Duration::from_millis(most))
+    }
+
+    #[test]
+    fn beacondue() {
+        let mut tdma = coordinator();
+        assert!(tdma.beacondue());
+        assert_eq!(tdma.wait(), None);
+        tdma.beaconsent();
+        assert!(!tdma.beacondue());
+        assert!(between(tdma.deadline(), 1900, 2000));
+        assert!(!member(Duration::ZERO).beacondue());
+        assert_eq!(member(Duration::ZERO).deadline(), None);
+    }
+
+    #[test]
+    fn received() {
+        let tdma = member(Duration::ZERO);
+        assert_eq!((tdma.slot, &tdma.members[..], tdma.index), (SLOT, &[2][..], Some(1)));
+        assert_eq!(tdma.period(), SLOT * 2);
+
+        // A member the coordinator doesn't list gets no slot.
+        let mut other = Tdma::new(&TdmaConfig::default(), 3, Duration::ZERO);
+        other.received(&coordinator().beacon(), Instant::now());
+        assert_eq!(other.index, None);
+        assert_eq!(other.wait(), Some(UNSYNCEDWAIT));
+
+        // Short beacons, and beacons heard by a coordinator, are ignored.
+        let mut unsynced = Tdma::new(&TdmaConfig::default(), 2, Duration::ZERO);
+        unsynced.received(&[0, 0, 3], Instant::now());
+        assert_eq!(unsynced.start, None);
+        let mut tdma = coordinator();
+        tdma.received(&[0, 0, 1, 0], Instant::now());
+        assert_eq!(tdma.slot, SLOT);
+    }
+
+    #[test]
+    fn wait() {
+        // Not synchronized yet.
+        let mut tdma = Tdma::new(&TdmaConfig::default(), 2, Duration::ZERO);
+        assert_eq!(tdma.wait(), Some(UNSYNCEDWAIT));
+        // Before the slot, until the guard time at its start has passed.
+        assert!(between(member(Duration::ZERO).wait(), 1000, 1100));
+        // Within it.
+        assert_eq!(member(Duration::from_millis(1200)).wait(), None);
+        // Too near its end for a frame: until the slot in the next superframe.
+        assert!(between(member(Duration::from_millis(1800)).wait(), 1200, 1300));
+    }
+
+    #[test]
+    fn advance() {
+        // Beacons missed: the schedule carries on from the last one heard.
+        let mut tdma = member(Duration::from_millis(2 * 2000 + 1200));
+        assert_eq!(tdma.wait(), None);
+        assert_eq!(tdma.missed, 2);
+        // Too many: stop transmitting until one is heard.
+        let mut tdma = member(Duration::from_millis((MAXMISSED as u64 + 1) * 2000 + 1200));
+        assert_eq!(tdma.wait(), Some(UNSYNCEDWAIT));
+        assert_eq!(tdma.start, None);
+        tdma.received(&coordinator().beacon(), Instant::now() - Duration::from_millis(1200));
+        assert_eq!(tdma.wait(), None);
+    }
+}
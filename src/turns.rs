@@ -0,0 +1,941 @@
+/*
+    Copyright (C) 2019  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+*/
+
+use crate::adr::{self, Adr, AdrConfig, Message, Modulation, TpcConfig, CONTROLRESERVE};
+use crate::airtime;
+use crate::arq::{Arq, ARQRESERVE};
+use crate::datagram::{Packer, Reassembler};
+use crate::dutycycle::{DutyCycle, DUTYWINDOW};
+use crate::fec::{FecDecoder, FecEncoder, FECRESERVE};
+use crate::frame::{Fec, FrameType, Framing, Header, Sequence, Turn, ADDRRESERVE, BROADCAST};
+use crate::lorastik::{LbtConfig, LinkConfig, LinkStats};
+use crate::radioconfig::RadioSettings;
+use crate::region;
+use crate::tdma::Tdma;
+use log::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The eotwait used for an automatic one until the radio settings are known.
+const FALLBACKEOTWAIT: Duration = Duration::from_millis(1000);
+
+/// What a received frame brought.
+pub(crate) struct Received {
+    /// The payloads ready to be handed on: its own, and with reliable
+    /// delivery, any held back waiting for it.
+    pub payloads: Vec<Vec<u8>>,
+    /// The power it was sent at, if the other end said.
+    pub power: Option<i8>,
+    /// Its sender's address, if it has one.
+    pub sender: Option<u8>,
+    /// Whether the other end is giving us a turn, and something should be
+    /// sent immediately.
+    pub ourturn: bool,
+}
+
+/// What is kept for the frames exchanged with one other node.
+#[derive(Clone, Default)]
+struct Peer {
+    // Reliable delivery, if on.
+    arq: Option<Arq>,
+    // Forward error correction of frames received.
+    fecrx: FecDecoder,
+    // The messages being received, and whether it last sent datagrams.
+    reassembler: Reassembler,
+    rxdatagram: bool,
+}
+
+impl Peer {
+    fn new(reliable: bool, rto: Duration) -> Peer {
+        Peer { arq: if reliable { Some(Arq::new(rto)) } else { None }, ..Peer::default() }
+    }
+
+    // The messages in received payloads, if it sends datagrams.
+    fn unpack(&mut self, payloads: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        if self.rxdatagram {
+            payloads.iter().flat_map(|p| self.reassembler.received(p)).collect()
+        } else {
+            payloads
+        }
+    }
+}
+
+/// The turn-taking state of a link: what is waiting to be sent, and when
+/// we may send it.  This does no I/O, so the blocking and async links
+/// can share it.
+#[derive(Clone)]
+pub(crate) struct Turns {
+    // The wait before transmitting.
+    pub txwait: Duration,
+
+    // The transmit prevention timeout.
+    eotwait: Duration,
+
+    // Whether eotwait is to be worked out from the radio settings.
+    autoeotwait: bool,
+
+    // The maximum transmit time.
+    txslot: Option<Duration>,
+
+    // Extra data, to send before the next frame.
+    extradata: Vec<u8>,
+
+    // Maximum packet size
+    maxpacketsize: usize,
+
+    // The header to send, and whether the other end has been heard
+    // sending legacy frames when we don't.
+    framing: Framing,
+    mismatchwarned: bool,
+
+    // Whether or not to always try to cram as much as possible into each TX frame
+    pack: bool,
+
+    // Whether we must delay before transmit.  The Instant
+    // reflects the moment when the delay should end.
+    txdelay: Option<Instant>,
+
+    // When the current TX slot ends, if any.
+    txslotend: Option<Instant>,
+
+    // The duty cycle limit asked for; None for the band's legal one.
+    dutylimit: Option<f64>,
+
+    // The transmissions counting against the duty cycle, if limited.
+    dutycycle: Option<DutyCycle>,
+
+    // The radio settings, once known, for working out airtime.
+    settings: Option<RadioSettings>,
+
+    // Listen-before-talk settings, and the longest backoff in effect.
+    lbt: Option<LbtConfig>,
+    lbtbackoff: Duration,
+
+    // For choosing backoff times.
+    rng: u64,
+
+    // Shared by all clones.
+    stats: Arc<Mutex<LinkStats>>,
+
+    // Adaptive data rate and transmit power control settings, and their
+    // state once the radio settings are known.
+    adrconfig: Option<AdrConfig>,
+    tpcconfig: Option<TpcConfig>,
+    adr: Option<Adr>,
+
+    // Whether delivery is reliable, the sequence number of the frame being
+    // sent again, if it is one, and the node an acknowledgment-only frame
+    // being sent is for, if it is one.
+    reliable: bool,
+    resending: Option<Sequence>,
+    ackto: Option<u8>,
+
+    // Forward error correction of frames sent, if on, and the position of
+    // the parity frame being sent, if it is one.
+    fec: Option<FecEncoder>,
+    parity: Option<Fec>,
+
+    // In datagram mode, the messages waiting to be sent, and a payload
+    // that couldn't be; and how long to wait for the fragments of those
+    // being received.
+    packer: Option<Packer>,
+    unsent: Option<Vec<u8>>,
+    fragtimeout: Duration,
+
+    // The state kept for each node heard from, by address: None for
+    // frames without one, and for all frames if this node has none.  The
+    // entry for the destination also covers reliable delivery of what is
+    // sent to it.
+    peers: HashMap<Option<u8>, Peer>,
+
+    // This node's address, if frames are addressed, and where they go.
+    node: Option<u8>,
+    dest: u8,
+
+    // Time-division media access, if on, and whether the frame being sent
+    // is a beacon.
+    tdma: Option<Tdma>,
+    beacon: bool,
+
+    // Whether the frame being sent has been built, and so counted as sent
+    // by reliable delivery and forward error correction.
+    built: bool,
+}
+
+impl Turns {
+    pub fn new(config: &LinkConfig) -> Turns {
+        let (adrconfig, tpcconfig) = match config.framing {
+            Framing::Legacy if config.adr.is_some() || config.tpc.is_some() => {
+                warn!("Legacy frames can't carry link reports; not adapting the data rate or power");
+                (None, None)
+            },
+            _ => (config.adr.clone(), config.tpc.clone()),
+        };
+        let adrtimeout = adrconfig.as_ref().map(|a| a.timeout)
+            .or_else(|| tpcconfig.as_ref().map(|_| AdrConfig::default().timeout));
+        let node = match (config.node, config.framing) {
+            (Some(_), Framing::Legacy) => {
+                warn!("Legacy frames can't carry addresses; sending them unaddressed");
+                None
+            },
+            (node, _) => node,
+        };
+        let reliable = match (config.reliable, config.framing) {
+            (true, Framing::Legacy) => {
+                warn!("Legacy frames can't carry sequence numbers; not delivering reliably");
+                false
+            },
+            (reliable, _) => reliable,
+        };
+        let mut peers = HashMap::new();
+        peers.insert(node.map(|_| config.dest), Peer::new(reliable, FALLBACKEOTWAIT * 2));
+        Turns { txwait: config.txwait,
+                eotwait: config.eotwait.unwrap_or(FALLBACKEOTWAIT),
+                autoeotwait: config.eotwait.is_none(),
+                // ADR and TPC need the other end to get a turn to report back.
+                txslot: config.txslot.or_else(|| adrtimeout.map(|t| t / 3)),
+                extradata: vec![],
+                maxpacketsize: config.maxpacketsize,
+                framing: config.framing,
+                mismatchwarned: false,
+                pack: config.pack,
+                txdelay: None,
+                txslotend: None,
+                dutylimit: config.dutycycle,
+                dutycycle: None,
+                settings: None,
+                lbt: config.lbt.clone(),
+                lbtbackoff: config.lbt.as_ref().and_then(|l| l.backoff).unwrap_or(FALLBACKEOTWAIT),
+                rng: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(1) | 1,
+                stats: Arc::new(Mutex::new(LinkStats::default())),
+                adrconfig,
+                tpcconfig,
+                adr: None,
+                reliable,
+                resending: None,
+                ackto: None,
+                fec: match (&config.fec, config.framing) {
+                    (None, _) => None,
+                    (Some(_), Framing::Legacy) => {
+                        warn!("Legacy frames can't carry FEC groups; not sending parity");
+                        None
+                    },
+                    (Some(_), _) if config.reliable => {
+                        warn!("FEC and reliable delivery can't be used together; not sending parity");
+                        None
+                    },
+                    (Some(fec), _) => Some(FecEncoder::new(fec)),
+                },
+                parity: None,
+                packer: match (config.datagram, config.framing) {
+                    (false, _) => None,
+                    (true, Framing::Legacy) => {
+                        warn!("Legacy frames can't carry datagrams; sending a stream");
+                        None
+                    },
+                    (true, _) => Some(Packer::default()),
+                },
+                unsent: None,
+                fragtimeout: config.fragtimeout,
+                peers,
+                node,
+                dest: config.dest,
+                tdma: match (&config.tdma, node) {
+                    (None, _) => None,
+                    (Some(_), None) => {
+                        warn!("TDMA needs this node to have an address; taking turns instead");
+                        None
+                    },
+                    (Some(_), _) if config.adr.is_some() => {
+                        warn!("TDMA and adaptive data rate can't be used together; taking turns instead");
+                        None
+                    },
+                    (Some(tdma), Some(node)) => Some(Tdma::new(tdma, node, config.txwait)),
+                },
+                beacon: false,
+                built: false }
+    }
+
+    /// Fit the timings to the radio settings: work out eotwait if it is
+    /// automatic, and warn about configured times too short for a frame.
+    pub fn tune(&mut self, settings: &RadioSettings) {
+        if self.adrconfig.is_some() || self.tpcconfig.is_some() {
+            if self.adrconfig.is_some() {
+                info!("Adaptive data rate on, starting from {}", Modulation::of(settings));
+            }
+            if let Some(tpc) = &self.tpcconfig {
+                info!("Transmit power control on, starting from {} dBm, keeping a {} dB margin", settings.pwr, tpc.margin);
+            }
+            self.adr = Some(Adr::new(self.adrconfig.as_ref(), self.tpcconfig.as_ref(), settings));
+        }
+        let framelen = self.framelen(self.payloadmax());
+        let frametime = airtime::airtime(settings, framelen);
+        info!("A {}-byte frame takes {:?} on the air", framelen, frametime);
+
+        if self.autoeotwait {
+            self.eotwait = airtime::autoeotwait(settings, self.txwait, framelen);
+            info!("eotwait set to {:?}", self.eotwait);
+        } else if self.eotwait < frametime {
+            warn!("eotwait of {:?} is shorter than the {:?} a {}-byte frame takes on the air; \
+                   this end may transmit over the other", self.eotwait, frametime, framelen);
+        }
+        if let Some(tdma) = &mut self.tdma {
+            let lbtwindow = self.lbt.as_ref().map(|l| l.window).unwrap_or_default();
+            tdma.tune(self.txwait + lbtwindow + frametime);
+        }
+        if self.reliable {
+            self.setrto();
+            info!("Delivering reliably; retransmit timeout {:?}", self.rto());
+        }
+        if let Some(txslot) = self.txslot {
+            if txslot < frametime {
+                warn!("txslot of {:?} is shorter than the {:?} a {}-byte frame takes on the air; \
+                       the other end will get a turn after every frame", txslot, frametime, framelen);
+            }
+        }
+
+        match self.dutylimit.or_else(|| region::dutycycle(settings.freq)) {
+            Some(limit) if limit < 1.0 => {
+                let dutycycle = DutyCycle::new(limit.max(0.0), DUTYWINDOW);
+                info!("Duty cycle limited to {}%: {:?} of transmitting per {:?}",
+                      limit * 100.0, dutycycle.budget(), DUTYWINDOW);
+                if dutycycle.budget() < frametime {
+                    warn!("A {}-byte frame takes {:?} on the air, more than the duty cycle allows in {:?}",
+                          framelen, frametime, DUTYWINDOW);
+                }
+                self.dutycycle = Some(dutycycle);
+            },
+            _ => self.dutycycle = None,
+        }
+        if let Some(lbt) = &self.lbt {
+            if lbt.backoff.is_none() {
+                self.lbtbackoff = frametime;
+            }
+            info!("Listening before talking: busy at {} dBm, backing off up to {:?}",
+                  lbt.threshold, self.lbtbackoff);
+        }
+        self.settings = Some(settings.clone());
+    }
+
+    // The retransmit timeout: long enough for the other end to give up
+    // waiting on a lost last frame, and answer; or, with TDMA, to have
+    // its slot.
+    fn rto(&self) -> Duration {
+        self.tdma.as_ref().map(|t| t.period()).filter(|p| !p.is_zero())
+            .unwrap_or(self.eotwait * 2)
+    }
+
+    // Bring the retransmit timeout of every node up to date.
+    fn setrto(&mut self) {
+        let rto = self.rto();
+        for arq in self.peers.values_mut().filter_map(|p| p.arq.as_mut()) {
+            arq.setrto(rto);
+        }
+    }
+
+    // The key in `peers` of the node frames are sent to.
+    fn destkey(&self) -> Option<u8> {
+        self.node.map(|_| self.dest)
+    }
+
+    // Reliable delivery with the node frames are sent to, if on.
+    fn arq(&self) -> Option<&Arq> {
+        self.peers.get(&self.destkey()).and_then(|p| p.arq.as_ref())
+    }
+
+    // Another node that is owed an acknowledgment, if any.  The
+    // destination gets its own with the frames sent to it.
+    fn ackowed(&self) -> Option<u8> {
+        let destkey = self.destkey();
+        self.peers.iter()
+            .filter(|(key, p)| **key != destkey && p.arq.as_ref().map(|a| a.ackdue()).unwrap_or(false))
+            .find_map(|(key, _)| *key)
+    }
+
+    /// How long to listen before transmitting, if listen-before-talk is on.
+    pub fn lbtwindow(&self) -> Option<Duration> {
+        self.lbt.as_ref().map(|l| l.window)
+    }
+
+    /// Decide from a listen before talking whether the channel is clear.
+    /// `heard` says whether anything was received while listening.  If the
+    /// channel is busy, transmitting is held off for a random time.
+    pub fn channelclear(&mut self, rssi: i32, heard: bool) -> bool {
+        let threshold = match &self.lbt {
+            Some(lbt) => lbt.threshold,
+            None => return true,
+        };
+        if rssi < threshold && !heard {
+            trace!("Channel clear at {} dBm", rssi);
+            self.stats.lock().unwrap().lbtchecks += 1;
+            return true;
+        }
+
+        let backoff = self.lbtbackoff.mul_f64(self.random());
+        let mut stats = self.stats.lock().unwrap();
+        stats.lbtchecks += 1;
+        stats.lbtbackoffs += 1;
+        stats.lbtbackofftime += backoff;
+        info!("Channel busy ({} dBm{}); backing off for {:?} ({} of {} checks busy, {:?} in all)",
+              rssi, if heard { ", heard a frame" } else { "" }, backoff,
+              stats.lbtbackoffs, stats.lbtchecks, stats.lbtbackofftime);
+        drop(stats);
+        // Don't cut short a longer wait, such as for the other end's turn.
+        let until = Instant::now() + backoff;
+        if self.txdelay.map(|t| t < until).unwrap_or(true) {
+            self.txdelay = Some(until);
+        }
+        false
+    }
+
+    /// xorshift64*, in [0, 1).  Good enough for spreading out backoffs.
+    fn random(&mut self) -> f64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        (self.rng.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// The counters kept by the link, shared with its clones.
+    pub fn sharedstats(&self) -> Arc<Mutex<LinkStats>> {
+        self.stats.clone()
+    }
+
+    /// Whether a frame of `len` bytes may be sent now without exceeding
+    /// the duty cycle limit.  If it may, it is counted against the limit;
+    /// if not, transmitting is held off until it fits.
+    pub fn dutyclear(&mut self, len: usize) -> bool {
+        let (dutycycle, settings) = match (&mut self.dutycycle, &self.settings) {
+            (Some(dutycycle), Some(settings)) => (dutycycle, settings),
+            _ => return true,
+        };
+        let frametime = airtime::airtime(settings, len);
+        match dutycycle.waitfor(frametime) {
+            None => {
+                dutycycle.record(frametime);
+                true
+            },
+            Some(wait) => {
+                info!("Duty cycle budget used up ({:?} left); holding transmissions for {:?}",
+                      dutycycle.remaining(), wait);
+                self.txdelay = Some(Instant::now() + wait);
+                false
+            },
+        }
+    }
+
+    /// Whether data is left over from the last frame.
+    pub fn hasextra(&self) -> bool {
+        !self.extradata.is_empty() || self.unsent.is_some()
+            || self.packer.as_ref().map(|p| !p.is_empty()).unwrap_or(false)
+    }
+
+    // Keep `data` to go out in a later frame.
+    fn hold(&mut self, mut data: Vec<u8>) {
+        match &mut self.packer {
+            Some(packer) => packer.push(data),
+            None => self.extradata.append(&mut data),
+        }
+    }
+
+    /// Whether there is anything to send: leftover data that may be sent,
+    /// adaptive data rate messages, an acknowledgment for any node, a
+    /// frame to send again, or parity for the frames just sent.
+    pub fn pending(&self) -> bool {
+        (self.hasextra() && self.cansend())
+            || self.adr.as_ref().map(|a| a.hascontrol()).unwrap_or(false)
+            || self.peers.values().any(|p| p.arq.as_ref().map(|a| a.ackdue()).unwrap_or(false))
+            || self.arq().map(|a| a.retransmitdue()).unwrap_or(false)
+            || self.fec.as_ref().map(|f| f.ingroup() || f.paritywaiting()).unwrap_or(false)
+            || self.tdma.as_ref().map(|t| t.beacondue()).unwrap_or(false)
+    }
+
+    /// Whether new data may be sent, or reliable delivery must wait for
+    /// what has been sent to be acknowledged first.
+    pub fn cansend(&self) -> bool {
+        self.arq().map(|a| a.cansend()).unwrap_or(true)
+    }
+
+    /// The most a frame carrying `payload` bytes can take, with room for
+    /// every optional header field in use.
+    pub fn framelen(&self, payload: usize) -> usize {
+        payload + self.framing.overhead() + self.reserve()
+    }
+
+    /// Whether the SNR of every received frame is needed.
+    pub fn wantsqual(&self) -> bool {
+        self.adr.is_some()
+    }
+
+    /// The header room kept for addresses, adaptive data rate messages,
+    /// reliable delivery, and forward error correction.
+    fn reserve(&self) -> usize {
+        let mut reserve = 0;
+        if self.node.is_some() {
+            reserve += ADDRRESERVE;
+        }
+        if self.adr.is_some() {
+            reserve += CONTROLRESERVE;
+        }
+        if self.reliable {
+            reserve += ARQRESERVE;
+        }
+        if self.fec.is_some() {
+            reserve += FECRESERVE;
+        }
+        reserve
+    }
+
+    /// The most payload a frame can carry, leaving room for the optional
+    /// header fields.
+    fn payloadmax(&self) -> usize {
+        self.maxpacketsize.saturating_sub(self.reserve()).max(1)
+    }
+
+    /// Build the payload of the next frame from leftover data, `data`, and
+    /// as many further blocks from `next` as will fit.  With reliable
+    /// delivery, a frame due to be sent again comes first, and nothing new
+    /// is taken while the window is full.  With forward error correction,
+    /// parity comes first, and an FEC group is finished if there is
+    /// nothing more to send.  A TDMA beacon that is due comes before all,
+    /// then an acknowledgment owed to a node other than the destination.
+    pub fn fill<F: FnMut() -> Option<Vec<u8>>>(&mut self, data: Vec<u8>, next: F) -> Vec<u8> {
+        self.built = false;
+        self.beacon = self.tdma.as_ref().map(|t| t.beacondue()).unwrap_or(false);
+        if self.beacon {
+            let payload = self.tdma.as_ref().map(|t| t.beacon()).unwrap_or_default();
+            self.hold(data);
+            return payload;
+        }
+        self.ackto = self.ackowed();
+        if self.ackto.is_some() {
+            self.hold(data);
+            return vec![];
+        }
+        let destkey = self.destkey();
+        if let Some(arq) = self.peers.get_mut(&destkey).and_then(|p| p.arq.as_mut()) {
+            if let Some((seq, payload)) = arq.retransmission() {
+                self.resending = Some(seq);
+                self.hold(data);
+                return payload;
+            }
+            if !arq.cansend() {
+                self.hold(data);
+                return vec![];
+            }
+        }
+        if let Some((fec, payload)) = self.fec.as_mut().and_then(|f| f.nextparity()) {
+            self.parity = Some(fec);
+            self.hold(data);
+            return payload;
+        }
+        let data = if self.packer.is_some() {
+            self.filldatagrams(data, next)
+        } else {
+            self.filldata(data, next)
+        };
+        if let Some(fec) = &mut self.fec {
+            if data.is_empty() && fec.ingroup() {
+                fec.finish();
+                if let Some((fec, payload)) = fec.nextparity() {
+                    self.parity = Some(fec);
+                    return payload;
+                }
+            }
+        }
+        data
+    }
+
+    // Fill the payload of a new frame with as many queued messages as
+    // will fit.
+    fn filldatagrams<F: FnMut() -> Option<Vec<u8>>>(&mut self, data: Vec<u8>, mut next: F) -> Vec<u8> {
+        if let Some(payload) = self.unsent.take() {
+            self.hold(data);
+            return payload;
+        }
+        let max = self.payloadmax();
+        let packer = match &mut self.packer {
+            Some(packer) => packer,
+            None => return vec![],
+        };
+        packer.push(data);
+        let mut payload = vec![];
+        loop {
+            if packer.is_empty() {
+                match next() {
+                    Some(message) => packer.push(message),
+                    None => break,
+                }
+            }
+            if !packer.pack(&mut payload, max) {
+                break;
+            }
+        }
+        payload
+    }
+
+    // Fill the payload of a new frame.
+    fn filldata<F: FnMut() -> Option<Vec<u8>>>(&mut self, data: Vec<u8>, mut next: F) -> Vec<u8> {
+        let mut tosend = vec![];
+        tosend.append(&mut self.extradata);   // drains self.extradata!
+        tosend.append(&mut data.clone());
+        let mut data = tosend;                // hide the original 'data'
+        let maxpacketsize = self.payloadmax();
+
+        if data.len() > maxpacketsize {
+            self.extradata = data.split_off(maxpacketsize);
+        }
+        
+        while data.len() < maxpacketsize && self.extradata.is_empty() {
+            // Consider the next packet - maybe we can combine it with this one.
+            match next() {
+                Some(mut next) => {
+                    if self.pack {
+                        // Try to fill up the frame.
+                        data.append(&mut next);
+                        if data.len() > maxpacketsize {
+                            // Too much; put the extra into extradata.
+                            self.extradata = data.split_off(maxpacketsize);
+                            break;  // for clarity only -- would exit the loop anyhow
+                        }
+                    } else {
+                        // Only append the extra if it will fit entirely in the frame.
+                        if data.len() + next.len() <= maxpacketsize {
+                            data.append(&mut next);
+                        } else {
+                            self.extradata.append(&mut next);
+                            break;  // for clarity only -- would exit the loop anyhow
+                        }
+                    }
+                },
+                // Nothing more to send right now.
+                None => break,
+            }
+        }
+        data
+    }
+
+    /// The turn for the frame about to be sent with payload `data`.
+    /// `queued` says whether more blocks are waiting to be sent after it.
+    pub fn txturn(&mut self, queued: bool, data: &[u8]) -> Turn {
+        let mut turn = Turn::Done;
+        // With the window full after this frame, let the other end
+        // acknowledge straight away.
+        let new = !data.is_empty() && self.resending.is_none();
+        let room = self.arq().map(|a| a.room() > usize::from(new)).unwrap_or(true);
+        let resend = self.arq().map(|a| a.resendwaiting()).unwrap_or(false);
+        // A data frame is followed by parity, sooner or later.
+        let parity = self.fec.as_ref()
+            .map(|f| f.ingroup() || f.paritywaiting() || (self.parity.is_none() && !data.is_empty()))
+            .unwrap_or(false);
+        if resend || ((queued || self.hasextra() || parity) && room) {
+            // If there will be more data to send..
+            turn = Turn::More;
+
+            // See if we need to signal the other end's turn.
+            match (self.txslotend, self.txslot) {
+                (None, Some(txslot)) => self.txslotend = Some(Instant::now() + txslot),
+                (Some(txslotend), _) if Instant::now() > txslotend => {
+                    debug!("txslot exceeded; setting txdelay and yielding");
+                    turn = Turn::Yield;
+                    self.txdelay = Some(Instant::now() + self.eotwait);
+                    self.txslotend = None;
+                },
+                _ => ()
+            }
+        } else {
+            self.txslotend = None;
+        }
+        turn
+    }
+
+    /// The frame to send: the header, with any adaptive data rate
+    /// messages, reliable delivery fields, and FEC group, and the payload.
+    pub fn buildframe(&mut self, turn: Turn, data: &[u8]) -> Vec<u8> {
+        let mut header = Header::new(turn);
+        self.built = true;
+        if self.beacon {
+            header.kind = FrameType::Beacon;
+            header.addr = self.node.map(|node| (node, BROADCAST));
+            if let Some(tdma) = &mut self.tdma {
+                tdma.beaconsent();
+            }
+            return header.encode(self.framing, data);
+        }
+        if let Some(to) = self.ackto.take() {
+            header.addr = self.node.map(|node| (node, to));
+            header.ack = self.peers.get_mut(&Some(to)).and_then(|p| p.arq.as_mut()).and_then(|a| a.ack());
+            return header.encode(self.framing, data);
+        }
+        header.datagram = self.packer.is_some();
+        header.addr = self.node.map(|node| (node, self.dest));
+        if let Some(fec) = &mut self.fec {
+            match self.parity.take() {
+                Some(parity) => {
+                    header.kind = FrameType::Parity;
+                    header.fec = Some(parity);
+                },
+                None if !data.is_empty() => header.fec = Some(fec.add(data)),
+                None => (),
+            }
+        }
+        header.control = self.adr.as_mut().map(|a| a.control()).unwrap_or_default();
+        let destkey = self.destkey();
+        if let Some(arq) = self.peers.get_mut(&destkey).and_then(|p| p.arq.as_mut()) {
+            if self.resending.is_some() {
+                self.stats.lock().unwrap().retransmissions += 1;
+            }
+            header.seq = self.resending.take().or_else(|| {
+                if data.is_empty() { None } else { Some(arq.newframe(data)) }
+            });
+            header.ack = arq.ack();
+            arq.transmitted();
+        }
+        header.encode(self.framing, data)
+    }
+
+    /// A frame could not be sent.  Put its payload back at the head of the
+    /// queue so it goes out once the radio is back.  A frame being sent
+    /// again, or a parity frame, stays where it is, to be sent later.  A
+    /// frame already built is lost, as if on the air, to reliable delivery
+    /// and forward error correction, which recover it as such.
+    pub fn putback(&mut self, mut data: Vec<u8>) {
+        if self.built && (self.reliable || self.fec.is_some()) {
+            self.beacon = false;
+            return;
+        }
+        if self.beacon {
+            // It goes out again while it is due.
+            self.beacon = false;
+            return;
+        }
+        if let Some(parity) = self.parity.take() {
+            if let Some(fec) = &mut self.fec {
+                fec.putback(parity, data);
+            }
+            return;
+        }
+        if let Some(seq) = self.resending.take() {
+            let destkey = self.destkey();
+            if let Some(arq) = self.peers.get_mut(&destkey).and_then(|p| p.arq.as_mut()) {
+                arq.notsent(seq);
+            }
+            return;
+        }
+        if self.packer.is_some() {
+            if !data.is_empty() {
+                self.unsent = Some(data);
+            }
+            return;
+        }
+        data.append(&mut self.extradata);
+        self.extradata = data;
+    }
+
+    /// Take in a received frame, with the SNR it was received at if
+    /// known.  None if the frame can't be understood or is for another
+    /// node.
+    pub fn rxframe(&mut self, frame: Vec<u8>, snr: Option<&str>) -> Option<Received> {
+        let (header, framing, payload) = Header::decode(&frame)?;
+        if let (Some(node), Some((src, dst))) = (self.node, header.addr) {
+            if dst != node && dst != BROADCAST {
+                debug!("Dropping frame from {} for {}", src, dst);
+                // Don't talk over them, but don't take a turn from them
+                // either.  With TDMA, the schedule keeps them apart.
+                if header.turn != Turn::Done && self.tdma.is_none() {
+                    let end = Instant::now() + self.eotwait;
+                    self.txdelay = Some(self.txdelay.map_or(end, |t| t.max(end)));
+                }
+                return None;
+            }
+        }
+        if framing == Framing::Legacy && self.framing != Framing::Legacy && !self.mismatchwarned {
+            warn!("The other end sends legacy frames; if it runs an older lorapipe, \
+                   it can't understand this end without --framing legacy");
+            self.mismatchwarned = true;
+        } else if framing != Framing::Legacy && self.framing == Framing::Legacy && !self.mismatchwarned {
+            info!("The other end sends v1 frames; once every radio understands them, \
+                   --framing v1 can be used here too");
+            self.mismatchwarned = true;
+        }
+        let msgs = adr::parse(&header.control);
+        let power = msgs.iter().find_map(|m| match m {
+            Message::Power(power) => Some(*power),
+            _ => None,
+        });
+        let key = self.node.and(header.addr).map(|(src, _)| src);
+        let fromdest = key == self.destkey();
+        if let Some(adr) = &mut self.adr {
+            // The modulation suits the link to the destination only.
+            if fromdest {
+                adr.received(&msgs, snr.and_then(|s| s.parse().ok()));
+            }
+        }
+        if header.kind == FrameType::Beacon {
+            if let (Some(tdma), Some(settings)) = (&mut self.tdma, &self.settings) {
+                let now = Instant::now();
+                tdma.received(payload, now.checked_sub(airtime::airtime(settings, frame.len())).unwrap_or(now));
+                self.setrto();
+            }
+        }
+        let (reliable, rto) = (self.reliable, self.rto());
+        let peer = self.peers.entry(key).or_insert_with(|| Peer::new(reliable, rto));
+        let payloads = match (header.fec, &mut peer.arq) {
+            (Some(fec), _) => peer.fecrx.received(header.kind, fec, payload.to_vec()),
+            _ if header.kind != FrameType::Data => vec![],
+            (None, Some(arq)) => arq.received(header.seq, header.ack, payload.to_vec()),
+            (None, None) => vec![payload.to_vec()],
+        };
+        peer.rxdatagram = header.datagram;
+        let payloads = peer.unpack(payloads);
+        Some(Received { payloads,
+                        power,
+                        sender: header.addr.map(|(src, _)| src),
+                        ourturn: self.rxturn(header.turn) })
+    }
+
+    /// Note the turn of a received frame.  Returns true if the other end
+    /// is giving us a turn and something should be sent immediately.
+    fn rxturn(&mut self, turn: Turn) -> bool {
+        if self.tdma.is_some() {
+            // Slots, not turns, say when to transmit.
+            return false;
+        }
+        if turn == Turn::More {
+            // More data is coming
+            self.txdelay = Some(Instant::now() + self.eotwait);
+        } else {
+            self.txdelay = None;
+        }
+        debug!("handlerx: txdelay set to {:?}", self.txdelay);
+        turn == Turn::Yield && self.txslot.is_some()
+    }
+
+    // Whether or not a txdelay prevents transmit at this time.  None if
+    // we are cleared to transmit; Some(Duration) gives the amount of time
+    // we'd have to wait otherwise.  With TDMA, the wait for this node's
+    // slot counts too, whichever ends later.
+    pub fn txdelayrequired(&mut self) -> Option<Duration> {
+        let slotwait = self.tdma.as_mut().and_then(|tdma| tdma.wait());
+        if self.tdma.is_some() {
+            debug!("txdelayrequired: waiting {:?} for this node's TDMA slot", slotwait);
+        }
+        debug!("txdelayrequired: self.txdelay = {:?}", self.txdelay);
+        let delay = match self.txdelay {
+            None => None,
+            Some(delayend) => {
+                let now = Instant::now();
+                if now >= delayend {
+                    // We're past the delay.  Clear it and return.
+                    debug!("txdelayrequired: {:?} past the required delay {:?}", now, delayend);
+                    self.txdelay = None;
+                    None
+                } else {
+                    // Indicate we're still blocked.
+                    debug!("txdelayrequired: required delay {:?}", delayend - now);
+                    Some(delayend - now)
+                }
+            }
+        };
+        slotwait.max(delay)
+    }
+
+    /// A change of modulation to make before the radio is next used, if
+    /// adaptive data rate calls for one.  The timings are worked out again
+    /// for it.
+    pub fn adrswitch(&mut self) -> Option<Modulation> {
+        let modulation = self.adr.as_mut()?.switch()?;
+        self.retime(&modulation);
+        Some(modulation)
+    }
+
+    /// A change of power to make before the radio is next used, if
+    /// transmit power control calls for one.  Call after
+    /// [`adrswitch`](Turns::adrswitch).
+    pub fn powerchange(&mut self) -> Option<i8> {
+        let power = self.adr.as_mut()?.powerchange()?;
+        if let Some(settings) = &mut self.settings {
+            settings.pwr = power;
+        }
+        Some(power)
+    }
+
+    // Work out the timings again for a change of modulation.
+    fn retime(&mut self, modulation: &Modulation) {
+        if let Some(settings) = &mut self.settings {
+            settings.sf = modulation.sf;
+            settings.bw = modulation.bw;
+            settings.cr = format!("4/{}", modulation.cr);
+            if self.autoeotwait {
+                self.eotwait = airtime::autoeotwait(settings, self.txwait, self.maxpacketsize + self.framing.overhead());
+                debug!("eotwait set to {:?}", self.eotwait);
+            }
+            if self.lbt.as_ref().map(|l| l.backoff.is_none()).unwrap_or(false) {
+                self.lbtbackoff = airtime::airtime(settings, self.maxpacketsize + self.framing.overhead());
+            }
+        }
+        self.setrto();
+    }
+
+    /// How long the radio may be left receiving before adaptive data rate
+    /// may need to send a keepalive or fall back to the initial settings,
+    /// a frame is due to be sent again, frames held waiting for a lost
+    /// one are to be handed on anyway, or a TDMA beacon is due.
+    pub fn deadline(&self) -> Option<Duration> {
+        let peers = self.peers.values().flat_map(|p| {
+            [p.arq.as_ref().and_then(|a| a.deadline()),
+             p.fecrx.deadline(self.eotwait * 2),
+             p.reassembler.deadline(self.fragtimeout)]
+        });
+        [self.adr.as_ref().and_then(|a| a.deadline()),
+         self.tdma.as_ref().and_then(|t| t.deadline())]
+            .iter().copied().chain(peers).flatten().min()
+    }
+
+    /// Payloads held waiting for a lost frame of an FEC group that are to
+    /// be handed on without it, no more of the group having arrived, with
+    /// the node that sent each.  Fragmented messages not completed in time
+    /// are dropped.
+    pub fn expired(&mut self) -> Vec<(Option<u8>, Vec<u8>)> {
+        let (hold, fragtimeout) = (self.eotwait * 2, self.fragtimeout);
+        self.peers.iter_mut().flat_map(|(&sender, p)| {
+            let payloads = p.fecrx.expire(hold);
+            p.reassembler.expire(fragtimeout);
+            p.unpack(payloads).into_iter().map(move |payload| (sender, payload))
+        }).collect()
+    }
+
+    /// Stop waiting for the other end.
+    pub fn cleartxdelay(&mut self) {
+        self.txdelay = None;
+    }
+
+    /// Forget the turn-taking state, as after the radio has been
+    /// reinitialized.  Data waiting to be sent is kept.
+    pub fn reset(&mut self) {
+        self.txdelay = None;
+        self.txslotend = None;
+        if let Some(adr) = &mut self.adr {
+            // The radio is back on the initial modulation and power.
+            adr.reset();
+            let safe = adr.safe().clone();
+            self.retime(&safe);
+        }
+    }
+}
@@ -0,0 +1,108 @@
+/*
+    Copyright (C) 2019  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+*/
+
+//! The async link, over radios emulated by lorapipe-sim.
+
+#![cfg(feature = "async")]
+
+use futures_core::Stream;
+use lorapipe::{AsyncLoraStik, LinkConfig, Profile, RadioInit, ReceivedFrames, ReceivedStream, Region};
+use std::fs;
+use std::future::poll_fn;
+use std::io::{BufRead, BufReader};
+use std::pin::Pin;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+/// How long to wait for everything to arrive.
+const DEADLINE: Duration = Duration::from_secs(60);
+
+/// The emulator, killed when dropped.
+struct Sim {
+    dir: std::path::PathBuf,
+    child: Child,
+}
+
+impl Sim {
+    fn new(name: &str) -> Sim {
+        let dir = std::env::temp_dir().join(format!("lorapipe-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut child = Command::new(env!("CARGO_BIN_EXE_lorapipe-sim"))
+            .arg("--link").arg(dir.join("lora"))
+            .args(["--seed", "1", "--airtime", "10", "--bytetime", "0"])
+            .stdout(Stdio::piped()).stderr(Stdio::null())
+            .spawn().unwrap();
+        // It names each radio once its link is in place.
+        let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+        for _ in 0..2 {
+            assert!(lines.next().unwrap().unwrap().starts_with("radio"));
+        }
+        std::thread::spawn(move || lines.for_each(drop));
+        Sim { dir, child }
+    }
+
+    async fn open(&self, i: usize) -> (AsyncLoraStik, ReceivedStream) {
+        let init = RadioInit::Config(Region::Us915.config(Profile::Fast).unwrap());
+        AsyncLoraStik::open(self.dir.join(format!("lora{}", i)), LinkConfig::default(), init).await.unwrap()
+    }
+}
+
+impl Drop for Sim {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+async fn next(frames: &mut ReceivedStream) -> Option<ReceivedFrames> {
+    poll_fn(|cx| Pin::new(&mut *frames).poll_next(cx)).await
+}
+
+/// Everything that comes out of `frames` until it holds `len` bytes.
+async fn collect(frames: &mut ReceivedStream, len: usize) -> Vec<u8> {
+    let mut got = vec![];
+    while got.len() < len {
+        got.extend(next(frames).await.unwrap().payload);
+    }
+    got
+}
+
+#[tokio::test]
+async fn exchange() {
+    let sim = Sim::new("async");
+    let (a, mut arx) = sim.open(0).await;
+    let (b, mut brx) = sim.open(1).await;
+    assert_eq!(a.settings().sf, 7);
+
+    let fromb: Vec<u8> = (0..5).flat_map(|i| format!("B-line {}\n", i).into_bytes()).collect();
+    let froma: Vec<u8> = (0..5).flat_map(|i| format!("A-line {}\n", i).into_bytes()).collect();
+    for i in 0..5 {
+        a.send(format!("A-line {}\n", i).as_bytes()).await.unwrap();
+    }
+    // The other end starts a moment later, so they don't transmit in
+    // lockstep.
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    for i in 0..5 {
+        b.send(format!("B-line {}\n", i).as_bytes()).await.unwrap();
+    }
+    let got = tokio::time::timeout(DEADLINE, async {
+        (collect(&mut arx, fromb.len()).await, collect(&mut brx, froma.len()).await)
+    }).await.unwrap();
+    assert_eq!(got, (fromb, froma));
+}
@@ -0,0 +1,227 @@
+/*
+    Copyright (C) 2019  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+*/
+
+//! End-to-end tests: two `lorapipe pipe` processes talking over radios
+//! emulated by lorapipe-sim, which can lose frames on the way.
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a test may take to get everything across, and how long to
+/// wait for more when nothing is arriving.
+const DEADLINE: Duration = Duration::from_secs(180);
+const QUIET: Duration = Duration::from_secs(30);
+
+/// The emulator and the two ends, killed when dropped.  The radios use
+/// fast settings, and the emulator keeps frames on the air for less time
+/// than they would take, so the tests don't take long.
+struct Link {
+    dir: PathBuf,
+    children: Vec<Child>,
+    stdins: Vec<ChildStdin>,
+    rx: mpsc::Receiver<(usize, String)>,
+}
+
+impl Link {
+    /// Start the emulator, losing `loss` percent of frames, and a
+    /// lorapipe on each of two radios with `args`, then `pipe`, then
+    /// `pipeargs`.
+    fn new(name: &str, loss: u32, args: &[&str], pipeargs: &[&str]) -> Link {
+        let dir = std::env::temp_dir().join(format!("lorapipe-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut link = Link { dir, children: vec![], stdins: vec![], rx: mpsc::channel().1 };
+
+        let mut sim = Command::new(env!("CARGO_BIN_EXE_lorapipe-sim"))
+            .arg("--link").arg(link.dir.join("lora"))
+            .args(["--loss", &loss.to_string(), "--seed", "1", "--airtime", "10", "--bytetime", "0"])
+            .stdout(Stdio::piped()).stderr(Stdio::null())
+            .spawn().unwrap();
+        let simout = sim.stdout.take().unwrap();
+        link.children.push(sim);
+        // It names each radio once its link is in place.
+        let mut simout = BufReader::new(simout).lines();
+        for _ in 0..2 {
+            assert!(simout.next().unwrap().unwrap().starts_with("radio"));
+        }
+        thread::spawn(move || simout.for_each(drop));
+
+        let (tx, rx) = mpsc::channel();
+        link.rx = rx;
+        for i in 0..2 {
+            let mut child = Command::new(env!("CARGO_BIN_EXE_lorapipe"))
+                .args(["--region", "us915", "--profile", "fast"]).args(args)
+                .arg(link.dir.join(format!("lora{}", i))).arg("pipe").args(pipeargs)
+                .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped())
+                .spawn().unwrap();
+            // It describes the radio once it has set it up.
+            let mut stderr = BufReader::new(child.stderr.take().unwrap()).lines();
+            assert!(stderr.any(|l| l.unwrap().starts_with("Radio:")));
+            thread::spawn(move || stderr.for_each(drop));
+            let stdout = child.stdout.take().unwrap();
+            link.stdins.push(child.stdin.take().unwrap());
+            link.children.push(child);
+            let tx = tx.clone();
+            thread::spawn(move || {
+                for line in BufReader::new(stdout).lines() {
+                    if tx.send((i, line.unwrap())).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        link
+    }
+
+    /// Send `lines[i]` in at end i, and return what comes out of each
+    /// end.
+    fn exchange(&mut self, lines: &[Vec<String>; 2]) -> [Vec<String>; 2] {
+        self.send(lines);
+        self.collect(lines)
+    }
+
+    /// Send `lines[i]` in at end i.  The second end starts a moment after
+    /// the first, so they don't transmit in lockstep.
+    fn send(&mut self, lines: &[Vec<String>; 2]) {
+        for (stdin, lines) in self.stdins.iter_mut().zip(lines) {
+            for line in lines {
+                writeln!(stdin, "{}", line).unwrap();
+            }
+            stdin.flush().unwrap();
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+
+    /// What comes out of each end once the lines sent at the other have
+    /// all arrived, nothing has for a while, or the deadline has passed.
+    fn collect(&mut self, lines: &[Vec<String>; 2]) -> [Vec<String>; 2] {
+        let mut got = [vec![], vec![]];
+        let end = Instant::now() + DEADLINE;
+        while got[0].len() < lines[1].len() || got[1].len() < lines[0].len() {
+            let left = end.saturating_duration_since(Instant::now());
+            match self.rx.recv_timeout(left.min(QUIET)) {
+                Ok((i, line)) => got[i].push(line),
+                Err(_) => break,
+            }
+        }
+        got
+    }
+
+    /// Send `signal` to the emulator, as with kill(1).
+    fn signal(&self, signal: &str) {
+        let status = Command::new("kill")
+            .arg(format!("-{}", signal)).arg(self.children[0].id().to_string())
+            .status().unwrap();
+        assert!(status.success());
+    }
+
+    /// Whether both ends are still running.
+    fn running(&mut self) -> bool {
+        self.children[1..].iter_mut().all(|c| c.try_wait().unwrap().is_none())
+    }
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        for child in &mut self.children {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Lines from `end` of varying length, up to `longest` bytes.
+fn lines(end: &str, count: usize, longest: usize) -> Vec<String> {
+    (0..count).map(|i| {
+        let line = format!("{}-line {} ", end, i);
+        let pad = (i * 37) % longest.saturating_sub(line.len()).max(1);
+        line + &"x".repeat(pad)
+    }).collect()
+}
+
+/// Whether `got` is `sent` with some lines left out.
+fn subsequence(got: &[String], sent: &[String]) -> bool {
+    let mut sent = sent.iter();
+    got.iter().all(|g| sent.any(|s| s == g))
+}
+
+#[test]
+fn plain() {
+    let mut link = Link::new("plain", 0, &[], &[]);
+    let sent = [lines("A", 20, 60), lines("B", 20, 60)];
+    let got = link.exchange(&sent);
+    assert_eq!(got[0], sent[1]);
+    assert_eq!(got[1], sent[0]);
+}
+
+#[test]
+fn reliable() {
+    let mut link = Link::new("reliable", 10, &["--framing", "v1"], &["--reliable"]);
+    let sent = [lines("A", 20, 60), lines("B", 20, 60)];
+    let got = link.exchange(&sent);
+    assert_eq!(got[0], sent[1]);
+    assert_eq!(got[1], sent[0]);
+}
+
+#[test]
+fn reliabledatagram() {
+    // Lines longer than a frame are fragmented.
+    let mut link = Link::new("reliabledatagram", 10, &["--framing", "v1"], &["--reliable", "--datagram"]);
+    let sent = [lines("A", 12, 250), lines("B", 12, 250)];
+    let got = link.exchange(&sent);
+    assert_eq!(got[0], sent[1]);
+    assert_eq!(got[1], sent[0]);
+}
+
+#[test]
+fn fec() {
+    // Parity rebuilds most of what is lost; what is still lost goes
+    // whole, without damaging the lines around it.
+    let mut link = Link::new("fec", 5, &["--framing", "v1", "--fec", "4:2"], &["--datagram"]);
+    let sent = [lines("A", 20, 60), lines("B", 20, 60)];
+    let got = link.exchange(&sent);
+    for (got, sent) in got.iter().zip(sent.iter().rev()) {
+        assert!(subsequence(got, sent), "{:?} isn't part of {:?}", got, sent);
+        assert!(got.len() >= sent.len() * 4 / 5, "only {} of {} lines arrived", got.len(), sent.len());
+    }
+}
+
+#[test]
+fn radiostops() {
+    // The radios stop answering while there is data to send; once they
+    // are back, they are set up again and it goes out.  Only one end has
+    // data meanwhile, so the two don't come back sending in lockstep.
+    let mut link = Link::new("radiostops", 0, &["--framing", "v1"], &["--reliable"]);
+    link.signal("STOP");
+    let sent = [lines("A", 5, 60), vec![]];
+    link.send(&sent);
+    thread::sleep(Duration::from_secs(6));
+    link.signal("CONT");
+    let got = link.collect(&sent);
+    assert!(link.running());
+    assert_eq!(got[1], sent[0]);
+
+    let sent = [vec![], lines("B", 5, 60)];
+    let got = link.exchange(&sent);
+    assert_eq!(got[0], sent[1]);
+}